@@ -1,6 +1,18 @@
-use std::env::var;
+use std::{env::var, path::Path};
+
+use wayland_scanner::{generate_code, Side};
+
+/// Protocols not provided by `wayland-protocols` yet, generated into `OUT_DIR`.
+const PROTOCOLS: &[(&str, &str)] = &[("ext-idle-notify-v1.xml", "ext_idle_notify_v1.rs")];
 
 fn main() {
+    let out_dir = var("OUT_DIR").unwrap();
+    for (xml, generated) in PROTOCOLS {
+        let xml = Path::new("resources/protocols").join(xml);
+        println!("cargo:rerun-if-changed={}", xml.display());
+        generate_code(xml, Path::new(&out_dir).join(generated), Side::Server);
+    }
+
     if var("CARGO_FEATURE_LOGIND").ok().is_none() && var("CARGO_FEATURE_LIBSEAT").ok().is_none() {
         println!("cargo:warning=You are compiling anvil without logind/libseat support.");
         println!("cargo:warning=This means that you'll likely need to run it as root if you want to launch it from a tty.");
@@ -0,0 +1,36 @@
+use super::{parse_percentage, run, AudioBackend, Error};
+
+/// Controls a mixer element of an ALSA card through `amixer`.
+#[derive(Debug)]
+pub struct AlsaMixer {
+    card: Option<String>,
+    control: String,
+}
+
+impl AlsaMixer {
+    pub fn new(card: Option<String>, control: String) -> AlsaMixer {
+        AlsaMixer { card, control }
+    }
+
+    /// Runs `amixer` on the card, with the volume mapped like `alsamixer` shows it.
+    fn amixer(&self, args: &[&str]) -> Result<String, Error> {
+        let mut all_args = vec!["-M"];
+        if let Some(card) = &self.card {
+            all_args.extend(&["-c", card.as_str()]);
+        }
+        all_args.extend(args);
+        run("amixer", &all_args)
+    }
+}
+
+impl AudioBackend for AlsaMixer {
+    fn volume(&mut self) -> Result<f64, Error> {
+        let output = self.amixer(&["sget", &self.control])?;
+        parse_percentage(&output).ok_or(Error::Parse("amixer"))
+    }
+
+    fn set_volume(&mut self, volume: f64) -> Result<(), Error> {
+        let volume = format!("{}%", (volume * 100.0).round());
+        self.amixer(&["-q", "sset", &self.control, &volume]).map(|_| ())
+    }
+}
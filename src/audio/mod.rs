@@ -0,0 +1,313 @@
+use std::{
+    path::PathBuf,
+    process::Command,
+    sync::mpsc::{self, Sender},
+};
+
+use crate::{
+    config::{self, AudioBackendKind, AudioConfig},
+    gui::osd::Toast,
+    state::AnvilState,
+};
+
+mod alsa;
+mod pulse;
+
+pub use self::{alsa::AlsaMixer, pulse::PulseAudio};
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("Unable to run {0}: {1}")]
+    Spawn(&'static str, #[source] std::io::Error),
+    #[error("{0} failed: {1}")]
+    Failed(&'static str, String),
+    #[error("Unable to find the volume in the output of {0}")]
+    Parse(&'static str),
+}
+
+/// Runs a mixer command, returning its output.
+fn run(program: &'static str, args: &[&str]) -> Result<String, Error> {
+    let output = Command::new(program)
+        .args(args)
+        .output()
+        .map_err(|err| Error::Spawn(program, err))?;
+    if !output.status.success() {
+        return Err(Error::Failed(
+            program,
+            String::from_utf8_lossy(&output.stderr).trim().to_owned(),
+        ));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Returns the first `NN%` in the output of a mixer command, as a volume between 0 and 1.
+fn parse_percentage(output: &str) -> Option<f64> {
+    output
+        .split(|c: char| c.is_whitespace() || c == '[' || c == ']' || c == '/')
+        .filter_map(|word| word.strip_suffix('%'))
+        .find_map(|percent| percent.parse::<f64>().ok())
+        .map(|percent| percent / 100.0)
+}
+
+/// Controls the system volume.
+pub trait AudioBackend: std::fmt::Debug + Send {
+    /// The current volume, between 0 and 1.
+    fn volume(&mut self) -> Result<f64, Error>;
+    fn set_volume(&mut self, volume: f64) -> Result<(), Error>;
+}
+
+/// Keeps the volume in memory only, for machines without sound hardware.
+#[derive(Debug)]
+pub struct MockAudio {
+    volume: f64,
+}
+
+impl MockAudio {
+    pub fn new() -> MockAudio {
+        MockAudio::default()
+    }
+}
+
+impl Default for MockAudio {
+    fn default() -> Self {
+        MockAudio { volume: 0.5 }
+    }
+}
+
+impl AudioBackend for MockAudio {
+    fn volume(&mut self) -> Result<f64, Error> {
+        Ok(self.volume)
+    }
+
+    fn set_volume(&mut self, volume: f64) -> Result<(), Error> {
+        self.volume = volume;
+        Ok(())
+    }
+}
+
+fn create_backend(config: &AudioConfig, log: &slog::Logger) -> Box<dyn AudioBackend> {
+    match config.backend {
+        AudioBackendKind::Alsa => Box::new(AlsaMixer::new(
+            config.alsa_card.clone(),
+            config.alsa_control.clone(),
+        )),
+        AudioBackendKind::Pulse => Box::new(PulseAudio::new()),
+        AudioBackendKind::Mock => Box::new(MockAudio::new()),
+        AudioBackendKind::Auto => {
+            // pactl also talks to PipeWire, through pipewire-pulse
+            let mut pulse = PulseAudio::new();
+            if pulse.volume().is_ok() {
+                info!(log, "Controlling the volume through PulseAudio");
+                Box::new(pulse)
+            } else {
+                info!(log, "Controlling the volume through the ALSA mixer");
+                Box::new(AlsaMixer::new(
+                    config.alsa_card.clone(),
+                    config.alsa_control.clone(),
+                ))
+            }
+        }
+    }
+}
+
+fn volume_file() -> PathBuf {
+    config::state_dir().join("volume")
+}
+
+/// The system volume, changed from a worker thread so slow mixers do not block the compositor.
+#[derive(Debug)]
+pub struct Audio {
+    volume: f64,
+    step: f64,
+    worker: Sender<f64>,
+}
+
+impl Audio {
+    /// Sets up the configured backend, and restores the volume saved by the last run.
+    pub fn new(config: &AudioConfig, log: &slog::Logger) -> Audio {
+        Audio::with_backend(create_backend(config, log), config, volume_file(), log)
+    }
+
+    /// Uses the given backend, saving the volume to `state_path` and restoring it from there.
+    pub fn with_backend(
+        mut backend: Box<dyn AudioBackend>,
+        config: &AudioConfig,
+        state_path: PathBuf,
+        log: &slog::Logger,
+    ) -> Audio {
+        let saved = std::fs::read_to_string(&state_path)
+            .ok()
+            .and_then(|volume| volume.trim().parse::<f64>().ok())
+            .map(|volume| volume.clamp(0.0, 1.0));
+        let volume = match saved {
+            Some(volume) => volume,
+            None => backend.volume().unwrap_or_else(|err| {
+                warn!(log, "Unable to read the volume: {}", err);
+                0.5
+            }),
+        };
+
+        let (worker, requests) = mpsc::channel::<f64>();
+        let log = log.clone();
+        std::thread::spawn(move || {
+            while let Ok(mut volume) = requests.recv() {
+                // Only the latest volume matters
+                while let Ok(next) = requests.try_recv() {
+                    volume = next;
+                }
+                if let Err(err) = backend.set_volume(volume) {
+                    warn!(log, "Unable to set the volume: {}", err);
+                }
+                let saved = match state_path.parent() {
+                    Some(dir) => std::fs::create_dir_all(dir),
+                    None => Ok(()),
+                }
+                .and_then(|_| std::fs::write(&state_path, format!("{:.2}\n", volume)));
+                if let Err(err) = saved {
+                    warn!(log, "Unable to save the volume: {}", err; "path" => state_path.display().to_string());
+                }
+            }
+        });
+        if saved.is_some() {
+            let _ = worker.send(volume);
+        }
+
+        Audio {
+            volume,
+            step: config.step as f64 / 100.0,
+            worker,
+        }
+    }
+
+    /// The volume, between 0 and 1.
+    pub fn volume(&self) -> f64 {
+        self.volume
+    }
+
+    pub fn set_volume(&mut self, volume: f64) {
+        // Avoid drifting away from the steps through rounding
+        self.volume = (volume.clamp(0.0, 1.0) * 100.0).round() / 100.0;
+        let _ = self.worker.send(self.volume);
+    }
+
+    /// Moves the volume by a number of steps.
+    pub fn step(&mut self, steps: i32) {
+        self.set_volume(self.volume + steps as f64 * self.step);
+    }
+}
+
+impl<BackendData: 'static> AnvilState<BackendData> {
+    /// Moves the volume by a number of steps, and shows it on the OSD.
+    pub fn change_volume(&mut self, steps: i32) {
+        self.audio.step(steps);
+        self.osd.post(Toast::bar("Volume", self.audio.volume()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        path::{Path, PathBuf},
+        time::{Duration, Instant},
+    };
+
+    use super::*;
+
+    fn logger() -> slog::Logger {
+        slog::Logger::root(slog::Discard, o!())
+    }
+
+    /// A state file that does not exist yet, unique to the test.
+    fn state_path(test: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("waystation-audio-{}-{}", std::process::id(), test));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir.join("volume")
+    }
+
+    fn mock_audio(state_path: &Path) -> Audio {
+        Audio::with_backend(
+            Box::new(MockAudio::new()),
+            &AudioConfig::default(),
+            state_path.to_owned(),
+            &logger(),
+        )
+    }
+
+    /// Waits for the worker thread to save a volume.
+    fn saved_volume(state_path: &Path, expected: &str) -> String {
+        let start = Instant::now();
+        loop {
+            let saved = std::fs::read_to_string(state_path).unwrap_or_default();
+            if saved == expected || start.elapsed() > Duration::from_secs(5) {
+                return saved;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn parses_amixer_output() {
+        let output = "Simple mixer control 'Master',0\n  Mono: Playback 42 [65%] [-14.00dB] [on]\n";
+        assert_eq!(parse_percentage(output), Some(0.65));
+    }
+
+    #[test]
+    fn parses_pactl_output() {
+        let output =
+            "Volume: front-left: 32768 /  50% / -18.06 dB,   front-right: 32768 /  50% / -18.06 dB\n";
+        assert_eq!(parse_percentage(output), Some(0.5));
+    }
+
+    #[test]
+    fn parses_no_percentage() {
+        assert_eq!(parse_percentage("Mute: no\n"), None);
+        assert_eq!(parse_percentage("100 %"), None);
+    }
+
+    #[test]
+    fn steps_and_clamps_volume() {
+        let path = state_path("steps");
+        let mut audio = mock_audio(&path);
+        assert_eq!(audio.volume(), 0.5);
+
+        audio.step(1);
+        assert_eq!(audio.volume(), 0.55);
+        audio.step(-3);
+        assert_eq!(audio.volume(), 0.4);
+        audio.step(20);
+        assert_eq!(audio.volume(), 1.0);
+        audio.step(-30);
+        assert_eq!(audio.volume(), 0.0);
+
+        audio.set_volume(0.333);
+        assert_eq!(audio.volume(), 0.33);
+        audio.set_volume(-0.2);
+        assert_eq!(audio.volume(), 0.0);
+    }
+
+    #[test]
+    fn saves_volume() {
+        let path = state_path("save");
+        let mut audio = mock_audio(&path);
+        audio.set_volume(0.7);
+        assert_eq!(saved_volume(&path, "0.70\n"), "0.70\n");
+        audio.step(-1);
+        assert_eq!(saved_volume(&path, "0.65\n"), "0.65\n");
+    }
+
+    /// Starts with a saved volume, each test with its own file as the worker writes it back.
+    fn restored_volume(test: &str, saved: &str) -> f64 {
+        let path = state_path(test);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, saved).unwrap();
+        mock_audio(&path).volume()
+    }
+
+    #[test]
+    fn restores_saved_volume() {
+        assert_eq!(restored_volume("restore", "0.30\n"), 0.3);
+        assert_eq!(restored_volume("restore-clamped", "4.2\n"), 1.0);
+        // The backend volume is used instead
+        assert_eq!(restored_volume("restore-invalid", "loud\n"), 0.5);
+    }
+}
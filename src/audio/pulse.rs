@@ -0,0 +1,23 @@
+use super::{parse_percentage, run, AudioBackend, Error};
+
+/// Controls the default sink through `pactl`, which works with PulseAudio and PipeWire.
+#[derive(Debug, Default)]
+pub struct PulseAudio;
+
+impl PulseAudio {
+    pub fn new() -> PulseAudio {
+        PulseAudio
+    }
+}
+
+impl AudioBackend for PulseAudio {
+    fn volume(&mut self) -> Result<f64, Error> {
+        let output = run("pactl", &["get-sink-volume", "@DEFAULT_SINK@"])?;
+        parse_percentage(&output).ok_or(Error::Parse("pactl"))
+    }
+
+    fn set_volume(&mut self, volume: f64) -> Result<(), Error> {
+        let volume = format!("{}%", (volume * 100.0).round());
+        run("pactl", &["set-sink-volume", "@DEFAULT_SINK@", &volume]).map(|_| ())
+    }
+}
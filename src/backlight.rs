@@ -0,0 +1,260 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::mpsc::{self, Sender},
+};
+
+use crate::{config, gui::osd::Toast, state::AnvilState};
+
+/// The panel is never turned fully dark from the brightness controls.
+const MIN_LEVEL: f64 = 0.05;
+
+/// Connectors of built-in panels, which are the ones with a backlight.
+const INTERNAL_CONNECTORS: [&str; 3] = ["eDP", "LVDS", "DSI"];
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("Error accessing the backlight: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Invalid value in {0}")]
+    Parse(PathBuf),
+    #[cfg(feature = "logind")]
+    #[error("Error setting the brightness through logind: {0}")]
+    Logind(#[from] dbus::Error),
+}
+
+fn read_value(path: &Path) -> Result<u32, Error> {
+    std::fs::read_to_string(path)?
+        .trim()
+        .parse()
+        .map_err(|_| Error::Parse(path.to_owned()))
+}
+
+fn brightness_file() -> PathBuf {
+    config::state_dir().join("brightness")
+}
+
+/// What a `Backlight` asks of its worker thread.
+#[derive(Debug)]
+enum Request {
+    /// Set the raw brightness value.
+    Set(u32),
+    /// Save the brightness level for the next run.
+    Save(f64),
+}
+
+/// Writes the brightness values sent by a `Backlight`, from its worker thread.
+struct Writer {
+    name: String,
+    path: PathBuf,
+    /// Kept open between writes, connecting to the system bus is slow.
+    #[cfg(feature = "logind")]
+    connection: Option<dbus::blocking::Connection>,
+}
+
+impl Writer {
+    /// Starts the worker thread, so slow logind calls do not block the compositor.
+    fn spawn(mut self, log: slog::Logger) -> Sender<Request> {
+        let (worker, requests) = mpsc::channel::<Request>();
+        std::thread::spawn(move || {
+            while let Ok(first) = requests.recv() {
+                // Only the latest brightness and saved level matter
+                let (mut value, mut save) = (None, None);
+                for request in std::iter::once(first).chain(requests.try_iter()) {
+                    match request {
+                        Request::Set(next) => value = Some(next),
+                        Request::Save(level) => save = Some(level),
+                    }
+                }
+                if let Some(value) = value {
+                    if let Err(err) = self.write(value) {
+                        warn!(log, "Unable to set the brightness: {}", err; "name" => &self.name);
+                    }
+                }
+                if let Some(level) = save {
+                    let path = brightness_file();
+                    let saved = std::fs::create_dir_all(config::state_dir())
+                        .and_then(|_| std::fs::write(&path, format!("{:.2}\n", level)));
+                    if let Err(err) = saved {
+                        warn!(log, "Unable to save the brightness: {}", err; "path" => path.display().to_string());
+                    }
+                }
+            }
+        });
+        worker
+    }
+
+    /// Sets the brightness through logind, which lets unprivileged sessions change it.
+    #[cfg(feature = "logind")]
+    fn write(&mut self, value: u32) -> Result<(), Error> {
+        match self.write_logind(value) {
+            Ok(()) => Ok(()),
+            Err(err) => {
+                slog_scope::debug!("{}, writing to sysfs instead", err);
+                self.write_sysfs(value)
+            }
+        }
+    }
+
+    #[cfg(not(feature = "logind"))]
+    fn write(&mut self, value: u32) -> Result<(), Error> {
+        self.write_sysfs(value)
+    }
+
+    #[cfg(feature = "logind")]
+    fn write_logind(&mut self, value: u32) -> Result<(), Error> {
+        let connection = match &self.connection {
+            Some(connection) => connection,
+            None => self.connection.insert(dbus::blocking::Connection::new_system()?),
+        };
+        let proxy = connection.with_proxy(
+            "org.freedesktop.login1",
+            "/org/freedesktop/login1/session/auto",
+            std::time::Duration::from_secs(1),
+        );
+        proxy.method_call(
+            "org.freedesktop.login1.Session",
+            "SetBrightness",
+            ("backlight", self.name.as_str(), value),
+        )?;
+        Ok(())
+    }
+
+    fn write_sysfs(&self, value: u32) -> Result<(), Error> {
+        std::fs::write(self.path.join("brightness"), value.to_string())?;
+        Ok(())
+    }
+}
+
+/// A device of the `backlight` class, controlling the brightness of a panel.
+#[derive(Debug)]
+pub struct Backlight {
+    name: String,
+    max_brightness: u32,
+    /// The brightness, between `MIN_LEVEL` and 1.
+    level: f64,
+    step: f64,
+    worker: Sender<Request>,
+}
+
+impl Backlight {
+    fn open(device: &udev::Device, step: f64, log: &slog::Logger) -> Result<Backlight, Error> {
+        let path = device.syspath().to_owned();
+        let max_brightness = read_value(&path.join("max_brightness"))?;
+        if max_brightness == 0 {
+            return Err(Error::Parse(path.join("max_brightness")));
+        }
+        let brightness = read_value(&path.join("brightness"))?;
+        let name = device.sysname().to_string_lossy().into_owned();
+        let writer = Writer {
+            name: name.clone(),
+            path,
+            #[cfg(feature = "logind")]
+            connection: None,
+        };
+        Ok(Backlight {
+            name,
+            max_brightness,
+            level: (brightness as f64 / max_brightness as f64).clamp(MIN_LEVEL, 1.0),
+            step,
+            worker: writer.spawn(log.clone()),
+        })
+    }
+
+    /// Finds the backlight of a DRM connector, like `eDP-1` on `card0`.
+    ///
+    /// Backlights that are children of the connector in sysfs are used first. Panels
+    /// of most ARM devices are not linked to their backlight, so a built-in connector
+    /// otherwise gets the backlight udev considers best, in the same order as logind
+    /// and the desktops: firmware, then platform, then raw interfaces.
+    pub fn for_connector(card: &str, connector: &str, step: f64, log: &slog::Logger) -> Option<Backlight> {
+        let devices = udev::Enumerator::new().and_then(|mut enumerator| {
+            enumerator.match_subsystem("backlight")?;
+            Ok(enumerator.scan_devices()?.collect::<Vec<_>>())
+        });
+        let devices = match devices {
+            Ok(devices) => devices,
+            Err(err) => {
+                warn!(log, "Unable to list backlights: {}", err);
+                return None;
+            }
+        };
+
+        let connector_sysname = format!("{}-{}", card, connector);
+        let linked = devices.iter().find(|device| {
+            device
+                .parent()
+                .map(|parent| parent.sysname().to_string_lossy() == connector_sysname)
+                .unwrap_or(false)
+        });
+        let internal = INTERNAL_CONNECTORS
+            .iter()
+            .any(|prefix| connector.starts_with(prefix));
+        let device = linked.or_else(|| {
+            if !internal {
+                return None;
+            }
+            ["firmware", "platform", "raw"].iter().find_map(|kind| {
+                devices.iter().find(|device| {
+                    device
+                        .attribute_value("type")
+                        .map(|value| value.to_string_lossy() == *kind)
+                        .unwrap_or(false)
+                })
+            })
+        })?;
+
+        match Backlight::open(device, step, log) {
+            Ok(backlight) => {
+                info!(log, "Using backlight"; "name" => &backlight.name, "connector" => connector);
+                Some(backlight)
+            }
+            Err(err) => {
+                warn!(log, "Unable to use backlight: {}", err; "path" => device.syspath().display().to_string());
+                None
+            }
+        }
+    }
+
+    /// The brightness, between 0 and 1.
+    pub fn level(&self) -> f64 {
+        self.level
+    }
+
+    pub fn set_level(&mut self, level: f64) {
+        self.level = level.clamp(MIN_LEVEL, 1.0);
+        let value = (self.level * self.max_brightness as f64).round() as u32;
+        let _ = self.worker.send(Request::Set(value));
+    }
+
+    /// Restores the brightness saved by the last run.
+    pub fn restore(&mut self) {
+        let saved = std::fs::read_to_string(brightness_file())
+            .ok()
+            .and_then(|level| level.trim().parse::<f64>().ok());
+        if let Some(level) = saved {
+            self.set_level(level);
+        }
+    }
+
+    /// Saves the brightness for the next run, from the worker thread.
+    fn save(&self) {
+        let _ = self.worker.send(Request::Save(self.level));
+    }
+}
+
+impl<BackendData: 'static> AnvilState<BackendData> {
+    /// Moves the brightness by a number of steps, and shows it on the OSD.
+    pub fn change_brightness(&mut self, steps: i32) {
+        let backlight = match &mut self.backlight {
+            Some(backlight) => backlight,
+            None => {
+                debug!(self.log, "No backlight to change the brightness of");
+                return;
+            }
+        };
+        let level = ((backlight.level + steps as f64 * backlight.step) * 100.0).round() / 100.0;
+        backlight.set_level(level);
+        backlight.save();
+        self.osd.post(Toast::bar("Brightness", backlight.level));
+    }
+}
@@ -0,0 +1,260 @@
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+use smithay::{
+    reexports::calloop::{
+        timer::{Timer, TimerHandle},
+        LoopHandle,
+    },
+    wayland::seat::{Keysym, ModifiersState},
+};
+
+use crate::{
+    config::{BindingsConfig, Modifier},
+    gamepad::Button,
+    state::AnvilState,
+};
+
+/// Something the compositor does in response to a gamepad chord or a key.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Action {
+    ToggleMenu,
+    /// Close the game currently shown.
+    CloseGame,
+    Screenshot,
+    VolumeUp,
+    VolumeDown,
+    BrightnessUp,
+    BrightnessDown,
+    Suspend,
+    /// Start or stop recording the screen.
+    ToggleRecording,
+    /// Save the last seconds shown, with instant replay on.
+    SaveReplay,
+}
+
+#[derive(Debug)]
+struct ChordBinding {
+    buttons: Vec<Button>,
+    hold: Duration,
+    action: Action,
+}
+
+#[derive(Debug)]
+struct KeyBinding {
+    keysym: Keysym,
+    modifiers: Vec<Modifier>,
+    action: Action,
+}
+
+/// Turns gamepad button presses and keys into actions, according to the config.
+///
+/// A chord only counts when its buttons are exactly the ones pressed since all buttons
+/// were last released, so `Select` alone does not trigger `Select+Start`. Chords without
+/// a hold time run when they are released, which lets the same chord have a hold binding
+/// as well.
+///
+/// The button completing a chord, and the ones pressed after an action ran, are kept from
+/// the game, so that chords like `Select+Start` do not also trigger its own hotkeys.
+#[derive(Debug)]
+pub struct Bindings {
+    chord_window: Duration,
+    chords: Vec<ChordBinding>,
+    keys: Vec<KeyBinding>,
+    /// The buttons held down, and when they were pressed.
+    held: Vec<(Button, Instant)>,
+    /// Every button pressed since all buttons were last released.
+    gesture: Vec<Button>,
+    /// Whether an action already ran for the current gesture.
+    fired: bool,
+    /// The buttons held down that the game must not see.
+    suppressed: Vec<Button>,
+    timer: TimerHandle<()>,
+}
+
+impl Bindings {
+    /// Creates the bindings from the config, and checks the held chords from the event loop.
+    pub fn new<BackendData: 'static>(
+        config: &BindingsConfig,
+        handle: &LoopHandle<'static, AnvilState<BackendData>>,
+        log: &slog::Logger,
+    ) -> Bindings {
+        let timer = Timer::new().expect("Failed to create the bindings timer");
+        let timer_handle = timer.handle();
+        handle
+            .insert_source(timer, |(), _, state| {
+                if let Some(action) = state.bindings.check_holds(Instant::now()) {
+                    state.run_action(action);
+                }
+            })
+            .expect("Failed to init the bindings timer");
+
+        let chords = config
+            .gamepad
+            .iter()
+            .filter(|binding| !binding.buttons.is_empty())
+            .map(|binding| {
+                let mut buttons = binding.buttons.clone();
+                buttons.sort_by_key(|button| *button as u8);
+                buttons.dedup();
+                ChordBinding {
+                    buttons,
+                    hold: Duration::from_millis(binding.hold_ms),
+                    action: binding.action,
+                }
+            })
+            .collect();
+
+        let keys = config
+            .keys
+            .iter()
+            .filter_map(|binding| {
+                match xkbcommon::xkb::keysym_from_name(&binding.key, xkbcommon::xkb::KEYSYM_NO_FLAGS) {
+                    xkbcommon::xkb::KEY_NoSymbol => {
+                        warn!(log, "Unknown key in binding"; "key" => &binding.key);
+                        None
+                    }
+                    keysym => Some(KeyBinding {
+                        keysym,
+                        modifiers: binding.modifiers.clone(),
+                        action: binding.action,
+                    }),
+                }
+            })
+            .collect();
+
+        Bindings {
+            chord_window: Duration::from_millis(config.chord_window_ms),
+            chords,
+            keys,
+            held: Vec::new(),
+            gesture: Vec::new(),
+            fired: false,
+            suppressed: Vec::new(),
+            timer: timer_handle,
+        }
+    }
+
+    /// Returns when the last button of the chord was pressed, if the chord is currently held
+    /// and is everything pressed during the gesture.
+    fn chord_held(&self, chord: &ChordBinding) -> Option<Instant> {
+        if chord.buttons.len() != self.gesture.len() {
+            return None;
+        }
+        let times = chord
+            .buttons
+            .iter()
+            .map(|button| self.held.iter().find(|(b, _)| b == button).map(|(_, t)| *t))
+            .collect::<Option<Vec<_>>>()?;
+        let first = *times.iter().min()?;
+        let last = *times.iter().max()?;
+        (last - first <= self.chord_window).then(|| last)
+    }
+
+    pub fn press(&mut self, button: Button, now: Instant) {
+        if self.held.is_empty() {
+            self.gesture.clear();
+            self.fired = false;
+        }
+        if self.held.iter().any(|(b, _)| *b == button) {
+            return;
+        }
+        self.held.push((button, now));
+        if !self.gesture.contains(&button) {
+            self.gesture.push(button);
+            self.gesture.sort_by_key(|button| *button as u8);
+        }
+
+        for chord in self.chords.iter().filter(|chord| !chord.hold.is_zero()) {
+            if self.chord_held(chord).is_some() {
+                self.timer.add_timeout(chord.hold, ());
+            }
+        }
+        if self.fired || self.chords.iter().any(|chord| self.chord_held(chord).is_some()) {
+            self.suppressed.push(button);
+        }
+    }
+
+    /// Returns `true` if the button is held down as part of a chord, rather than for the game.
+    pub fn is_suppressed(&self, button: Button) -> bool {
+        self.suppressed.contains(&button)
+    }
+
+    /// Returns the action of the chord released, if any.
+    pub fn release(&mut self, button: Button) -> Option<Action> {
+        let action = if self.fired || !self.held.iter().any(|(b, _)| *b == button) {
+            None
+        } else {
+            self.chords
+                .iter()
+                .filter(|chord| chord.hold.is_zero())
+                .find(|chord| self.chord_held(chord).is_some())
+                .map(|chord| chord.action)
+        };
+        if action.is_some() {
+            self.fired = true;
+        }
+        self.held.retain(|(b, _)| *b != button);
+        self.suppressed.retain(|b| *b != button);
+        action
+    }
+
+    /// Returns the action of the chord held long enough, if any.
+    fn check_holds(&mut self, now: Instant) -> Option<Action> {
+        if self.fired {
+            return None;
+        }
+        let action = self
+            .chords
+            .iter()
+            .filter(|chord| !chord.hold.is_zero())
+            .filter(|chord| {
+                self.chord_held(chord)
+                    .map(|pressed| now.duration_since(pressed) >= chord.hold)
+                    .unwrap_or(false)
+            })
+            .max_by_key(|chord| chord.hold)
+            .map(|chord| chord.action);
+        self.fired = action.is_some();
+        action
+    }
+
+    /// Returns the action bound to a key, with exactly the given modifiers held.
+    pub fn key_action(&self, modifiers: &ModifiersState, keysym: Keysym) -> Option<Action> {
+        self.keys
+            .iter()
+            .find(|binding| {
+                binding.keysym == keysym
+                    && binding.modifiers.contains(&Modifier::Ctrl) == modifiers.ctrl
+                    && binding.modifiers.contains(&Modifier::Alt) == modifiers.alt
+                    && binding.modifiers.contains(&Modifier::Shift) == modifiers.shift
+                    && binding.modifiers.contains(&Modifier::Logo) == modifiers.logo
+            })
+            .map(|binding| binding.action)
+    }
+}
+
+impl<BackendData: 'static> AnvilState<BackendData> {
+    pub fn run_action(&mut self, action: Action) {
+        debug!(self.log, "Running action"; "action" => format!("{:?}", action));
+        match action {
+            Action::ToggleMenu => {
+                let on_top = self.window_map.borrow().menu_on_top;
+                self.set_menu_on_top(!on_top);
+            }
+            Action::CloseGame => self.close_top_game(),
+            Action::VolumeUp => self.change_volume(1),
+            Action::VolumeDown => self.change_volume(-1),
+            Action::BrightnessUp => self.change_brightness(1),
+            Action::BrightnessDown => self.change_brightness(-1),
+            Action::Suspend => self.request_suspend(),
+            Action::Screenshot => {
+                self.screenshots.request();
+                self.schedule_render();
+            }
+            Action::ToggleRecording => self.toggle_recording(),
+            Action::SaveReplay => self.save_replay(),
+        }
+    }
+}
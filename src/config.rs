@@ -0,0 +1,479 @@
+use std::{collections::HashMap, path::PathBuf};
+
+use serde::Deserialize;
+use smithay::reexports::wayland_server::protocol::wl_output;
+
+use crate::{bindings::Action, gamepad::Button};
+
+/// The compositor configuration, read from `$XDG_CONFIG_HOME/waystation/config.toml`.
+///
+/// Every section is optional, a missing file results in the default configuration.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub launcher: LauncherConfig,
+    pub games: GamesConfig,
+    pub bindings: BindingsConfig,
+    pub power: PowerConfig,
+    pub audio: AudioConfig,
+    pub display: DisplayConfig,
+    pub screenshots: ScreenshotConfig,
+    pub screencopy: ScreencopyConfig,
+    pub recording: RecordingConfig,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct LauncherConfig {
+    /// Directory scanned for freedesktop `.desktop` files, in addition to the apps listed here.
+    pub desktop_dir: Option<PathBuf>,
+    #[serde(rename = "app")]
+    pub apps: Vec<AppConfig>,
+}
+
+/// A launcher entry as written in the config file.
+#[derive(Debug, Clone, Deserialize)]
+pub struct AppConfig {
+    pub name: String,
+    /// The command line, split with shell quoting rules.
+    pub exec: String,
+    pub working_dir: Option<PathBuf>,
+    #[serde(default)]
+    pub env: HashMap<String, String>,
+    pub icon: Option<PathBuf>,
+    #[serde(default)]
+    pub pause: PausePolicy,
+    #[serde(default)]
+    pub scaling: ScalingMode,
+    /// The refresh rate the screen switches to while the game is shown, in Hz.
+    pub refresh_rate: Option<f64>,
+}
+
+/// What happens to a game while it is hidden behind the menu or another game.
+///
+/// Everything is opt-in, since not every game copes with being stopped.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct PausePolicy {
+    /// Stop sending frame callbacks to its windows.
+    pub frames: bool,
+    /// Send SIGSTOP to its processes, and SIGCONT when it is shown again.
+    pub stop: bool,
+    /// Mute its audio streams.
+    pub mute: bool,
+}
+
+impl PausePolicy {
+    pub fn is_enabled(&self) -> bool {
+        self.frames || self.stop || self.mute
+    }
+}
+
+/// How a game window that does not match the size of the output is shown.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ScalingMode {
+    /// Unscaled, in the middle of the output.
+    Center,
+    /// Scaled to fill the output, ignoring the aspect ratio.
+    Stretch,
+    /// Scaled as large as it fits, keeping the aspect ratio.
+    Fit,
+    /// Scaled by the largest whole factor that fits, for sharp pixel art.
+    Integer,
+}
+
+impl Default for ScalingMode {
+    fn default() -> Self {
+        ScalingMode::Fit
+    }
+}
+
+impl ScalingMode {
+    const ALL: [ScalingMode; 4] = [
+        ScalingMode::Center,
+        ScalingMode::Stretch,
+        ScalingMode::Fit,
+        ScalingMode::Integer,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ScalingMode::Center => "Center",
+            ScalingMode::Stretch => "Stretch",
+            ScalingMode::Fit => "Fit",
+            ScalingMode::Integer => "Integer",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<ScalingMode> {
+        Self::ALL
+            .iter()
+            .copied()
+            .find(|mode| mode.name().eq_ignore_ascii_case(name))
+    }
+
+    /// Returns the mode `steps` away from this one, wrapping around.
+    pub fn cycle(self, steps: i32) -> ScalingMode {
+        let len = Self::ALL.len() as i32;
+        let i = Self::ALL.iter().position(|mode| *mode == self).unwrap() as i32;
+        Self::ALL[(i + steps).rem_euclid(len) as usize]
+    }
+}
+
+/// How games are treated by the compositor.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct GamesConfig {
+    /// Time a game gets to exit after being asked to close, before it receives SIGTERM.
+    pub close_timeout_ms: u64,
+    /// Time a game gets to exit after SIGTERM, before it receives SIGKILL.
+    pub term_timeout_ms: u64,
+}
+
+impl Default for GamesConfig {
+    fn default() -> Self {
+        GamesConfig {
+            close_timeout_ms: 5000,
+            term_timeout_ms: 2000,
+        }
+    }
+}
+
+/// The gamepad and keyboard shortcuts.
+///
+/// Listing any gamepad or key bindings replaces the corresponding defaults.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct BindingsConfig {
+    /// All buttons of a chord must be pressed within this time of each other.
+    pub chord_window_ms: u64,
+    #[serde(rename = "gamepad")]
+    pub gamepad: Vec<GamepadBinding>,
+    #[serde(rename = "key")]
+    pub keys: Vec<KeyBinding>,
+}
+
+impl Default for BindingsConfig {
+    fn default() -> Self {
+        let gamepad = |buttons: &[Button], hold_ms, action| GamepadBinding {
+            buttons: buttons.to_vec(),
+            hold_ms,
+            action,
+        };
+        let key = |key: &str, modifiers: &[Modifier], action| KeyBinding {
+            key: key.to_owned(),
+            modifiers: modifiers.to_vec(),
+            action,
+        };
+        BindingsConfig {
+            chord_window_ms: 300,
+            gamepad: vec![
+                gamepad(&[Button::Mode], 0, Action::ToggleMenu),
+                gamepad(&[Button::Mode], 1500, Action::CloseGame),
+                gamepad(&[Button::Select, Button::Start], 0, Action::ToggleMenu),
+                gamepad(&[Button::Select, Button::Start], 1500, Action::CloseGame),
+                gamepad(&[Button::Mode, Button::North], 0, Action::Screenshot),
+                gamepad(&[Button::Mode, Button::West], 0, Action::ToggleRecording),
+                gamepad(&[Button::Mode, Button::East], 0, Action::SaveReplay),
+            ],
+            keys: vec![
+                key("Escape", &[Modifier::Logo], Action::ToggleMenu),
+                key("XF86AudioRaiseVolume", &[], Action::VolumeUp),
+                key("XF86AudioLowerVolume", &[], Action::VolumeDown),
+                key("XF86MonBrightnessUp", &[], Action::BrightnessUp),
+                key("XF86MonBrightnessDown", &[], Action::BrightnessDown),
+                key("XF86Sleep", &[], Action::Suspend),
+                key("Print", &[], Action::Screenshot),
+            ],
+        }
+    }
+}
+
+/// A chord of gamepad buttons, optionally held for some time.
+#[derive(Debug, Clone, Deserialize)]
+pub struct GamepadBinding {
+    pub buttons: Vec<Button>,
+    /// Without a hold time, the action runs when the chord is released.
+    #[serde(default)]
+    pub hold_ms: u64,
+    pub action: Action,
+}
+
+/// A keysym, by its xkb name, with the modifiers that must be held along with it.
+#[derive(Debug, Clone, Deserialize)]
+pub struct KeyBinding {
+    pub key: String,
+    #[serde(default)]
+    pub modifiers: Vec<Modifier>,
+    pub action: Action,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Modifier {
+    Ctrl,
+    Alt,
+    Shift,
+    Logo,
+}
+
+/// Battery warnings, and what happens when the battery runs out.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct PowerConfig {
+    /// Charge in percent below which a warning is shown, for the device and the controllers.
+    pub low_battery: u8,
+    /// Charge in percent of the device battery at which `critical_action` runs.
+    pub critical_battery: u8,
+    pub critical_action: CriticalAction,
+    /// Evdev key codes pressed together to make the focused game save, like `[29, 31]` for Ctrl+S.
+    pub save_keys: Vec<u32>,
+    /// Buttons pressed together on the virtual gamepad of the game to make it save.
+    pub save_buttons: Vec<Button>,
+    /// Time the game gets to save before `critical_action` runs.
+    pub save_delay_ms: u64,
+}
+
+impl Default for PowerConfig {
+    fn default() -> Self {
+        PowerConfig {
+            low_battery: 15,
+            critical_battery: 5,
+            critical_action: CriticalAction::Suspend,
+            save_keys: Vec::new(),
+            save_buttons: Vec::new(),
+            save_delay_ms: 3000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CriticalAction {
+    None,
+    Suspend,
+    Shutdown,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct AudioConfig {
+    pub backend: AudioBackendKind,
+    /// Volume change of the hotkeys and the menu, in percent.
+    pub step: u8,
+    /// The ALSA card, the default one if unset.
+    pub alsa_card: Option<String>,
+    /// The ALSA mixer element controlling the volume.
+    pub alsa_control: String,
+}
+
+impl Default for AudioConfig {
+    fn default() -> Self {
+        AudioConfig {
+            backend: AudioBackendKind::Auto,
+            step: 5,
+            alsa_card: None,
+            alsa_control: "Master".to_owned(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AudioBackendKind {
+    /// PulseAudio or PipeWire if running, ALSA otherwise.
+    Auto,
+    Alsa,
+    /// PulseAudio, or PipeWire through pipewire-pulse.
+    Pulse,
+    /// No sound hardware, the volume is only kept in memory.
+    Mock,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct DisplayConfig {
+    /// Brightness change of the hotkeys and the menu, in percent.
+    pub brightness_step: u8,
+    /// Time without input after which the backlight is turned down, 0 to never dim.
+    pub dim_after_secs: u64,
+    /// Brightness in percent while dimmed.
+    pub dim_brightness: u8,
+    /// Time without input after which the screen is turned off, 0 to never blank.
+    pub blank_after_secs: u64,
+    /// Transforms of the outputs by name, like `DSI-1`, instead of the panel orientation
+    /// reported by the kernel.
+    pub transform: HashMap<String, OutputTransform>,
+}
+
+impl Default for DisplayConfig {
+    fn default() -> Self {
+        DisplayConfig {
+            brightness_step: 5,
+            dim_after_secs: 60,
+            dim_brightness: 10,
+            blank_after_secs: 120,
+            transform: HashMap::new(),
+        }
+    }
+}
+
+/// How the content of an output is turned, counter-clockwise, to be upright on its panel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum OutputTransform {
+    #[serde(rename = "normal")]
+    Normal,
+    #[serde(rename = "90")]
+    Rotate90,
+    #[serde(rename = "180")]
+    Rotate180,
+    #[serde(rename = "270")]
+    Rotate270,
+    #[serde(rename = "flipped")]
+    Flipped,
+    #[serde(rename = "flipped-90")]
+    Flipped90,
+    #[serde(rename = "flipped-180")]
+    Flipped180,
+    #[serde(rename = "flipped-270")]
+    Flipped270,
+}
+
+impl OutputTransform {
+    pub fn wl_transform(self) -> wl_output::Transform {
+        match self {
+            OutputTransform::Normal => wl_output::Transform::Normal,
+            OutputTransform::Rotate90 => wl_output::Transform::_90,
+            OutputTransform::Rotate180 => wl_output::Transform::_180,
+            OutputTransform::Rotate270 => wl_output::Transform::_270,
+            OutputTransform::Flipped => wl_output::Transform::Flipped,
+            OutputTransform::Flipped90 => wl_output::Transform::Flipped90,
+            OutputTransform::Flipped180 => wl_output::Transform::Flipped180,
+            OutputTransform::Flipped270 => wl_output::Transform::Flipped270,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ScreenshotConfig {
+    /// Where screenshots are saved, named after the game shown.
+    pub directory: PathBuf,
+}
+
+impl Default for ScreenshotConfig {
+    fn default() -> Self {
+        ScreenshotConfig {
+            directory: PathBuf::from("~/Pictures/Screenshots"),
+        }
+    }
+}
+
+/// Access to the screen for external capture tools.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ScreencopyConfig {
+    /// Full paths of the executables allowed to capture the outputs, so games cannot
+    /// capture each other.
+    pub allowed: Vec<PathBuf>,
+}
+
+impl Default for ScreencopyConfig {
+    fn default() -> Self {
+        ScreencopyConfig {
+            allowed: vec![
+                PathBuf::from("/usr/bin/grim"),
+                PathBuf::from("/usr/bin/wf-recorder"),
+            ],
+        }
+    }
+}
+
+/// Screen recordings and instant replays, saved as MJPEG through `ffmpeg`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct RecordingConfig {
+    /// Where recordings and replays are saved, named after the game shown.
+    pub directory: PathBuf,
+    /// Frames recorded per second, at most the refresh rate of the output.
+    pub fps: u32,
+    /// JPEG quality of the frames, from 1 to 100.
+    pub quality: u8,
+    /// How many of the last seconds are kept for instant replays, 0 to turn them off.
+    pub replay_secs: u32,
+    /// Options of the `ffmpeg` output, the frames being copied as they are by default.
+    pub ffmpeg_args: Vec<String>,
+}
+
+impl Default for RecordingConfig {
+    fn default() -> Self {
+        RecordingConfig {
+            directory: PathBuf::from("~/Videos/Clips"),
+            fps: 30,
+            quality: 85,
+            replay_secs: 0,
+            ffmpeg_args: vec!["-c:v".to_owned(), "copy".to_owned()],
+        }
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("Error reading config file: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Error parsing config file: {0}")]
+    Parse(#[from] toml::de::Error),
+}
+
+/// Returns `$XDG_CONFIG_HOME/waystation`, falling back to `~/.config/waystation`.
+pub fn config_dir() -> PathBuf {
+    std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))
+        .unwrap_or_else(|| PathBuf::from("/etc/xdg"))
+        .join("waystation")
+}
+
+/// Returns `$XDG_STATE_HOME/waystation`, falling back to `~/.local/state/waystation`, where
+/// settings changed from the menu are kept.
+pub fn state_dir() -> PathBuf {
+    std::env::var_os("XDG_STATE_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".local/state")))
+        .unwrap_or_else(|| PathBuf::from("/var/lib"))
+        .join("waystation")
+}
+
+/// Expands a leading `~/` to the home directory.
+pub fn expand_home(path: PathBuf) -> PathBuf {
+    match (path.strip_prefix("~"), std::env::var_os("HOME")) {
+        (Ok(rest), Some(home)) => PathBuf::from(home).join(rest),
+        _ => path,
+    }
+}
+
+impl Config {
+    pub fn path() -> PathBuf {
+        std::env::var_os("WAYSTATION_CONFIG")
+            .map(PathBuf::from)
+            .unwrap_or_else(|| config_dir().join("config.toml"))
+    }
+
+    pub fn read() -> Result<Config, Error> {
+        let contents = match std::fs::read_to_string(Self::path()) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Config::default()),
+            Err(err) => return Err(err.into()),
+        };
+        Ok(toml::from_str(&contents)?)
+    }
+
+    /// Reads the config file, falling back to the defaults if it is invalid.
+    pub fn load(log: &::slog::Logger) -> Config {
+        Self::read().unwrap_or_else(|err| {
+            slog::warn!(log, "{}, using the default configuration", err; "path" => Self::path().display().to_string());
+            Config::default()
+        })
+    }
+}
@@ -0,0 +1,312 @@
+use std::{cell::RefCell, collections::VecDeque, sync::Mutex};
+
+use smithay::{
+    backend::allocator::dmabuf::{Dmabuf, WeakDmabuf},
+    reexports::wayland_server::protocol::{wl_output, wl_surface::WlSurface},
+    utils::{Logical, Physical, Point, Rectangle, Size},
+    wayland::{
+        compositor::{
+            self, with_states, with_surface_tree_downward, with_surface_tree_upward, Damage,
+            SubsurfaceCachedState, SurfaceAttributes, TraversalAction,
+        },
+        seat::{CursorImageAttributes, CursorImageStatus},
+        shell::wlr_layer::Layer,
+    },
+};
+
+use crate::{
+    gui::widget::Widget,
+    output_map::OutputMap,
+    shell::SurfaceData,
+    window_map::{Kind, Placement, WindowMap},
+};
+
+/// How many frames back the damage is remembered, which is the most buffers a swapchain has.
+const MAX_AGE: usize = 4;
+
+/// Returns the part of `rect` inside `area`, if any.
+pub fn clip(rect: Rectangle<i32, Logical>, area: Rectangle<i32, Logical>) -> Option<Rectangle<i32, Logical>> {
+    let x = rect.loc.x.max(area.loc.x);
+    let y = rect.loc.y.max(area.loc.y);
+    let right = (rect.loc.x.saturating_add(rect.size.w)).min(area.loc.x + area.size.w);
+    let bottom = (rect.loc.y.saturating_add(rect.size.h)).min(area.loc.y + area.size.h);
+    if right <= x || bottom <= y {
+        None
+    } else {
+        Some(Rectangle::from_loc_and_size((x, y), (right - x, bottom - y)))
+    }
+}
+
+/// Converts an area of an output to physical pixels, rounding outwards.
+pub fn to_physical(rect: Rectangle<i32, Logical>, scale: f32) -> Rectangle<i32, Physical> {
+    let rect = rect.to_f64().to_physical(scale as f64);
+    let (x, y) = (rect.loc.x.floor(), rect.loc.y.floor());
+    let right = (rect.loc.x + rect.size.w).ceil();
+    let bottom = (rect.loc.y + rect.size.h).ceil();
+    Rectangle::from_loc_and_size((x as i32, y as i32), ((right - x) as i32, (bottom - y) as i32))
+}
+
+/// Adds the damage just committed to a surface tree to the outputs showing it.
+pub fn surface_committed(surface: &WlSurface, window_map: &WindowMap, output_map: &OutputMap) {
+    let mut root = surface.clone();
+    while let Some(parent) = compositor::get_parent(&root) {
+        root = parent;
+    }
+
+    let placement = if window_map.find(&root).is_some() {
+        let mut top = false;
+        window_map.with_top_window(|toplevel, _, _| top = toplevel.get_surface() == Some(&root));
+        match window_map.top_placement() {
+            Some(placement) if top => placement,
+            // Only the topmost window is drawn
+            _ => return,
+        }
+    } else if let Some(layer) = window_map.layers.find(&root) {
+        Placement::at(layer.location)
+    } else {
+        // Popups, cursors and drag icons are small and rare enough to redraw everything
+        output_map.damage_all();
+        return;
+    };
+
+    let mut damage = Vec::new();
+    with_surface_tree_upward(
+        &root,
+        Point::<i32, Logical>::from((0, 0)),
+        |_, states, location| {
+            let mut location = *location;
+            if states.role == Some("subsurface") {
+                location += states.cached_state.current::<SubsurfaceCachedState>().location;
+            }
+            TraversalAction::DoChildren(location)
+        },
+        |_, states, location| {
+            let mut location = *location;
+            if states.role == Some("subsurface") {
+                location += states.cached_state.current::<SubsurfaceCachedState>().location;
+            }
+            let size = match states.data_map.get::<RefCell<SurfaceData>>() {
+                Some(data) => data.borrow().size().unwrap_or_default(),
+                None => return,
+            };
+            let attributes = states.cached_state.current::<SurfaceAttributes>();
+            for rect in attributes.damage.iter() {
+                let rect = match *rect {
+                    Damage::Surface(rect) => rect,
+                    Damage::Buffer(rect) if attributes.buffer_transform == wl_output::Transform::Normal => {
+                        rect.to_logical(attributes.buffer_scale)
+                    }
+                    // Transformed buffer damage is not worth mapping back to the surface
+                    Damage::Buffer(_) => Rectangle::from_loc_and_size((0, 0), size),
+                };
+                damage.push(Rectangle::from_loc_and_size(rect.loc + location, rect.size));
+            }
+        },
+        |_, _, _| true,
+    );
+
+    for rect in damage {
+        output_map.damage(placement.apply_rect(rect));
+    }
+}
+
+/// Returns the area covered by a surface tree drawn at `location`.
+fn surface_tree_area(root: &WlSurface, location: Point<i32, Logical>) -> Rectangle<i32, Logical> {
+    let mut area = Rectangle::from_loc_and_size(location, (0, 0));
+    with_surface_tree_downward(
+        root,
+        location,
+        |_, states, &location| {
+            let mut location = location;
+            let data = states.data_map.get::<RefCell<SurfaceData>>();
+            if let Some(size) = data.and_then(|data| data.borrow().size()) {
+                if states.role == Some("subsurface") {
+                    location += states.cached_state.current::<SubsurfaceCachedState>().location;
+                }
+                area = area.merge(Rectangle::from_loc_and_size(location, size));
+                TraversalAction::DoChildren(location)
+            } else {
+                TraversalAction::SkipChildren
+            }
+        },
+        |_, _, _| {},
+        |_, _, _| true,
+    );
+    area
+}
+
+/// Returns the area covered by the drag icon and the cursor drawn at `location`, the
+/// default cursor being an image of `default_size`.
+pub fn cursor_area(
+    location: Point<i32, Logical>,
+    dnd_icon: &Option<WlSurface>,
+    cursor_status: &CursorImageStatus,
+    default_size: Size<i32, Logical>,
+) -> Option<Rectangle<i32, Logical>> {
+    let icon = dnd_icon
+        .as_ref()
+        .filter(|surface| surface.as_ref().is_alive())
+        .map(|surface| surface_tree_area(surface, location));
+    let cursor = match cursor_status {
+        CursorImageStatus::Image(surface) => {
+            let hotspot = with_states(surface, |states| {
+                states
+                    .data_map
+                    .get::<Mutex<CursorImageAttributes>>()
+                    .map(|attributes| attributes.lock().unwrap().hotspot)
+            })
+            .ok()
+            .flatten()
+            .unwrap_or_default();
+            Some(surface_tree_area(surface, location - hotspot))
+        }
+        CursorImageStatus::Default => Some(Rectangle::from_loc_and_size(location, default_size)),
+        CursorImageStatus::Hidden => None,
+    };
+    match (icon, cursor) {
+        (Some(icon), Some(cursor)) => Some(icon.merge(cursor)),
+        (icon, cursor) => icon.or(cursor),
+    }
+}
+
+/// What an output shows, besides the contents of the surfaces.
+///
+/// Anything changing here redraws the whole output, apart from the cursor.
+#[derive(Debug, PartialEq)]
+pub struct Scene {
+    /// The window shown, its bounding box and where it is drawn.
+    top: Option<(Kind, Rectangle<i32, Logical>, Option<Placement>)>,
+    popups: Vec<WlSurface>,
+    menu_on_top: bool,
+    layers: Vec<Rectangle<i32, Logical>>,
+    widgets: Vec<Widget>,
+    /// The area covered by the cursor, and the frame of the default cursor shown.
+    cursor: Option<(Rectangle<i32, Logical>, Option<usize>)>,
+}
+
+impl Scene {
+    pub fn new(
+        window_map: &WindowMap,
+        widgets: &[Widget],
+        cursor: Option<(Rectangle<i32, Logical>, Option<usize>)>,
+    ) -> Scene {
+        let mut top = None;
+        window_map.with_top_window(|toplevel, _, &bbox| top = Some((toplevel.clone(), bbox)));
+
+        let mut popups = Vec::new();
+        if let Some(surface) = top.as_ref().and_then(|(toplevel, _)| toplevel.get_surface()) {
+            window_map.with_child_popups(surface, |popup| popups.extend(popup.get_surface().cloned()));
+        }
+
+        let mut layers = Vec::new();
+        for layer in [Layer::Background, Layer::Bottom, Layer::Top, Layer::Overlay] {
+            window_map
+                .layers
+                .with_layers_from_bottom_to_top(&layer, |layer_surface| layers.push(layer_surface.bbox));
+        }
+
+        Scene {
+            top: top.map(|(toplevel, bbox)| (toplevel, bbox, window_map.top_placement())),
+            popups,
+            menu_on_top: window_map.menu_on_top,
+            layers,
+            widgets: widgets.to_vec(),
+            cursor,
+        }
+    }
+
+    /// Returns what changed since the `previous` scene, on an output of `size`.
+    fn damage_since(&self, previous: &Scene, size: Size<i32, Logical>) -> Option<Rectangle<i32, Logical>> {
+        if self.top != previous.top
+            || self.popups != previous.popups
+            || self.menu_on_top != previous.menu_on_top
+            || self.layers != previous.layers
+            || self.widgets != previous.widgets
+        {
+            return Some(Rectangle::from_loc_and_size((0, 0), size));
+        }
+        if self.cursor == previous.cursor {
+            return None;
+        }
+        match (previous.cursor, self.cursor) {
+            (Some((old, _)), Some((new, _))) => Some(old.merge(new)),
+            (old, new) => old.or(new).map(|(area, _)| area),
+        }
+    }
+}
+
+/// Remembers what the last frames of an output changed, to redraw the buffers of its
+/// swapchain only where they are out of date.
+#[derive(Debug, Default)]
+pub struct DamageHistory {
+    scene: Option<Scene>,
+    size: Size<i32, Logical>,
+    /// The damage of the last frames, the most recent first.
+    frames: VecDeque<Rectangle<i32, Logical>>,
+    /// The frame each buffer was last drawn in.
+    buffers: Vec<(WeakDmabuf, u64)>,
+    frame: u64,
+}
+
+impl DamageHistory {
+    /// Returns what changed on an output of `size` since its last frame, given the damage
+    /// committed by clients meanwhile, or `None` if nothing did.
+    pub fn frame_damage(
+        &mut self,
+        scene: Scene,
+        damage: Option<Rectangle<i32, Logical>>,
+        size: Size<i32, Logical>,
+    ) -> Option<Rectangle<i32, Logical>> {
+        let output = Rectangle::from_loc_and_size((0, 0), size);
+        let changed = match self.scene.as_ref() {
+            Some(previous) if self.size == size => scene.damage_since(previous, size),
+            _ => {
+                self.invalidate();
+                Some(output)
+            }
+        };
+        self.scene = Some(scene);
+        self.size = size;
+
+        let damage = match (damage, changed) {
+            (Some(damage), Some(changed)) => Some(damage.merge(changed)),
+            (damage, changed) => damage.or(changed),
+        };
+        damage.and_then(|damage| clip(damage, output))
+    }
+
+    /// Records that `damage` is drawn into `buffer`, and returns the part of the buffer to
+    /// redraw for it to be up to date.
+    pub fn repaint(&mut self, buffer: &Dmabuf, damage: Rectangle<i32, Logical>) -> Rectangle<i32, Logical> {
+        self.frame += 1;
+        self.frames.push_front(damage);
+        self.frames.truncate(MAX_AGE);
+
+        let last_drawn = self
+            .buffers
+            .iter()
+            .find(|(weak, _)| weak.upgrade().as_ref() == Some(buffer))
+            .map(|&(_, frame)| frame);
+        let repaint = match last_drawn {
+            Some(frame) if (self.frame - frame) as usize <= self.frames.len() => self
+                .frames
+                .iter()
+                .take((self.frame - frame) as usize)
+                .fold(damage, |repaint, &damage| repaint.merge(damage)),
+            // A new buffer, or one too old to know what it holds
+            _ => Rectangle::from_loc_and_size((0, 0), self.size),
+        };
+
+        self.buffers
+            .retain(|(weak, _)| !weak.is_gone() && weak.upgrade().as_ref() != Some(buffer));
+        self.buffers.push((buffer.weak(), self.frame));
+        repaint
+    }
+
+    /// Forgets what the buffers hold, when they were not drawn by us or the output showed
+    /// something else meanwhile.
+    pub fn invalidate(&mut self) {
+        self.buffers.clear();
+        self.frames.clear();
+    }
+}
@@ -2,10 +2,8 @@
 
 use std::{cell::RefCell, sync::Mutex};
 
-#[cfg(feature = "image")]
 use image::{ImageBuffer, Rgba};
 use slog::Logger;
-#[cfg(feature = "image")]
 use smithay::backend::renderer::gles2::{Gles2Error, Gles2Renderer, Gles2Texture};
 use smithay::{
     backend::{
@@ -24,7 +22,7 @@ use smithay::{
     },
 };
 
-use crate::{shell::SurfaceData, window_map::{Kind, WindowMap}};
+use crate::{shell::SurfaceData, window_map::{Kind, Placement, WindowMap}};
 
 struct BufferTextures<T> {
     buffer: Option<wl_buffer::WlBuffer>,
@@ -86,6 +84,23 @@ fn draw_surface_tree<R, E, F, T>(
     output_scale: f32,
     log: &Logger,
 ) -> Result<(), SwapBuffersError>
+where
+    R: Renderer<Error = E, TextureId = T, Frame = F> + ImportAll,
+    F: Frame<Error = E, TextureId = T>,
+    E: std::error::Error + Into<SwapBuffersError>,
+    T: Texture + 'static,
+{
+    draw_placed_surface_tree(renderer, frame, root, Placement::at(location), output_scale, log)
+}
+
+fn draw_placed_surface_tree<R, E, F, T>(
+    renderer: &mut R,
+    frame: &mut F,
+    root: &wl_surface::WlSurface,
+    placement: Placement,
+    output_scale: f32,
+    log: &Logger,
+) -> Result<(), SwapBuffersError>
 where
     R: Renderer<Error = E, TextureId = T, Frame = F> + ImportAll,
     F: Frame<Error = E, TextureId = T>,
@@ -96,7 +111,7 @@ where
 
     with_surface_tree_upward(
         root,
-        location,
+        Point::<i32, Logical>::from((0, 0)),
         |_surface, states, location| {
             let mut location = *location;
             // Pull a new buffer if available
@@ -172,14 +187,36 @@ where
                         let current = states.cached_state.current::<SubsurfaceCachedState>();
                         location += current.location;
                     }
-                    if let Err(err) = frame.render_texture_at(
-                        &texture.texture,
-                        location.to_f64().to_physical(output_scale as f64).to_i32_round(),
-                        buffer_scale,
-                        output_scale as f64,
-                        Transform::Normal, /* TODO */
-                        1.0,
-                    ) {
+                    let location = placement
+                        .apply(location.to_f64())
+                        .to_physical(output_scale as f64);
+                    let rendered = if placement.is_scaled() {
+                        let size = texture.texture.size();
+                        let logical_size = size.to_logical(buffer_scale).to_f64();
+                        frame.render_texture_from_to(
+                            &texture.texture,
+                            Rectangle::from_loc_and_size((0, 0), size),
+                            Rectangle::from_loc_and_size(
+                                location,
+                                (
+                                    logical_size.w * placement.scale.0 * output_scale as f64,
+                                    logical_size.h * placement.scale.1 * output_scale as f64,
+                                ),
+                            ),
+                            Transform::Normal,
+                            1.0,
+                        )
+                    } else {
+                        frame.render_texture_at(
+                            &texture.texture,
+                            location.to_i32_round(),
+                            buffer_scale,
+                            output_scale as f64,
+                            Transform::Normal, /* TODO */
+                            1.0,
+                        )
+                    };
+                    if let Err(err) = rendered {
                         result = Err(err.into());
                     }
                 }
@@ -208,15 +245,21 @@ where
     let mut result = Ok(());
 
      // redraw the frame, in a simple but inneficient way
-    window_map.with_top_window(|toplevel_surface, mut initial_place, &bounding_box| {
+    window_map.with_top_window(|toplevel_surface, _, &bounding_box| {
         // skip windows that do not overlap with a given output
         if !output_rect.overlaps(bounding_box) {
             return;
         }
-        initial_place.x -= output_rect.loc.x;
+        // games that do not match the output are scaled following their scaling mode
+        let mut placement = match window_map.top_placement() {
+            Some(placement) => placement,
+            None => return,
+        };
+        placement.origin.x -= output_rect.loc.x as f64;
         if let Some(wl_surface) = toplevel_surface.get_surface() {
             // this surface is a root of a subsurface tree that needs to be drawn
-            if let Err(err) = draw_surface_tree(renderer, frame, wl_surface, initial_place, output_scale, log)
+            if let Err(err) =
+                draw_placed_surface_tree(renderer, frame, wl_surface, placement, output_scale, log)
             {
                 result = Err(err);
             }
@@ -227,11 +270,16 @@ where
                 .unwrap_or_default();
             window_map.with_child_popups(wl_surface, |popup| {
                 let location = popup.location();
-                let draw_location = initial_place + location + toplevel_geometry_offset;
+                let draw_placement = placement.offset(location + toplevel_geometry_offset);
                 if let Some(wl_surface) = popup.get_surface() {
-                    if let Err(err) =
-                        draw_surface_tree(renderer, frame, wl_surface, draw_location, output_scale, log)
-                    {
+                    if let Err(err) = draw_placed_surface_tree(
+                        renderer,
+                        frame,
+                        wl_surface,
+                        draw_placement,
+                        output_scale,
+                        log,
+                    ) {
                         result = Err(err);
                     }
                 }
@@ -416,7 +464,6 @@ where
     Ok(())
 }
 
-#[cfg(feature = "image")]
 pub fn import_bitmap<C: std::ops::Deref<Target = [u8]>>(
     renderer: &mut Gles2Renderer,
     image: &ImageBuffer<Rgba<u8>, C>,
@@ -0,0 +1,550 @@
+use std::{
+    io,
+    mem,
+    os::unix::io::RawFd,
+    path::{Path, PathBuf},
+};
+
+use serde::Deserialize;
+use smithay::reexports::{
+    calloop::{generic::Generic, Interest, LoopHandle, Mode, PostAction, RegistrationToken},
+    nix::{libc, request_code_read},
+};
+
+use crate::{
+    gui::osd::Toast,
+    state::{AnvilState, Backend},
+};
+
+mod uinput;
+pub use uinput::VirtualGamepad;
+
+mod ioctl {
+    use smithay::reexports::nix::{ioctl_read_buf, ioctl_write_int};
+
+    ioctl_read_buf!(eviocgname, b'E', 0x06, u8);
+    ioctl_write_int!(eviocgrab, b'E', 0x90);
+}
+
+const EV_SYN: u16 = 0x00;
+const EV_KEY: u16 = 0x01;
+const EV_ABS: u16 = 0x03;
+
+const ABS_X: u16 = 0x00;
+const ABS_Y: u16 = 0x01;
+const ABS_Z: u16 = 0x02;
+const ABS_RX: u16 = 0x03;
+const ABS_RY: u16 = 0x04;
+const ABS_RZ: u16 = 0x05;
+const ABS_HAT0X: u16 = 0x10;
+const ABS_HAT0Y: u16 = 0x11;
+
+/// A gamepad button, named after its position like the Linux `BTN_*` codes.
+///
+/// In the config file, buttons are written in kebab-case (`left-trigger`, `dpad-up`), and
+/// the shoulder buttons also go by `l1`, `r1`, `l2`, `r2`, `l3` and `r3`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Button {
+    South,
+    East,
+    North,
+    West,
+    #[serde(alias = "l1")]
+    LeftTrigger,
+    #[serde(alias = "r1")]
+    RightTrigger,
+    #[serde(alias = "l2")]
+    LeftTrigger2,
+    #[serde(alias = "r2")]
+    RightTrigger2,
+    Select,
+    Start,
+    Mode,
+    #[serde(alias = "l3")]
+    LeftThumb,
+    #[serde(alias = "r3")]
+    RightThumb,
+    #[serde(rename = "dpad-up")]
+    DPadUp,
+    #[serde(rename = "dpad-down")]
+    DPadDown,
+    #[serde(rename = "dpad-left")]
+    DPadLeft,
+    #[serde(rename = "dpad-right")]
+    DPadRight,
+}
+
+impl Button {
+    const ALL: [Button; 17] = [
+        Button::South,
+        Button::East,
+        Button::North,
+        Button::West,
+        Button::LeftTrigger,
+        Button::RightTrigger,
+        Button::LeftTrigger2,
+        Button::RightTrigger2,
+        Button::Select,
+        Button::Start,
+        Button::Mode,
+        Button::LeftThumb,
+        Button::RightThumb,
+        Button::DPadUp,
+        Button::DPadDown,
+        Button::DPadLeft,
+        Button::DPadRight,
+    ];
+
+    /// The evdev `BTN_*` code of this button.
+    fn code(self) -> u16 {
+        match self {
+            Button::South => 0x130,
+            Button::East => 0x131,
+            Button::North => 0x133,
+            Button::West => 0x134,
+            Button::LeftTrigger => 0x136,
+            Button::RightTrigger => 0x137,
+            Button::LeftTrigger2 => 0x138,
+            Button::RightTrigger2 => 0x139,
+            Button::Select => 0x13a,
+            Button::Start => 0x13b,
+            Button::Mode => 0x13c,
+            Button::LeftThumb => 0x13d,
+            Button::RightThumb => 0x13e,
+            Button::DPadUp => 0x220,
+            Button::DPadDown => 0x221,
+            Button::DPadLeft => 0x222,
+            Button::DPadRight => 0x223,
+        }
+    }
+
+    fn from_code(code: u16) -> Option<Button> {
+        Button::ALL.iter().copied().find(|button| button.code() == code)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GamepadEvent {
+    Pressed(Button),
+    Released(Button),
+}
+
+/// A direction of the hat or the left stick, as -1, 0 or 1 on each axis.
+type Direction = [i32; 2];
+
+const DPAD: [[Button; 2]; 2] = [
+    [Button::DPadLeft, Button::DPadRight],
+    [Button::DPadUp, Button::DPadDown],
+];
+
+/// Turns a change of direction on one axis into DPad presses and releases.
+fn direction_events(axis: usize, old: i32, new: i32, events: &mut Vec<GamepadEvent>) {
+    if old == new {
+        return;
+    }
+    if old != 0 {
+        events.push(GamepadEvent::Released(DPAD[axis][(old > 0) as usize]));
+    }
+    if new != 0 {
+        events.push(GamepadEvent::Pressed(DPAD[axis][(new > 0) as usize]));
+    }
+}
+
+/// An evdev event, as type, code and value.
+type RawEvent = (u16, u16, i32);
+
+#[derive(Debug)]
+struct Gamepad {
+    path: PathBuf,
+    name: String,
+    fd: RawFd,
+    token: RegistrationToken,
+    /// Range of the axes the virtual gamepads also have, as code, minimum and maximum.
+    ranges: Vec<(u16, i32, i32)>,
+    hat: Direction,
+    stick: Direction,
+}
+
+impl Gamepad {
+    fn range(&self, code: u16) -> Option<(i32, i32)> {
+        self.ranges
+            .iter()
+            .find(|(c, _, _)| *c == code)
+            .map(|&(_, minimum, maximum)| (minimum, maximum))
+    }
+
+    /// Rescales an event to the ranges of the virtual gamepads, if they have its button or axis.
+    ///
+    /// The Mode button always stays with the compositor, and the buttons of the chords are
+    /// filtered out later by `read_gamepad`.
+    fn to_virtual(&self, event: &libc::input_event) -> Option<RawEvent> {
+        match event.type_ {
+            EV_SYN if event.code == 0 => Some((EV_SYN, 0, 0)),
+            EV_KEY => Button::from_code(event.code)
+                .filter(|button| *button != Button::Mode)
+                .map(|_| (EV_KEY, event.code, event.value)),
+            EV_ABS => {
+                let (minimum, maximum) = self.range(event.code)?;
+                let &(_, target_minimum, target_maximum) =
+                    uinput::AXES.iter().find(|(code, _, _)| *code == event.code)?;
+                let value = target_minimum as i64
+                    + (event.value - minimum) as i64 * (target_maximum - target_minimum) as i64
+                        / (maximum - minimum) as i64;
+                Some((EV_ABS, event.code, value as i32))
+            }
+            _ => None,
+        }
+    }
+
+    fn translate(&mut self, event: &libc::input_event, events: &mut Vec<GamepadEvent>) {
+        match (event.type_, event.code) {
+            (EV_KEY, code) => {
+                if let Some(button) = Button::from_code(code) {
+                    events.push(match event.value {
+                        0 => GamepadEvent::Released(button),
+                        1 => GamepadEvent::Pressed(button),
+                        // Autorepeat, the menu does its own
+                        _ => return,
+                    });
+                }
+            }
+            (EV_ABS, code @ (ABS_HAT0X | ABS_HAT0Y)) => {
+                let axis = (code - ABS_HAT0X) as usize;
+                let new = event.value.signum();
+                direction_events(axis, self.hat[axis], new, events);
+                self.hat[axis] = new;
+            }
+            (EV_ABS, code @ (ABS_X | ABS_Y)) => {
+                let axis = code as usize;
+                if let Some((min, max)) = self.range(code) {
+                    // Half way to the edge counts as a press
+                    let center = (min + max) / 2;
+                    let threshold = (max - min) / 4;
+                    let new = if event.value < center - threshold {
+                        -1
+                    } else if event.value > center + threshold {
+                        1
+                    } else {
+                        0
+                    };
+                    direction_events(axis, self.stick[axis], new, events);
+                    self.stick[axis] = new;
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// The evdev gamepads opened by the compositor.
+///
+/// Games get their own virtual gamepad, which only receives the inputs while the game is
+/// shown. The physical gamepads are then grabbed with `EVIOCGRAB`, so games reading them
+/// directly do not see anything. Without a virtual gamepad, the game reads the physical
+/// ones, which are only grabbed while the menu is shown.
+#[derive(Debug, Default)]
+pub struct GamepadManager {
+    gamepads: Vec<Gamepad>,
+    grabbed: bool,
+    /// The game receiving the inputs through its virtual gamepad.
+    target: Option<u32>,
+    next_product_id: u16,
+}
+
+impl GamepadManager {
+    pub fn new() -> GamepadManager {
+        GamepadManager::default()
+    }
+
+    /// The game receiving the inputs through its virtual gamepad, if any.
+    pub fn target(&self) -> Option<u32> {
+        self.target
+    }
+
+    /// Creates the virtual gamepad of a game about to be started.
+    pub fn create_virtual(&mut self, name: &str, log: &slog::Logger) -> Option<VirtualGamepad> {
+        self.next_product_id = self.next_product_id.wrapping_add(1);
+        match VirtualGamepad::new(name, self.next_product_id) {
+            Ok(gamepad) => {
+                debug!(log, "Created virtual gamepad"; "name" => name, "path" => gamepad.devnode().display().to_string());
+                Some(gamepad)
+            }
+            Err(err) => {
+                warn!(log, "Unable to create a virtual gamepad, the game will read the gamepads directly: {}", err; "name" => name);
+                None
+            }
+        }
+    }
+
+    /// Grabs or releases all gamepads.
+    pub fn set_grabbed(&mut self, grabbed: bool, log: &slog::Logger) {
+        if grabbed == self.grabbed {
+            return;
+        }
+        self.grabbed = grabbed;
+        for gamepad in &self.gamepads {
+            grab(gamepad.fd, grabbed, &gamepad.name, log);
+        }
+    }
+}
+
+fn grab(fd: RawFd, grabbed: bool, name: &str, log: &slog::Logger) {
+    if let Err(err) = unsafe { ioctl::eviocgrab(fd, grabbed as _) } {
+        warn!(log, "Unable to change the gamepad grab: {}", err; "name" => name, "grabbed" => grabbed);
+    }
+}
+
+/// Returns the range of an axis through `EVIOCGABS`, if the device has it.
+fn axis_range(fd: RawFd, axis: u16) -> Option<(i32, i32)> {
+    let request = request_code_read!(b'E', 0x40 + axis, mem::size_of::<libc::input_absinfo>());
+    let mut info: libc::input_absinfo = unsafe { mem::zeroed() };
+    let result = unsafe { libc::ioctl(fd, request as _, &mut info) };
+    if result < 0 || info.maximum <= info.minimum {
+        None
+    } else {
+        Some((info.minimum, info.maximum))
+    }
+}
+
+fn device_name(fd: RawFd) -> String {
+    let mut buf = [0u8; 256];
+    match unsafe { ioctl::eviocgname(fd, &mut buf) } {
+        Ok(_) => {
+            let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+            String::from_utf8_lossy(&buf[..len]).into_owned()
+        }
+        Err(_) => String::from("Unknown gamepad"),
+    }
+}
+
+/// Returns `true` for the evdev nodes udev tagged as joysticks, except our virtual gamepads.
+fn is_gamepad(device: &udev::Device) -> bool {
+    let is_virtual = device
+        .parent()
+        .and_then(|parent| {
+            parent
+                .attribute_value("id/vendor")
+                .map(|vendor| vendor.to_string_lossy() == format!("{:04x}", uinput::VENDOR_ID))
+        })
+        .unwrap_or(false);
+    device.property_value("ID_INPUT_JOYSTICK").map(|v| v == "1").unwrap_or(false)
+        && device.sysname().to_string_lossy().starts_with("event")
+        && !is_virtual
+}
+
+/// Watches udev for gamepads being plugged in or removed.
+///
+/// Gamepads already present are opened from an idle callback, once the state exists.
+pub fn init_gamepads<BackendData: Backend + 'static>(
+    handle: &LoopHandle<'static, AnvilState<BackendData>>,
+    log: &slog::Logger,
+) {
+    let monitor = udev::MonitorBuilder::new()
+        .and_then(|builder| builder.match_subsystem("input"))
+        .and_then(|builder| builder.listen());
+    match monitor {
+        Ok(monitor) => {
+            handle
+                .insert_source(
+                    Generic::new(monitor, Interest::READ, Mode::Level),
+                    |_, monitor, state: &mut AnvilState<BackendData>| {
+                        for event in monitor.iter() {
+                            let path = match event.devnode() {
+                                Some(path) => path.to_owned(),
+                                None => continue,
+                            };
+                            match event.event_type() {
+                                udev::EventType::Add if is_gamepad(&event) => state.add_gamepad(&path),
+                                udev::EventType::Remove => {
+                                    if let Some(name) = state.remove_gamepad(&path) {
+                                        state.osd.post(Toast::text(format!("{} disconnected", name)).warning());
+                                    }
+                                }
+                                _ => {}
+                            }
+                        }
+                        Ok(PostAction::Continue)
+                    },
+                )
+                .expect("Failed to init the gamepad monitor");
+        }
+        Err(err) => error!(log, "Unable to watch for gamepads: {}", err),
+    }
+
+    handle.insert_idle(|state| state.scan_gamepads());
+}
+
+impl<BackendData: Backend + 'static> AnvilState<BackendData> {
+    /// Opens all gamepads not opened yet.
+    pub fn scan_gamepads(&mut self) {
+        let devices = udev::Enumerator::new().and_then(|mut enumerator| {
+            enumerator.match_subsystem("input")?;
+            enumerator.match_property("ID_INPUT_JOYSTICK", "1")?;
+            Ok(enumerator.scan_devices()?.collect::<Vec<_>>())
+        });
+        match devices {
+            Ok(devices) => {
+                for device in devices.iter().filter(|device| is_gamepad(device)) {
+                    if let Some(path) = device.devnode() {
+                        if !self.gamepads.gamepads.iter().any(|g| g.path == path) {
+                            self.add_gamepad(path);
+                        }
+                    }
+                }
+            }
+            Err(err) => warn!(self.log, "Unable to list gamepads: {}", err),
+        }
+    }
+
+    fn add_gamepad(&mut self, path: &Path) {
+        let fd = match self.backend_data.open_device(path) {
+            Ok(fd) => fd,
+            Err(err) => {
+                warn!(self.log, "Unable to open gamepad: {}", err; "path" => path.display().to_string());
+                return;
+            }
+        };
+
+        let token = self.handle.insert_source(
+            Generic::from_fd(fd, Interest::READ, Mode::Level),
+            move |_, _, state: &mut AnvilState<BackendData>| Ok(state.read_gamepad(fd)),
+        );
+        let token = match token {
+            Ok(token) => token,
+            Err(err) => {
+                warn!(self.log, "Unable to watch gamepad: {}", err; "path" => path.display().to_string());
+                self.backend_data.close_device(fd);
+                return;
+            }
+        };
+
+        let gamepad = Gamepad {
+            path: path.to_owned(),
+            name: device_name(fd),
+            fd,
+            token,
+            ranges: uinput::AXES
+                .iter()
+                .filter_map(|&(code, _, _)| axis_range(fd, code).map(|(minimum, maximum)| (code, minimum, maximum)))
+                .collect(),
+            hat: [0, 0],
+            stick: [0, 0],
+        };
+        info!(self.log, "Gamepad connected"; "name" => &gamepad.name, "path" => path.display().to_string());
+        if self.gamepads.grabbed {
+            grab(fd, true, &gamepad.name, &self.log);
+        }
+        self.gamepads.gamepads.push(gamepad);
+    }
+
+    /// Closes a gamepad, returning its name if it was open.
+    fn remove_gamepad(&mut self, path: &Path) -> Option<String> {
+        let i = self.gamepads.gamepads.iter().position(|g| g.path == path)?;
+        let gamepad = self.gamepads.gamepads.remove(i);
+        info!(self.log, "Gamepad disconnected"; "name" => &gamepad.name);
+        self.handle.remove(gamepad.token);
+        self.backend_data.close_device(gamepad.fd);
+        Some(gamepad.name)
+    }
+
+    fn read_gamepad(&mut self, fd: RawFd) -> PostAction {
+        let mut raw = Vec::new();
+        let mut buf: [libc::input_event; 32] = unsafe { mem::zeroed() };
+        loop {
+            let len = unsafe { libc::read(fd, buf.as_mut_ptr() as *mut _, mem::size_of_val(&buf)) };
+            if len < 0 {
+                let err = io::Error::last_os_error();
+                if err.kind() == io::ErrorKind::WouldBlock {
+                    break;
+                }
+                // The device was unplugged or revoked by the session
+                if let Some(gamepad) = self.gamepads.gamepads.iter().find(|g| g.fd == fd) {
+                    debug!(self.log, "Unable to read gamepad: {}", err; "name" => &gamepad.name);
+                    let path = gamepad.path.clone();
+                    self.handle.insert_idle(move |state| {
+                        state.remove_gamepad(&path);
+                    });
+                }
+                return PostAction::Disable;
+            }
+
+            let count = len as usize / mem::size_of::<libc::input_event>();
+            raw.extend_from_slice(&buf[..count]);
+            if count < buf.len() {
+                break;
+            }
+        }
+
+        // Each event goes through the bindings before being forwarded, to know whether its
+        // button is part of a chord
+        let mut forwarded = Vec::new();
+        let mut notified = false;
+        for event in &raw {
+            let mut events = Vec::new();
+            let virtual_event = match self.gamepads.gamepads.iter_mut().find(|g| g.fd == fd) {
+                Some(gamepad) => {
+                    gamepad.translate(event, &mut events);
+                    gamepad.to_virtual(event)
+                }
+                None => break,
+            };
+            let active =
+                !events.is_empty() || matches!(virtual_event, Some((type_, _, _)) if type_ != EV_SYN);
+            if active && !notified {
+                self.notify_activity();
+                self.schedule_render();
+                notified = true;
+            }
+
+            let button = Button::from_code(event.code).filter(|_| event.type_ == EV_KEY);
+            // Releases and autorepeats follow their press, which completed a chord or not
+            let mut suppressed =
+                event.value != 1 && matches!(button, Some(button) if self.bindings.is_suppressed(button));
+            for gamepad_event in events {
+                self.on_gamepad_event(gamepad_event);
+            }
+            suppressed |=
+                event.value == 1 && matches!(button, Some(button) if self.bindings.is_suppressed(button));
+            if !suppressed {
+                forwarded.extend(virtual_event);
+            }
+        }
+
+        if let Some(gamepad) = self
+            .gamepads
+            .target
+            .and_then(|pid| self.games.get_mut(pid))
+            .and_then(|game| game.gamepad_mut())
+        {
+            gamepad.write(&forwarded);
+        }
+        PostAction::Continue
+    }
+}
+
+impl<BackendData: 'static> AnvilState<BackendData> {
+    /// Sends the gamepad inputs to the virtual gamepad of the game shown, if any, and grabs
+    /// the physical gamepads when nobody should read them directly.
+    pub fn route_gamepads(&mut self, visible_game: Option<u32>) {
+        let menu_on_top = self.window_map.borrow().menu_on_top;
+        let target = visible_game.filter(|_| !menu_on_top);
+        if target != self.gamepads.target {
+            if let Some(gamepad) = self
+                .gamepads
+                .target
+                .and_then(|pid| self.games.get_mut(pid))
+                .and_then(|game| game.gamepad_mut())
+            {
+                gamepad.reset();
+            }
+            self.gamepads.target = target;
+        }
+
+        let target_has_virtual = target
+            .and_then(|pid| self.games.get(pid))
+            .map(|game| game.gamepad().is_some())
+            .unwrap_or(false);
+        self.gamepads.set_grabbed(menu_on_top || target_has_virtual, &self.log);
+    }
+}
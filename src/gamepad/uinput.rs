@@ -0,0 +1,226 @@
+use std::{
+    collections::HashSet,
+    fs::OpenOptions,
+    io, mem,
+    os::unix::{
+        fs::OpenOptionsExt,
+        io::{IntoRawFd, RawFd},
+    },
+    path::{Path, PathBuf},
+};
+
+use smithay::reexports::nix::libc;
+
+use super::{
+    Button, ABS_HAT0X, ABS_HAT0Y, ABS_RX, ABS_RY, ABS_RZ, ABS_X, ABS_Y, ABS_Z, EV_ABS, EV_KEY, EV_SYN,
+};
+
+mod ioctl {
+    use smithay::reexports::nix::{
+        ioctl_none, ioctl_read_buf, ioctl_write_int, ioctl_write_ptr,
+        libc::{uinput_abs_setup, uinput_setup},
+    };
+
+    ioctl_none!(ui_dev_create, b'U', 1);
+    ioctl_none!(ui_dev_destroy, b'U', 2);
+    ioctl_write_ptr!(ui_dev_setup, b'U', 3, uinput_setup);
+    ioctl_write_ptr!(ui_abs_setup, b'U', 4, uinput_abs_setup);
+    ioctl_read_buf!(ui_get_sysname, b'U', 44, u8);
+    ioctl_write_int!(ui_set_evbit, b'U', 100);
+    ioctl_write_int!(ui_set_keybit, b'U', 101);
+    ioctl_write_int!(ui_set_absbit, b'U', 103);
+}
+
+const BUS_VIRTUAL: u16 = 0x06;
+/// Vendor id of the virtual gamepads, every game gets its own product id.
+pub const VENDOR_ID: u16 = 0x5753;
+
+/// Axes of the virtual gamepad and their ranges, physical gamepads are rescaled to these.
+pub const AXES: [(u16, i32, i32); 8] = [
+    (ABS_X, -32768, 32767),
+    (ABS_Y, -32768, 32767),
+    (ABS_RX, -32768, 32767),
+    (ABS_RY, -32768, 32767),
+    (ABS_Z, 0, 255),
+    (ABS_RZ, 0, 255),
+    (ABS_HAT0X, -1, 1),
+    (ABS_HAT0Y, -1, 1),
+];
+
+/// The value of an axis at rest: centered for sticks and hats, released for triggers.
+fn rest(minimum: i32, maximum: i32) -> i32 {
+    if minimum < 0 {
+        (minimum + maximum) / 2
+    } else {
+        minimum
+    }
+}
+
+fn to_io_error(err: impl std::fmt::Display) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, err.to_string())
+}
+
+/// A uinput gamepad created for a single game, fed with the inputs of the physical gamepads
+/// while that game is shown.
+#[derive(Debug)]
+pub struct VirtualGamepad {
+    fd: RawFd,
+    product_id: u16,
+    devnode: PathBuf,
+    /// Buttons currently held down, released when the game loses the inputs.
+    pressed: HashSet<u16>,
+}
+
+impl VirtualGamepad {
+    pub fn new(name: &str, product_id: u16) -> io::Result<VirtualGamepad> {
+        let fd = OpenOptions::new()
+            .write(true)
+            .custom_flags(libc::O_NONBLOCK | libc::O_CLOEXEC)
+            .open("/dev/uinput")?
+            .into_raw_fd();
+        // From here on, dropping the gamepad closes the fd
+        let mut gamepad = VirtualGamepad {
+            fd,
+            product_id,
+            devnode: PathBuf::new(),
+            pressed: HashSet::new(),
+        };
+        gamepad.setup(name)?;
+        gamepad.devnode = gamepad.find_devnode()?;
+        Ok(gamepad)
+    }
+
+    fn setup(&mut self, name: &str) -> io::Result<()> {
+        unsafe {
+            ioctl::ui_set_evbit(self.fd, EV_KEY as _).map_err(to_io_error)?;
+            for button in Button::ALL {
+                ioctl::ui_set_keybit(self.fd, button.code() as _).map_err(to_io_error)?;
+            }
+
+            ioctl::ui_set_evbit(self.fd, EV_ABS as _).map_err(to_io_error)?;
+            for (code, minimum, maximum) in AXES {
+                ioctl::ui_set_absbit(self.fd, code as _).map_err(to_io_error)?;
+                let mut setup: libc::uinput_abs_setup = mem::zeroed();
+                setup.code = code;
+                setup.absinfo.minimum = minimum;
+                setup.absinfo.maximum = maximum;
+                setup.absinfo.value = rest(minimum, maximum);
+                ioctl::ui_abs_setup(self.fd, &setup).map_err(to_io_error)?;
+            }
+
+            let mut setup: libc::uinput_setup = mem::zeroed();
+            setup.id.bustype = BUS_VIRTUAL;
+            setup.id.vendor = VENDOR_ID;
+            setup.id.product = self.product_id;
+            let name = format!("Waystation gamepad ({})", name);
+            for (dst, src) in setup
+                .name
+                .iter_mut()
+                .zip(name.bytes().take(libc::UINPUT_MAX_NAME_SIZE - 1))
+            {
+                *dst = src as libc::c_char;
+            }
+            ioctl::ui_dev_setup(self.fd, &setup).map_err(to_io_error)?;
+            ioctl::ui_dev_create(self.fd).map_err(to_io_error)?;
+        }
+        Ok(())
+    }
+
+    /// Finds the `/dev/input/event*` node of the device we created.
+    fn find_devnode(&self) -> io::Result<PathBuf> {
+        let mut buf = [0u8; 64];
+        unsafe { ioctl::ui_get_sysname(self.fd, &mut buf) }.map_err(to_io_error)?;
+        let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+        let sysname = String::from_utf8_lossy(&buf[..len]).into_owned();
+
+        std::fs::read_dir(format!("/sys/devices/virtual/input/{}", sysname))?
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.file_name().to_string_lossy().into_owned())
+            .find(|name| name.starts_with("event"))
+            .map(|event| PathBuf::from("/dev/input").join(event))
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "No event node for the virtual gamepad"))
+    }
+
+    pub fn devnode(&self) -> &Path {
+        &self.devnode
+    }
+
+    /// Environment variables pointing the game, and SDL in particular, to this gamepad only.
+    pub fn env(&self) -> Vec<(String, String)> {
+        let devnode = self.devnode.display().to_string();
+        vec![
+            ("WAYSTATION_GAMEPAD".to_owned(), devnode.clone()),
+            ("SDL_JOYSTICK_DEVICE".to_owned(), devnode),
+            (
+                "SDL_GAMECONTROLLER_IGNORE_DEVICES_EXCEPT".to_owned(),
+                format!("0x{:04x}/0x{:04x}", VENDOR_ID, self.product_id),
+            ),
+        ]
+    }
+
+    /// Writes evdev events, which must already be in the ranges of `AXES`.
+    pub fn write(&mut self, events: &[(u16, u16, i32)]) {
+        let events = events
+            .iter()
+            .map(|&(type_, code, value)| {
+                if type_ == EV_KEY {
+                    if value == 0 {
+                        self.pressed.remove(&code);
+                    } else {
+                        self.pressed.insert(code);
+                    }
+                }
+                let mut event: libc::input_event = unsafe { mem::zeroed() };
+                event.type_ = type_;
+                event.code = code;
+                event.value = value;
+                event
+            })
+            .collect::<Vec<_>>();
+        if events.is_empty() {
+            return;
+        }
+        let len = mem::size_of::<libc::input_event>() * events.len();
+        if unsafe { libc::write(self.fd, events.as_ptr() as *const _, len) } < 0 {
+            slog_scope::debug!(
+                "Unable to write to the virtual gamepad: {}",
+                io::Error::last_os_error()
+            );
+        }
+    }
+
+    /// Presses or releases buttons, as if from a physical gamepad.
+    pub fn set_buttons(&mut self, buttons: &[Button], pressed: bool) {
+        let mut events = buttons
+            .iter()
+            .map(|button| (EV_KEY, button.code(), pressed as i32))
+            .collect::<Vec<_>>();
+        events.push((EV_SYN, 0, 0));
+        self.write(&events);
+    }
+
+    /// Releases all buttons and centers all axes, so nothing stays stuck while the game
+    /// does not get the inputs.
+    pub fn reset(&mut self) {
+        let mut events = self
+            .pressed
+            .iter()
+            .map(|&code| (EV_KEY, code, 0))
+            .collect::<Vec<_>>();
+        events.extend(
+            AXES.iter()
+                .map(|&(code, minimum, maximum)| (EV_ABS, code, rest(minimum, maximum))),
+        );
+        events.push((EV_SYN, 0, 0));
+        self.write(&events);
+    }
+}
+
+impl Drop for VirtualGamepad {
+    fn drop(&mut self) {
+        unsafe {
+            let _ = ioctl::ui_dev_destroy(self.fd);
+            libc::close(self.fd);
+        }
+    }
+}
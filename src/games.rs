@@ -0,0 +1,591 @@
+use std::{
+    process::{Child, Command},
+    time::Duration,
+};
+
+use smithay::reexports::{
+    calloop::{
+        timer::{Timer, TimerHandle},
+        LoopHandle,
+    },
+    nix::{
+        sys::signal::{kill, Signal},
+        unistd::Pid,
+    },
+    wayland_server::protocol::wl_surface::WlSurface,
+};
+
+use smithay::wayland::SERIAL_COUNTER as SCOUNTER;
+
+use crate::{config::ScalingMode, gamepad::VirtualGamepad, launcher::AppEntry, state::AnvilState};
+
+/// How often exited games are reaped.
+const REAP_INTERVAL: Duration = Duration::from_millis(500);
+
+/// How many times SIGKILL is sent before giving up on processes that survive it.
+const MAX_KILL_ROUNDS: u32 = 5;
+
+/// How far along we are in getting a game to exit.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TerminationStage {
+    /// The windows were asked to close.
+    Closing,
+    /// SIGTERM was sent.
+    Terminating,
+    /// SIGKILL was sent.
+    Killing,
+}
+
+/// A game being closed, which lasts until all of its processes are gone, even those left
+/// behind by a wrapper script that exited first.
+#[derive(Debug)]
+struct Termination {
+    /// The pid of the process we spawned.
+    pid: u32,
+    stage: TerminationStage,
+    /// The processes of the game as of the last step, with their start times so that
+    /// a pid reused by an unrelated process is never signalled.
+    processes: Vec<(i32, u64)>,
+    /// How many times SIGKILL was sent.
+    kill_rounds: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum GameTimeout {
+    Reap,
+    /// Move on to the next termination stage of the game with this pid.
+    Escalate(u32),
+}
+
+/// A game process started from the menu.
+#[derive(Debug)]
+pub struct Game {
+    pub app: AppEntry,
+    child: Child,
+    termination: Option<TerminationStage>,
+    /// Whether the pause policy of the app is currently applied.
+    paused: bool,
+    /// Whether it was the game shown at the last visibility refresh.
+    shown: bool,
+    /// The gamepad the game reads, unless it could not be created.
+    gamepad: Option<VirtualGamepad>,
+    /// The scaling mode of the app, unless changed from the menu.
+    scaling: ScalingMode,
+}
+
+impl Game {
+    pub fn pid(&self) -> u32 {
+        self.child.id()
+    }
+
+    /// How far along closing the game is, if it is being closed.
+    pub fn termination(&self) -> Option<TerminationStage> {
+        self.termination
+    }
+
+    pub fn scaling(&self) -> ScalingMode {
+        self.scaling
+    }
+
+    pub fn gamepad(&self) -> Option<&VirtualGamepad> {
+        self.gamepad.as_ref()
+    }
+
+    pub fn gamepad_mut(&mut self) -> Option<&mut VirtualGamepad> {
+        self.gamepad.as_mut()
+    }
+
+    /// Whether the game is stopped by its pause policy.
+    pub fn is_stopped(&self) -> bool {
+        self.paused && self.app.pause.stop
+    }
+}
+
+/// Returns the fields of `/proc/<pid>/stat` following the command name, starting with the
+/// state of the process.
+fn stat_fields(pid: i32) -> Option<String> {
+    let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    // The command name is in parentheses and may itself contain spaces or parentheses,
+    // so the fields we want start after the last ')'.
+    Some(stat[stat.rfind(')')? + 1..].to_owned())
+}
+
+/// Returns the parent of a process, as listed in `/proc/<pid>/stat`.
+fn parent_pid(pid: i32) -> Option<i32> {
+    stat_fields(pid)?.split_whitespace().nth(1)?.parse().ok()
+}
+
+/// Returns when a process started, in clock ticks since boot, unless it is gone or a zombie.
+fn running_since(pid: i32) -> Option<u64> {
+    let fields = stat_fields(pid)?;
+    let mut fields = fields.split_whitespace();
+    if fields.next()? == "Z" {
+        return None;
+    }
+    // The start time is the 22nd field of the stat file, we skipped the first 3
+    fields.nth(18)?.parse().ok()
+}
+
+/// Returns a process and all of its descendants.
+fn process_tree(pid: i32) -> Vec<i32> {
+    let processes = std::fs::read_dir("/proc")
+        .map(|entries| {
+            entries
+                .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse::<i32>().ok())
+                .filter_map(|p| Some((p, parent_pid(p)?)))
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+    let mut tree = vec![pid];
+    let mut i = 0;
+    while i < tree.len() {
+        let parent = tree[i];
+        tree.extend(
+            processes
+                .iter()
+                .filter(|(_, ppid)| *ppid == parent)
+                .map(|(p, _)| *p),
+        );
+        i += 1;
+    }
+    tree
+}
+
+/// Mutes or unmutes the PulseAudio/PipeWire streams of the given processes.
+///
+/// This goes through `pactl` on a separate thread, so that a slow sound server never
+/// blocks the compositor.
+fn set_streams_muted(pids: Vec<i32>, muted: bool, log: slog::Logger) {
+    std::thread::spawn(move || {
+        let output = match Command::new("pactl").args(&["list", "sink-inputs"]).output() {
+            Ok(output) => String::from_utf8_lossy(&output.stdout).into_owned(),
+            Err(err) => {
+                warn!(log, "Unable to list audio streams: {}", err);
+                return;
+            }
+        };
+
+        let mut sink_input = None;
+        for line in output.lines().map(str::trim) {
+            if let Some(id) = line.strip_prefix("Sink Input #") {
+                sink_input = Some(id.to_owned());
+            } else if let Some(pid) = line.strip_prefix("application.process.id = ") {
+                let pid = pid.trim_matches('"').parse::<i32>().ok();
+                if let (Some(id), Some(pid)) = (&sink_input, pid) {
+                    if pids.contains(&pid) {
+                        let _ = Command::new("pactl")
+                            .args(&["set-sink-input-mute", id, if muted { "1" } else { "0" }])
+                            .status();
+                    }
+                }
+            }
+        }
+    });
+}
+
+/// Keeps track of the games we spawned, so their windows can be matched to them
+/// through the client credentials.
+#[derive(Debug)]
+pub struct GameRegistry {
+    games: Vec<Game>,
+    terminations: Vec<Termination>,
+    timer: TimerHandle<GameTimeout>,
+    /// The surface last given the keyboard focus when refreshing the visibility.
+    focus: Option<WlSurface>,
+}
+
+impl GameRegistry {
+    /// Creates the registry, and starts reaping exited games from the event loop.
+    pub fn new<BackendData: 'static>(handle: &LoopHandle<'static, AnvilState<BackendData>>) -> GameRegistry {
+        let timer = Timer::new().expect("Failed to create the game timer");
+        let timer_handle = timer.handle();
+        timer_handle.add_timeout(REAP_INTERVAL, GameTimeout::Reap);
+        handle
+            .insert_source(timer, |timeout, timer_handle, state| match timeout {
+                GameTimeout::Reap => {
+                    if state.games.reap(&state.log) {
+                        // The menu lists the running games
+                        state.schedule_render();
+                    }
+                    // Catches the visibility changes that happen without us knowing,
+                    // like a new window or a window closing
+                    state.refresh_game_visibility();
+                    timer_handle.add_timeout(REAP_INTERVAL, GameTimeout::Reap);
+                }
+                GameTimeout::Escalate(pid) => state.escalate_termination(pid),
+            })
+            .expect("Failed to init the game timer");
+
+        GameRegistry {
+            games: Vec::new(),
+            terminations: Vec::new(),
+            timer: timer_handle,
+            focus: None,
+        }
+    }
+
+    pub fn games(&self) -> impl Iterator<Item = &Game> + '_ {
+        self.games.iter()
+    }
+
+    pub fn get(&self, pid: u32) -> Option<&Game> {
+        self.games.iter().find(|g| g.pid() == pid)
+    }
+
+    pub fn get_mut(&mut self, pid: u32) -> Option<&mut Game> {
+        self.games.iter_mut().find(|g| g.pid() == pid)
+    }
+
+    /// Finds the game a client process belongs to.
+    ///
+    /// Games are often started through wrapper scripts, so the parents of the client
+    /// process are considered as well.
+    pub fn find_by_client_pid(&self, client_pid: i32) -> Option<&Game> {
+        let mut pid = Some(client_pid);
+        while let Some(p) = pid.filter(|p| *p > 1) {
+            if let Some(game) = self.games.iter().find(|g| g.pid() as i32 == p) {
+                return Some(game);
+            }
+            pid = parent_pid(p);
+        }
+        None
+    }
+
+    /// Returns `true` if the client process belongs to the game with the given pid.
+    pub fn owns_client(&self, game_pid: u32, client_pid: i32) -> bool {
+        self.find_by_client_pid(client_pid)
+            .map(|g| g.pid() == game_pid)
+            .unwrap_or(false)
+    }
+
+    /// Removes the games whose process exited, returning whether any did.
+    fn reap(&mut self, log: &slog::Logger) -> bool {
+        let count = self.games.len();
+        let mut i = 0;
+        while i < self.games.len() {
+            let game = &mut self.games[i];
+            match game.child.try_wait() {
+                Ok(Some(status)) => {
+                    info!(log, "Game exited"; "name" => &game.app.name, "status" => format!("{}", status));
+                    self.games.remove(i);
+                }
+                Ok(None) => i += 1,
+                Err(err) => {
+                    warn!(log, "Unable to query game status: {}", err; "name" => &game.app.name);
+                    i += 1;
+                }
+            }
+        }
+        self.games.len() != count
+    }
+}
+
+impl<BackendData: 'static> AnvilState<BackendData> {
+    pub fn launch(&mut self, app: AppEntry) {
+        info!(self.log, "Starting program"; "name" => &app.name, "cmd" => app.exec.join(" "));
+        let gamepad = self.gamepads.create_virtual(&app.name, &self.log);
+        let mut command = app.command();
+        if let Some(gamepad) = &gamepad {
+            command.envs(gamepad.env());
+        }
+        match command.spawn() {
+            Ok(child) => {
+                self.games.games.push(Game {
+                    scaling: app.scaling,
+                    app,
+                    child,
+                    termination: None,
+                    paused: false,
+                    shown: false,
+                    gamepad,
+                });
+            }
+            Err(e) => {
+                error!(self.log,
+                    "Failed to start program";
+                    "name" => &app.name,
+                    "err" => format!("{:?}", e)
+                );
+            }
+        }
+    }
+
+    /// Brings the windows of a game to the top and hides the menu.
+    pub fn resume_game(&mut self, pid: u32) {
+        let games = &self.games;
+        let mut window_map = self.window_map.borrow_mut();
+        if window_map.bring_client_to_top(|client_pid| games.owns_client(pid, client_pid)) {
+            window_map.set_menu_on_top(false);
+        } else {
+            warn!(self.log, "Game has no window to resume"; "pid" => pid);
+        }
+        drop(window_map);
+        self.refresh_game_visibility();
+    }
+
+    pub fn set_menu_on_top(&mut self, on_top: bool) {
+        self.window_map.borrow_mut().set_menu_on_top(on_top);
+        self.refresh_game_visibility();
+        self.schedule_render();
+    }
+
+    /// Forgets the dead windows. When the game shown closes, the next one is shown in its
+    /// place, and the menu stays open if it was.
+    pub fn refresh_windows(&mut self) {
+        if !self.window_map.borrow_mut().refresh() {
+            return;
+        }
+        let window_map = self.window_map.borrow();
+        let menu_on_top = window_map.menu_on_top || window_map.windows_len() == 0;
+        drop(window_map);
+        self.set_menu_on_top(menu_on_top);
+    }
+
+    /// Gives the keyboard focus to the window shown, and applies the pause policy of the
+    /// games that are hidden or shown again.
+    pub fn refresh_game_visibility(&mut self) {
+        // The menu takes all input while it is shown
+        let mut focus = None;
+        let window_map = self.window_map.borrow();
+        let menu_on_top = window_map.menu_on_top;
+        if !menu_on_top {
+            window_map.with_top_window(|toplevel, _, _| focus = toplevel.get_surface().cloned());
+        }
+        drop(window_map);
+        // Only when the top window changed, as this also runs on every reap
+        if focus != self.games.focus {
+            self.keyboard.set_focus(focus.as_ref(), SCOUNTER.next_serial());
+            self.games.focus = focus;
+        }
+
+        let visible_game = self
+            .window_map
+            .borrow()
+            .top_client_pid()
+            .and_then(|client_pid| self.games.find_by_client_pid(client_pid))
+            .map(|game| game.pid());
+        self.route_gamepads(visible_game);
+        self.refresh_idle_inhibit();
+
+        let mut changes = Vec::new();
+        for game in &mut self.games.games {
+            let shown = Some(game.pid()) == visible_game;
+            // A game is only paused once it was shown and then hidden, as one still starting
+            // has no window yet, and one on its way out must be able to react to the close
+            // request
+            let should_pause = if !game.app.pause.is_enabled() || shown || game.termination.is_some() {
+                false
+            } else {
+                game.paused || game.shown
+            };
+            game.shown = shown;
+            if should_pause != game.paused {
+                changes.push((game.pid(), should_pause));
+            }
+        }
+
+        for (pid, paused) in changes {
+            let game = self.games.games.iter_mut().find(|g| g.pid() == pid).unwrap();
+            game.paused = paused;
+            let policy = game.app.pause;
+            debug!(self.log, "Game visibility changed"; "name" => &game.app.name, "paused" => paused);
+
+            if policy.stop {
+                self.signal_game_tree(pid, if paused { Signal::SIGSTOP } else { Signal::SIGCONT });
+            }
+            if policy.mute {
+                set_streams_muted(self.game_tree(pid), paused, self.log.clone());
+            }
+        }
+
+        let games = &self.games;
+        self.window_map.borrow_mut().set_throttled(|client_pid| {
+            games
+                .find_by_client_pid(client_pid)
+                .map(|game| game.paused && game.app.pause.frames)
+                .unwrap_or(false)
+        });
+        self.window_map
+            .borrow_mut()
+            .set_scaling(|client_pid| games.find_by_client_pid(client_pid).map(|game| game.scaling));
+    }
+
+    /// Returns the pids of the clients owning the windows of a game.
+    fn game_client_pids(&self, pid: u32) -> Vec<i32> {
+        let mut pids = self
+            .window_map
+            .borrow()
+            .window_toplevels()
+            .filter_map(|toplevel| toplevel.client_pid())
+            .filter(|client_pid| self.games.owns_client(pid, *client_pid))
+            .collect::<Vec<_>>();
+        pids.sort_unstable();
+        pids.dedup();
+        pids
+    }
+
+    /// Returns the clients owning the windows of a game, and the process we spawned with all
+    /// of its descendants.
+    fn game_tree(&self, pid: u32) -> Vec<i32> {
+        let mut pids = self.game_client_pids(pid);
+        pids.extend(process_tree(pid as i32));
+        pids.sort_unstable();
+        pids.dedup();
+        pids
+    }
+
+    /// Returns the running processes of `game_tree`, with their start times.
+    fn running_game_tree(&self, pid: u32) -> Vec<(i32, u64)> {
+        self.game_tree(pid)
+            .into_iter()
+            .filter_map(|p| Some((p, running_since(p)?)))
+            .collect()
+    }
+
+    /// Sends a signal to the clients owning the windows of a game, and to the process we
+    /// spawned and all of its descendants.
+    pub fn signal_game_tree(&self, pid: u32, signal: Signal) {
+        self.signal_pids(&self.game_tree(pid), signal);
+    }
+
+    fn signal_pids(&self, pids: &[i32], signal: Signal) {
+        for &target in pids {
+            if let Err(err) = kill(Pid::from_raw(target), signal) {
+                warn!(self.log, "Failed to send {:?}: {}", signal, err; "pid" => target);
+            }
+        }
+    }
+
+    /// Asks all windows of a game to close, escalating to SIGTERM and then SIGKILL if the game
+    /// is still running after the configured timeouts.
+    pub fn close_game(&mut self, pid: u32) {
+        match self.games.games.iter_mut().find(|g| g.pid() == pid) {
+            Some(game) if game.termination.is_none() => {
+                info!(self.log, "Closing game"; "name" => &game.app.name);
+                game.termination = Some(TerminationStage::Closing);
+            }
+            // Already on its way out
+            Some(_) => return,
+            None => {
+                warn!(self.log, "Trying to close an unknown game"; "pid" => pid);
+                return;
+            }
+        }
+
+        let processes = self.running_game_tree(pid);
+        self.games.terminations.push(Termination {
+            pid,
+            stage: TerminationStage::Closing,
+            processes,
+            kill_rounds: 0,
+        });
+
+        // Show the progress in the menu, this also resumes the game if it was paused
+        self.set_menu_on_top(true);
+
+        for toplevel in self.window_map.borrow().window_toplevels() {
+            if toplevel
+                .client_pid()
+                .map(|client_pid| self.games.owns_client(pid, client_pid))
+                .unwrap_or(false)
+            {
+                toplevel.send_close();
+            }
+        }
+
+        self.games.timer.add_timeout(
+            Duration::from_millis(self.config.games.close_timeout_ms),
+            GameTimeout::Escalate(pid),
+        );
+    }
+
+    /// Changes how the windows of a game are scaled, until it exits.
+    pub fn change_game_scaling(&mut self, pid: u32, steps: i32) {
+        if let Some(game) = self.games.games.iter_mut().find(|g| g.pid() == pid) {
+            game.scaling = game.scaling.cycle(steps);
+            debug!(self.log, "Scaling changed"; "name" => &game.app.name, "mode" => game.scaling.name());
+            self.refresh_game_visibility();
+        }
+    }
+
+    /// Closes the game whose window is currently shown, if any.
+    pub fn close_top_game(&mut self) {
+        let top_game = self
+            .window_map
+            .borrow()
+            .top_client_pid()
+            .and_then(|client_pid| self.games.find_by_client_pid(client_pid))
+            .map(|game| game.pid());
+        if let Some(pid) = top_game {
+            self.close_game(pid);
+        }
+    }
+
+    /// Moves on to SIGTERM, then SIGKILL, for as long as any process of the game is left or
+    /// still has windows, whether or not the process we spawned exited.
+    fn escalate_termination(&mut self, pid: u32) {
+        let index = match self.games.terminations.iter().position(|t| t.pid == pid) {
+            Some(index) => index,
+            None => return,
+        };
+
+        // Descendants may have been started since the last step, and the recorded ones
+        // only count while their pid was not reused
+        let mut processes = std::mem::take(&mut self.games.terminations[index].processes);
+        let game_running = self.games.get(pid).is_some();
+        if game_running {
+            processes.extend(self.running_game_tree(pid));
+        }
+        processes.retain(|&(p, start)| running_since(p) == Some(start));
+        processes.sort_unstable();
+        processes.dedup();
+        let has_windows = game_running
+            && self
+                .window_map
+                .borrow()
+                .window_toplevels()
+                .filter_map(|toplevel| toplevel.client_pid())
+                .any(|client_pid| self.games.owns_client(pid, client_pid));
+        if processes.is_empty() && !has_windows {
+            // The game exited in time
+            self.games.terminations.remove(index);
+            return;
+        }
+
+        let termination = &mut self.games.terminations[index];
+        if termination.kill_rounds >= MAX_KILL_ROUNDS {
+            warn!(self.log, "Giving up on game processes that survived SIGKILL";
+                "pid" => pid, "processes" => processes.len());
+            self.games.terminations.remove(index);
+            return;
+        }
+        let (next, signal) = match termination.stage {
+            TerminationStage::Closing => (TerminationStage::Terminating, Signal::SIGTERM),
+            TerminationStage::Terminating | TerminationStage::Killing => {
+                termination.kill_rounds += 1;
+                (TerminationStage::Killing, Signal::SIGKILL)
+            }
+        };
+        termination.stage = next;
+        warn!(self.log, "Game did not exit in time, sending {:?}", signal;
+            "pid" => pid, "processes" => processes.len());
+        let pids = processes.iter().map(|&(p, _)| p).collect::<Vec<_>>();
+        self.signal_pids(&pids, signal);
+
+        self.games.terminations[index].processes = processes;
+        if let Some(game) = self.games.get_mut(pid) {
+            game.termination = Some(next);
+        }
+        self.games.timer.add_timeout(
+            Duration::from_millis(self.config.games.term_timeout_ms),
+            GameTimeout::Escalate(pid),
+        );
+    }
+
+    /// Kills the game with all of its processes, including those started by a launcher
+    /// script.
+    pub fn kill_game(&mut self, pid: u32) {
+        info!(self.log, "Killing game"; "pid" => pid);
+        self.signal_game_tree(pid, Signal::SIGKILL);
+    }
+}
@@ -0,0 +1,217 @@
+use std::collections::HashMap;
+
+use ab_glyph::{point, Font, FontRef, ScaleFont};
+use image::{ImageBuffer, Rgba, RgbaImage};
+use smithay::{
+    backend::{
+        renderer::{
+            gles2::{Gles2Frame, Gles2Renderer, Gles2Texture},
+            Frame, Transform,
+        },
+        SwapBuffersError,
+    },
+    utils::{Buffer, Rectangle, Size},
+};
+
+use super::widget::{Color, Widget};
+use crate::drawing::import_bitmap;
+
+static FONT_DATA: &[u8] = include_bytes!("../../assets/fonts/NotoSans/NotoSans-Regular.ttf");
+
+/// Text textures not drawn for this many frames are released.
+const TEXT_CACHE_FRAMES: u64 = 120;
+
+fn font() -> FontRef<'static> {
+    FontRef::try_from_slice(FONT_DATA).expect("The bundled font is invalid")
+}
+
+/// Returns the width of a line of text, in the same unit as `size`.
+pub fn text_width(text: &str, size: f32) -> f64 {
+    let font = font();
+    let font = font.as_scaled(size);
+    let mut width = 0.0;
+    let mut previous = None;
+    for c in text.chars() {
+        let id = font.glyph_id(c);
+        if let Some(previous) = previous {
+            width += font.kern(previous, id);
+        }
+        width += font.h_advance(id);
+        previous = Some(id);
+    }
+    width as f64
+}
+
+/// Rasterizes a line of text with premultiplied alpha.
+fn rasterize(text: &str, size: f32, color: Color) -> Option<RgbaImage> {
+    let font = font();
+    let font = font.as_scaled(size);
+
+    let width = text_width(text, size).ceil() as u32;
+    let height = (font.ascent() - font.descent()).ceil() as u32;
+    if width == 0 || height == 0 {
+        return None;
+    }
+
+    let mut image = ImageBuffer::new(width, height);
+    let mut caret = 0.0;
+    let mut previous = None;
+    for c in text.chars() {
+        let id = font.glyph_id(c);
+        if let Some(previous) = previous {
+            caret += font.kern(previous, id);
+        }
+        let glyph = id.with_scale_and_position(size, point(caret, font.ascent()));
+        caret += font.h_advance(id);
+        previous = Some(id);
+
+        if let Some(outline) = font.outline_glyph(glyph) {
+            let bounds = outline.px_bounds();
+            outline.draw(|x, y, coverage| {
+                let x = x as i32 + bounds.min.x as i32;
+                let y = y as i32 + bounds.min.y as i32;
+                if x >= 0 && y >= 0 && (x as u32) < width && (y as u32) < height {
+                    let pixel = premultiply(color, coverage);
+                    let existing = image.get_pixel_mut(x as u32, y as u32);
+                    // Glyphs may overlap a bit, keep the strongest coverage
+                    if pixel[3] > existing[3] {
+                        *existing = Rgba(pixel);
+                    }
+                }
+            });
+        }
+    }
+    Some(image)
+}
+
+fn premultiply(color: Color, coverage: f32) -> [u8; 4] {
+    let alpha = color[3] as f32 / 255.0 * coverage.clamp(0.0, 1.0);
+    [
+        (color[0] as f32 * alpha).round() as u8,
+        (color[1] as f32 * alpha).round() as u8,
+        (color[2] as f32 * alpha).round() as u8,
+        (255.0 * alpha).round() as u8,
+    ]
+}
+
+struct CachedText {
+    texture: Gles2Texture,
+    size: Size<i32, Buffer>,
+    last_used: u64,
+}
+
+/// Draws widgets with a `Gles2Renderer`, caching their textures.
+///
+/// Textures belong to the GL context they were created in, so every renderer needs its
+/// own `OverlayRenderer`.
+#[derive(Default)]
+pub struct OverlayRenderer {
+    colors: HashMap<Color, Gles2Texture>,
+    texts: HashMap<(String, u32, Color), CachedText>,
+    frame: u64,
+}
+
+impl std::fmt::Debug for OverlayRenderer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("OverlayRenderer")
+            .field("colors", &self.colors.len())
+            .field("texts", &self.texts.len())
+            .finish()
+    }
+}
+
+impl OverlayRenderer {
+    pub fn new() -> OverlayRenderer {
+        OverlayRenderer::default()
+    }
+
+    /// Draws the widgets in order, on an output with the given scale.
+    pub fn draw(
+        &mut self,
+        renderer: &mut Gles2Renderer,
+        frame: &mut Gles2Frame,
+        widgets: &[Widget],
+        output_scale: f32,
+    ) -> Result<(), SwapBuffersError> {
+        self.frame += 1;
+        self.draw_widgets(renderer, frame, widgets, output_scale, 1.0)?;
+
+        let current = self.frame;
+        self.texts
+            .retain(|_, cached| current - cached.last_used < TEXT_CACHE_FRAMES);
+        Ok(())
+    }
+
+    fn draw_widgets(
+        &mut self,
+        renderer: &mut Gles2Renderer,
+        frame: &mut Gles2Frame,
+        widgets: &[Widget],
+        output_scale: f32,
+        alpha: f32,
+    ) -> Result<(), SwapBuffersError> {
+        let scale = output_scale as f64;
+
+        for widget in widgets {
+            match widget {
+                Widget::Rect { geometry, color } => {
+                    if !self.colors.contains_key(color) {
+                        let pixel = ImageBuffer::from_pixel(1, 1, Rgba(premultiply(*color, 1.0)));
+                        self.colors.insert(*color, import_bitmap(renderer, &pixel)?);
+                    }
+                    frame.render_texture_from_to(
+                        &self.colors[color],
+                        Rectangle::from_loc_and_size((0, 0), (1, 1)),
+                        geometry.to_physical(scale),
+                        Transform::Normal,
+                        alpha,
+                    )?;
+                }
+                Widget::Text {
+                    text,
+                    location,
+                    size,
+                    color,
+                } => {
+                    let px = (*size * output_scale).round() as u32;
+                    let key = (text.clone(), px, *color);
+                    if !self.texts.contains_key(&key) {
+                        let image = match rasterize(text, px as f32, *color) {
+                            Some(image) => image,
+                            None => continue,
+                        };
+                        let texture = import_bitmap(renderer, &image)?;
+                        self.texts.insert(
+                            key.clone(),
+                            CachedText {
+                                texture,
+                                size: (image.width() as i32, image.height() as i32).into(),
+                                last_used: 0,
+                            },
+                        );
+                    }
+                    let cached = self.texts.get_mut(&key).unwrap();
+                    cached.last_used = self.frame;
+
+                    // Keep glyphs on the pixel grid, they get blurry otherwise
+                    let location = location.to_physical(scale);
+                    frame.render_texture_from_to(
+                        &cached.texture,
+                        Rectangle::from_loc_and_size((0, 0), cached.size),
+                        Rectangle::from_loc_and_size(
+                            (location.x.round(), location.y.round()),
+                            (cached.size.w as f64, cached.size.h as f64),
+                        ),
+                        Transform::Normal,
+                        alpha,
+                    )?;
+                }
+                Widget::Faded {
+                    alpha: faded,
+                    widgets,
+                } => self.draw_widgets(renderer, frame, widgets, output_scale, alpha * faded)?,
+            }
+        }
+        Ok(())
+    }
+}
@@ -1,184 +1,522 @@
-use std::{borrow::{Borrow, Cow}, ops::Deref, process::Command, sync::mpsc::Receiver};
+use std::time::{Duration, Instant};
 
-use conrod_core::{Colorable, Positionable, Widget, widget, widget_ids};
-use gilrs::{Button, EventType, Gilrs};
-use glium::Surface;
-use smithay::reexports::calloop::channel::Sender;
+use smithay::{
+    reexports::calloop::{
+        timer::{Timer, TimerHandle},
+        LoopHandle,
+    },
+    utils::{Logical, Point, Rectangle, Size},
+};
 
-conrod_winit::v023_conversion_fns!();
+use crate::{
+    audio::Audio,
+    backlight::Backlight,
+    gamepad::{Button, GamepadEvent},
+    games::{GameRegistry, TerminationStage},
+    launcher::Launcher,
+    power::PowerMonitor,
+    state::AnvilState,
+    window_map::WindowMap,
+};
 
 mod drawing;
+pub mod osd;
+pub mod widget;
 
-const WIDTH: u32 = 400;
-const HEIGHT: u32 = 200;
+pub use self::drawing::OverlayRenderer;
+use self::{
+    drawing::text_width,
+    osd::Osd,
+    widget::{Column, Widget},
+};
 
-struct UiState {
-    menu_on_top: bool
+/// Holding a DPad direction this long starts moving through the menu, or changing a
+/// setting, repeatedly.
+const REPEAT_DELAY: Duration = Duration::from_millis(400);
+const REPEAT_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The size of the title, relative to the rest of the text.
+const TITLE_SCALE: f32 = 1.4;
+
+/// How often the launcher entries are checked for changes.
+const LAUNCHER_RESCAN_INTERVAL: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Clone, Copy)]
+enum MenuTimeout {
+    /// The DPad direction held down may repeat.
+    Repeat,
+    Rescan,
 }
 
-pub enum ToCompositor {
-    SetMenuOnTop(bool)
+fn termination_text(name: &str, stage: TerminationStage) -> String {
+    match stage {
+        TerminationStage::Closing => format!("Closing {}...", name),
+        TerminationStage::Terminating => format!("{} is not responding, terminating...", name),
+        TerminationStage::Killing => format!("{} is not responding, killing...", name),
+    }
 }
 
-pub enum ToUi {
-    SetMenuOnTop(bool)
+/// Where the parts of the menu go on an output.
+#[derive(Debug)]
+struct Layout {
+    text_size: f32,
+    panel: Rectangle<f64, Logical>,
+    /// The panel without its padding.
+    inner: Rectangle<f64, Logical>,
+    /// Below the title, where the items are listed.
+    items: Rectangle<f64, Logical>,
+    /// The games being closed, at the bottom.
+    progress: Vec<String>,
+    progress_area: Rectangle<f64, Logical>,
 }
 
-pub fn main(rx: Receiver<ToUi>, tx: Sender<ToCompositor>) {
-    // Build the window.
-    let event_loop: glium::glutin::event_loop::EventLoop<()> = glium::glutin::platform::unix::EventLoopExtUnix::new_wayland_any_thread();
-    let window = glium::glutin::window::WindowBuilder::new()
-        .with_title("Hello Conrod!")
-        .with_inner_size(glium::glutin::dpi::LogicalSize::new(WIDTH, HEIGHT));
-    let context = glium::glutin::ContextBuilder::new()
-        .with_vsync(true)
-        .with_multisampling(4);
-    let display = glium::Display::new(window, context, &event_loop).unwrap();
-
-    let mut ui_state = UiState {
-        menu_on_top: true
-    };
+impl Layout {
+    fn new(games: &GameRegistry, output_size: Size<i32, Logical>) -> Layout {
+        let size = output_size.to_f64();
+        let text_size = (size.h as f32 / 30.0).clamp(16.0, 48.0);
+        let padding = text_size as f64;
 
-    // construct our `Ui`.
-    let mut ui = conrod_core::UiBuilder::new([WIDTH as f64, HEIGHT as f64]).build();
-
-    // Generate the widget identifiers.
-    widget_ids!(struct Ids { text, list });
-    let ids = Ids::new(ui.widget_id_generator());
-
-    // Add a `Font` to the `Ui`'s `font::Map` from file.
-    let assets = find_folder::Search::KidsThenParents(3, 5)
-        .for_folder("assets")
-        .unwrap();
-    let font_path = assets.join("fonts/NotoSans/NotoSans-Regular.ttf");
-    ui.fonts.insert_from_file(font_path).unwrap();
-
-    // A type used for converting `conrod_core::render::Primitives` into `Command`s that can be used
-    // for drawing to the glium `Surface`.
-    let mut renderer = conrod_glium::Renderer::new(&display).unwrap();
-
-    // The image map describing each of our widget->image mappings (in our case, none).
-    let image_map = conrod_core::image::Map::<glium::texture::Texture2d>::new();
-
-    let mut gilrs = Gilrs::new().unwrap();
-    // Iterate over all connected gamepads
-    for (_id, gamepad) in gilrs.gamepads() {
-        println!("{} is {:?}", gamepad.name(), gamepad.power_info());
-    }
-
-    let applications = vec![
-        ("RetroArch", "retroarch"),
-        ("SuperTuxKart", "supertuxkart"),
-        ("dummy application", ""),
-        ("dummy application", ""),
-    ];
-
-    let mut selected_application: usize = 0;
-
-    let mut should_update_ui = true;
-    event_loop.run(move |event, _, control_flow| {
-        // Break from the loop upon `Escape` or closed window.
-        match &event {
-            glium::glutin::event::Event::WindowEvent { event, .. } => match event {
-                // Break from the loop upon `Escape`.
-                glium::glutin::event::WindowEvent::CloseRequested
-                | glium::glutin::event::WindowEvent::KeyboardInput {
-                    input:
-                        glium::glutin::event::KeyboardInput {
-                            virtual_keycode: Some(glium::glutin::event::VirtualKeyCode::Escape),
-                            ..
-                        },
-                    ..
-                } => *control_flow = glium::glutin::event_loop::ControlFlow::Exit,
-                _ => {}
-            },
-            _ => {}
+        let panel = widget::centered(
+            Rectangle::from_loc_and_size((0.0, 0.0), size),
+            ((size.w * 0.6).max(size.w.min(480.0)), size.h * 0.8).into(),
+        );
+        let inner = Rectangle::from_loc_and_size(
+            (panel.loc.x + padding, panel.loc.y + padding),
+            (panel.size.w - 2.0 * padding, panel.size.h - 2.0 * padding),
+        );
+
+        let progress = games
+            .games()
+            .filter_map(|game| Some(termination_text(&game.app.name, game.termination()?)))
+            .collect::<Vec<_>>();
+        let progress_height = progress.len() as f64 * Column::row_height(text_size);
+
+        let title_height = Column::row_height(text_size * TITLE_SCALE) + padding / 2.0;
+        let items_height = (inner.size.h - progress_height - title_height).max(0.0);
+        Layout {
+            text_size,
+            panel,
+            inner,
+            items: Rectangle::from_loc_and_size(
+                (inner.loc.x, inner.loc.y + title_height),
+                (inner.size.w, items_height),
+            ),
+            progress,
+            progress_area: Rectangle::from_loc_and_size(
+                (inner.loc.x, inner.loc.y + inner.size.h - progress_height),
+                (inner.size.w, progress_height),
+            ),
         }
+    }
 
-        while let Ok(event) = rx.try_recv() {
-            match event {
-                ToUi::SetMenuOnTop(on_top) => ui_state.menu_on_top = on_top,
-            }
+    /// Returns the first item shown and how many are, scrolling just enough to keep the
+    /// `selected` one visible.
+    fn shown_items(&self, selected: usize) -> (usize, usize) {
+        let visible = ((self.items.size.h / Column::row_height(self.text_size)) as usize).max(1);
+        ((selected + 1).saturating_sub(visible), visible)
+    }
+
+    /// Returns the index of the item at `position` when `selected` is, and how far across
+    /// the row it is, from 0 to 1.
+    fn item_at(&self, position: Point<f64, Logical>, selected: usize) -> Option<(usize, f64)> {
+        let area = self.items;
+        if position.x < area.loc.x - self.text_size as f64
+            || position.x > area.loc.x + area.size.w + self.text_size as f64
+            || position.y < area.loc.y
+        {
+            return None;
         }
+        let (first, visible) = self.shown_items(selected);
+        let row = ((position.y - area.loc.y) / Column::row_height(self.text_size)) as usize;
+        if row >= visible {
+            return None;
+        }
+        let across = ((position.x - area.loc.x) / area.size.w).clamp(0.0, 1.0);
+        Some((first + row, across))
+    }
+}
 
-        // Check gamepad input
-        while let Some(gilrs::Event { id, event, time }) = gilrs.next_event() {
-            println!("{:?} New event from {}: {:?}", time, id, event);
-            //active_gamepad = Some(id);
-            if let EventType::ButtonPressed(Button::Mode, _) = event {
-                should_update_ui = true;
-                ui_state.menu_on_top = !ui_state.menu_on_top;
-                tx.send(ToCompositor::SetMenuOnTop(ui_state.menu_on_top));
-            } else if ui_state.menu_on_top {
-                should_update_ui = true;
-                match event {
-                    EventType::ButtonPressed(Button::DPadUp, _) | EventType::ButtonRepeated(Button::DPadUp, _) => {
-                        if selected_application == 0 { selected_application = applications.len()-1; }
-                        else { selected_application -= 1; }
-                    }
-                    EventType::ButtonPressed(Button::DPadDown, _) | EventType::ButtonRepeated(Button::DPadDown, _) => {
-                        if selected_application == applications.len()-1 { selected_application = 0; }
-                        else { selected_application += 1; }
-                    }
-                    EventType::ButtonPressed(Button::East, _) => {
-                        Command::new(applications[selected_application].1).spawn();
-                    }
-                    _ => {
-                        should_update_ui = false;
+/// A selectable row of the menu.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum MenuItem {
+    Resume(u32),
+    /// Changed with left and right.
+    Scaling(u32),
+    Close(u32),
+    Kill(u32),
+    Launch(usize),
+    /// Changed with left and right.
+    Volume,
+    /// Changed with left and right, only shown if the panel has a backlight.
+    Brightness,
+    Sleep,
+}
+
+/// A finger on the menu.
+#[derive(Debug, Clone, Copy)]
+struct MenuTouch {
+    start: Point<f64, Logical>,
+    /// Where the last swipe step was taken from.
+    anchor: Point<f64, Logical>,
+    /// Whether it moved too far to be a tap.
+    swiped: bool,
+    output_size: Size<i32, Logical>,
+}
+
+/// The menu drawn by the compositor over the game it hides.
+#[derive(Debug)]
+pub struct Menu {
+    launcher: Launcher,
+    selected_item: usize,
+    /// The DPad direction held down, and when it next repeats.
+    held: Option<(Button, Instant)>,
+    touch: Option<MenuTouch>,
+    timer: TimerHandle<MenuTimeout>,
+}
+
+impl Menu {
+    /// Loads the launcher entries, and starts checking them for changes from the event loop.
+    pub fn new<BackendData: 'static>(
+        log: slog::Logger,
+        handle: &LoopHandle<'static, AnvilState<BackendData>>,
+    ) -> Menu {
+        let timer = Timer::new().expect("Failed to create the menu timer");
+        let timer_handle = timer.handle();
+        timer_handle.add_timeout(LAUNCHER_RESCAN_INTERVAL, MenuTimeout::Rescan);
+        handle
+            .insert_source(timer, |timeout, timer_handle, state| match timeout {
+                MenuTimeout::Repeat => state.repeat_menu_direction(),
+                MenuTimeout::Rescan => {
+                    if state.menu.launcher.refresh() {
+                        state.schedule_render();
                     }
-                } 
-            }
+                    timer_handle.add_timeout(LAUNCHER_RESCAN_INTERVAL, MenuTimeout::Rescan);
+                }
+            })
+            .expect("Failed to init the menu timer");
+
+        Menu {
+            launcher: Launcher::new(log),
+            selected_item: 0,
+            held: None,
+            touch: None,
+            timer: timer_handle,
         }
+    }
 
-        // Use the `winit` backend feature to convert the winit event to a conrod one.
-        if let Some(event) = convert_event(&event, &display.gl_window().window()) {
-            ui.handle_event(event);
-            should_update_ui = true;
+    /// Running games come first, each with their actions, followed by the launcher entries
+    /// and the settings.
+    fn items(
+        &self,
+        games: &GameRegistry,
+        audio: &Audio,
+        backlight: Option<&Backlight>,
+    ) -> Vec<(MenuItem, String)> {
+        let mut items = Vec::new();
+        for game in games.games() {
+            let (pid, name) = (game.pid(), &game.app.name);
+            items.push((MenuItem::Resume(pid), format!("Resume {}", name)));
+            items.push((
+                MenuItem::Scaling(pid),
+                format!("Scaling: < {} >", game.scaling().name()),
+            ));
+            items.push((MenuItem::Close(pid), format!("Close {}", name)));
+            items.push((MenuItem::Kill(pid), format!("Force kill {}", name)));
+        }
+        for (i, app) in self.launcher.apps().iter().enumerate() {
+            items.push((MenuItem::Launch(i), app.name.clone()));
         }
+        items.push((
+            MenuItem::Volume,
+            format!("Volume: < {}% >", (audio.volume() * 100.0).round()),
+        ));
+        if let Some(backlight) = backlight {
+            items.push((
+                MenuItem::Brightness,
+                format!("Brightness: < {}% >", (backlight.level() * 100.0).round()),
+            ));
+        }
+        items.push((MenuItem::Sleep, "Sleep".to_owned()));
+        items
+    }
 
-        match &event {
-            glium::glutin::event::Event::MainEventsCleared => {
-                if should_update_ui {
-                    should_update_ui = false;
+    /// Lays the menu out for an output of the given size.
+    pub fn view(
+        &self,
+        games: &GameRegistry,
+        power: &PowerMonitor,
+        audio: &Audio,
+        backlight: Option<&Backlight>,
+        output_size: Size<i32, Logical>,
+    ) -> Vec<Widget> {
+        let layout = Layout::new(games, output_size);
+        let (text_size, inner) = (layout.text_size, layout.inner);
 
-                    // Set the widgets.
-                    let ui = &mut ui.set_widgets();
+        let mut widgets = vec![
+            Widget::fill(output_size, widget::DIM),
+            Widget::rect(layout.panel, widget::PANEL),
+        ];
 
-                    let (mut items, scrollbar) = widget::List::flow_down(applications.len())
-                        .top_left_of(ui.window)
-                        .set(ids.list, ui);
-                    
-                    while let Some(item) = items.next(ui) {
-                        let i = item.i;
+        let mut title = Column::new(inner);
+        let title_size = text_size * TITLE_SCALE;
+        title.centered_text("Waystation", title_size, widget::TEXT);
+        widgets.extend(title.into_widgets());
 
-                        let text = format!("{}{}", if i == selected_application {"> "} else {""}, applications[i].0);
+        // The batteries go to the right of the title
+        let batteries = power
+            .batteries()
+            .iter()
+            .map(|battery| {
+                let name = if battery.is_controller { "Controller" } else { "Battery" };
+                format!("{} {}", name, battery.status_text())
+            })
+            .collect::<Vec<_>>()
+            .join("   ");
+        if !batteries.is_empty() {
+            let size = text_size * 0.8;
+            widgets.push(Widget::Text {
+                location: (
+                    inner.loc.x + inner.size.w - text_width(&batteries, size),
+                    inner.loc.y + (Column::row_height(title_size) - size as f64) / 2.0,
+                )
+                    .into(),
+                text: batteries,
+                size,
+                color: widget::TEXT_DIM,
+            });
+        }
 
-                        let label = widget::Text::new(&text);
-                        item.set(label, ui);
-                    }
+        let mut list = Column::new(layout.items);
+        let items = self.items(games, audio, backlight);
+        if items.is_empty() {
+            list.text("No games configured", text_size, widget::TEXT_DIM);
+        }
 
-                    // "Hello World!" in the middle of the screen.
-/*                      widget::Text::new("Hello World!")
-                        .middle_of(ui.window)
-                        .color(conrod_core::color::BLACK)
-                        .font_size(32)
-                        .set(ids.text, ui); */
+        let selected = self.selected_item.min(items.len().saturating_sub(1));
+        let (first, visible) = layout.shown_items(selected);
+        for (i, (_, text)) in items.iter().enumerate().skip(first).take(visible) {
+            if i == selected {
+                list.highlighted_text(text.as_str(), text_size, widget::TEXT, widget::HIGHLIGHT);
+            } else {
+                list.text(text.as_str(), text_size, widget::TEXT);
+            }
+        }
+        widgets.extend(list.into_widgets());
 
-                    // Request redraw if the `Ui` has changed.
-                    display.gl_window().window().request_redraw();
-                }
+        let mut status = Column::new(layout.progress_area);
+        for text in layout.progress {
+            status.text(text, text_size, widget::WARNING);
+        }
+        widgets.extend(status.into_widgets());
+
+        widgets
+    }
+}
+
+/// Returns everything the compositor draws on top of the clients, for an output of the given size.
+///
+/// The OSD comes last, so it stays visible over the menu.
+#[allow(clippy::too_many_arguments)]
+pub fn overlay_widgets(
+    window_map: &WindowMap,
+    menu: &Menu,
+    games: &GameRegistry,
+    power: &PowerMonitor,
+    audio: &Audio,
+    backlight: Option<&Backlight>,
+    osd: &Osd,
+    output_size: Size<i32, Logical>,
+) -> Vec<Widget> {
+    let mut widgets = if window_map.menu_on_top {
+        menu.view(games, power, audio, backlight, output_size)
+    } else {
+        Vec::new()
+    };
+    widgets.extend(osd.view(output_size));
+    widgets
+}
+
+impl<BackendData: 'static> AnvilState<BackendData> {
+    /// Handles a gamepad event read by the compositor.
+    ///
+    /// Besides the bindings, only the menu acts on these: while a game is shown, it gets the
+    /// inputs through its virtual gamepad, or reads the gamepads itself.
+    pub fn on_gamepad_event(&mut self, event: GamepadEvent) {
+        let action = match event {
+            GamepadEvent::Pressed(button) => {
+                self.bindings.press(button, Instant::now());
+                None
             }
-            glium::glutin::event::Event::RedrawRequested(_) => {
-                // Draw the `Ui` if it has changed.
-                let primitives = ui.draw();
-
-                renderer.fill(&display, primitives, &image_map);
-                let mut target = display.draw();
-                target.clear_color(1.0, 1.0, 1.0, 1.0);
-                renderer.draw(&display, &mut target, &image_map).unwrap();
-                target.finish().unwrap();
+            GamepadEvent::Released(button) => self.bindings.release(button),
+        };
+        if self.window_map.borrow().menu_on_top {
+            self.on_menu_event(event);
+        }
+        if let Some(action) = action {
+            self.run_action(action);
+        }
+    }
+
+    fn on_menu_event(&mut self, event: GamepadEvent) {
+        match event {
+            GamepadEvent::Pressed(
+                button @ (Button::DPadUp | Button::DPadDown | Button::DPadLeft | Button::DPadRight),
+            ) => {
+                self.menu.held = Some((button, Instant::now() + REPEAT_DELAY));
+                self.menu.timer.add_timeout(REPEAT_DELAY, MenuTimeout::Repeat);
+                self.on_menu_direction(button);
+            }
+            GamepadEvent::Released(button) if self.menu.held.map(|(b, _)| b) == Some(button) => {
+                self.menu.held = None;
             }
+            GamepadEvent::Pressed(Button::East) => self.activate_menu_item(),
+            _ => {}
+        }
+    }
+
+    /// Up and down move through the menu, left and right change the selected setting.
+    fn on_menu_direction(&mut self, button: Button) {
+        match button {
+            Button::DPadLeft => self.adjust_menu_item(-1),
+            Button::DPadRight => self.adjust_menu_item(1),
+            _ => self.navigate_menu(button),
+        }
+    }
+
+    fn navigate_menu(&mut self, button: Button) {
+        let len = self.menu.items(&self.games, &self.audio, self.backlight.as_ref()).len();
+        if len == 0 {
+            return;
+        }
+        let selected = self.menu.selected_item.min(len - 1);
+        self.menu.selected_item = match button {
+            Button::DPadUp => selected.checked_sub(1).unwrap_or(len - 1),
+            _ => (selected + 1) % len,
+        };
+    }
+
+    /// Moves the selection by `steps` rows, stopping at the ends.
+    fn move_menu_selection(&mut self, steps: i32) {
+        let len = self.menu.items(&self.games, &self.audio, self.backlight.as_ref()).len();
+        if len == 0 {
+            return;
+        }
+        let selected = self.menu.selected_item.min(len - 1) as i64 + steps as i64;
+        self.menu.selected_item = selected.clamp(0, len as i64 - 1) as usize;
+    }
+
+    fn selected_menu_item(&self) -> Option<MenuItem> {
+        let items = self.menu.items(&self.games, &self.audio, self.backlight.as_ref());
+        items
+            .get(self.menu.selected_item.min(items.len().saturating_sub(1)))
+            .map(|(item, _)| *item)
+    }
+
+    fn adjust_menu_item(&mut self, steps: i32) {
+        match self.selected_menu_item() {
+            Some(MenuItem::Volume) => self.change_volume(steps),
+            Some(MenuItem::Brightness) => self.change_brightness(steps),
+            Some(MenuItem::Scaling(pid)) => self.change_game_scaling(pid, steps),
             _ => {}
         }
-    })
-}
\ No newline at end of file
+    }
+
+    fn activate_menu_item(&mut self) {
+        let item = match self.selected_menu_item() {
+            Some(item) => item,
+            None => return,
+        };
+        match item {
+            MenuItem::Resume(pid) => self.resume_game(pid),
+            MenuItem::Scaling(pid) => self.change_game_scaling(pid, 1),
+            MenuItem::Close(pid) => self.close_game(pid),
+            MenuItem::Kill(pid) => self.kill_game(pid),
+            MenuItem::Launch(i) => {
+                let app = self.menu.launcher.apps()[i].clone();
+                self.launch(app);
+            }
+            MenuItem::Volume | MenuItem::Brightness => {}
+            MenuItem::Sleep => self.request_suspend(),
+        }
+    }
+
+    /// Handles a finger going down on the menu, at `position` on an output of `output_size`.
+    pub fn on_menu_touch_down(&mut self, position: Point<f64, Logical>, output_size: Size<i32, Logical>) {
+        self.menu.touch = Some(MenuTouch {
+            start: position,
+            anchor: position,
+            swiped: false,
+            output_size,
+        });
+    }
+
+    /// Swiping up and down scrolls through the menu a row at a time, and swiping left and
+    /// right changes the selected setting.
+    pub fn on_menu_touch_motion(&mut self, position: Point<f64, Logical>) {
+        let mut touch = match self.menu.touch {
+            Some(touch) => touch,
+            None => return,
+        };
+        let row = Column::row_height(Layout::new(&self.games, touch.output_size).text_size);
+
+        let moved = position - touch.start;
+        if moved.x.hypot(moved.y) > row / 2.0 {
+            touch.swiped = true;
+        }
+        let delta = position - touch.anchor;
+        let rows = (delta.y / row) as i32;
+        if rows != 0 {
+            // The list follows the finger
+            self.move_menu_selection(-rows);
+            touch.anchor.y += rows as f64 * row;
+        }
+        let steps = (delta.x / (2.0 * row)) as i32;
+        if steps != 0 {
+            self.adjust_menu_item(steps);
+            touch.anchor.x += steps as f64 * 2.0 * row;
+        }
+        self.menu.touch = Some(touch);
+    }
+
+    /// A finger lifted without swiping taps the item under it, which is selected and
+    /// activated. Settings are changed down or up by tapping their left or right half.
+    pub fn on_menu_touch_up(&mut self) {
+        let touch = match self.menu.touch.take() {
+            Some(touch) if !touch.swiped => touch,
+            _ => return,
+        };
+        let len = self.menu.items(&self.games, &self.audio, self.backlight.as_ref()).len();
+        let selected = self.menu.selected_item.min(len.saturating_sub(1));
+        let layout = Layout::new(&self.games, touch.output_size);
+        let (index, across) = match layout.item_at(touch.start, selected) {
+            Some((index, across)) if index < len => (index, across),
+            _ => return,
+        };
+        self.menu.selected_item = index;
+        match self.selected_menu_item() {
+            Some(MenuItem::Volume | MenuItem::Brightness | MenuItem::Scaling(_)) => {
+                self.adjust_menu_item(if across < 0.5 { -1 } else { 1 })
+            }
+            _ => self.activate_menu_item(),
+        }
+    }
+
+    pub fn on_menu_touch_cancel(&mut self) {
+        self.menu.touch = None;
+    }
+
+    /// Repeats the DPad direction held down, which keeps the timer armed only while it is.
+    fn repeat_menu_direction(&mut self) {
+        if let Some((button, next_repeat)) = self.menu.held {
+            if !self.window_map.borrow().menu_on_top {
+                self.menu.held = None;
+            } else if Instant::now() >= next_repeat {
+                // Earlier presses left their own timeouts, which are ignored here
+                self.menu.held = Some((button, next_repeat + REPEAT_INTERVAL));
+                self.menu.timer.add_timeout(REPEAT_INTERVAL, MenuTimeout::Repeat);
+                self.on_menu_direction(button);
+                self.schedule_render();
+            }
+        }
+    }
+}
@@ -0,0 +1,207 @@
+use std::time::{Duration, Instant};
+
+use smithay::utils::{Logical, Rectangle, Size};
+
+use super::{
+    drawing::text_width,
+    widget::{self, Column, Widget},
+};
+
+/// How long toasts stay up by default.
+const TIMEOUT: Duration = Duration::from_secs(2);
+/// Warnings need more time to be read.
+const WARNING_TIMEOUT: Duration = Duration::from_secs(5);
+/// Toasts fade out during the end of their timeout.
+const FADE: Duration = Duration::from_millis(300);
+
+/// Older toasts are dropped to keep at most this many.
+const MAX_TOASTS: usize = 4;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ToastContent {
+    Text(String),
+    /// A labelled level between 0 and 1, like the volume.
+    Bar {
+        label: String,
+        level: f64,
+    },
+}
+
+/// A message shown for a short time over everything else.
+#[derive(Debug, Clone)]
+pub struct Toast {
+    content: ToastContent,
+    warning: bool,
+    timeout: Duration,
+    /// Posting a toast replaces the one with the same tag, so repeated changes do not pile up.
+    tag: Option<String>,
+}
+
+impl Toast {
+    pub fn text(text: impl Into<String>) -> Toast {
+        Toast {
+            content: ToastContent::Text(text.into()),
+            warning: false,
+            timeout: TIMEOUT,
+            tag: None,
+        }
+    }
+
+    /// A bar, tagged with its label.
+    pub fn bar(label: impl Into<String>, level: f64) -> Toast {
+        let label = label.into();
+        Toast {
+            tag: Some(label.clone()),
+            content: ToastContent::Bar { label, level },
+            warning: false,
+            timeout: TIMEOUT,
+        }
+    }
+
+    pub fn warning(mut self) -> Toast {
+        self.warning = true;
+        self.timeout = self.timeout.max(WARNING_TIMEOUT);
+        self
+    }
+
+    pub fn timeout(mut self, timeout: Duration) -> Toast {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn tag(mut self, tag: impl Into<String>) -> Toast {
+        self.tag = Some(tag.into());
+        self
+    }
+}
+
+/// The toasts currently shown, drawn above every layer and the menu.
+#[derive(Debug, Default)]
+pub struct Osd {
+    toasts: Vec<(Toast, Instant)>,
+    /// Shows the recording indicator.
+    recording: bool,
+    /// Whether toasts were posted since the outputs were last told to draw them.
+    changed: bool,
+}
+
+impl Osd {
+    pub fn new() -> Osd {
+        Osd::default()
+    }
+
+    pub fn post(&mut self, toast: Toast) {
+        self.toasts.retain(|(shown, posted)| {
+            posted.elapsed() < shown.timeout && (toast.tag.is_none() || shown.tag != toast.tag)
+        });
+        self.toasts.push((toast, Instant::now()));
+        if self.toasts.len() > MAX_TOASTS {
+            self.toasts.remove(0);
+        }
+        self.changed = true;
+    }
+
+    pub fn set_recording(&mut self, recording: bool) {
+        self.changed |= self.recording != recording;
+        self.recording = recording;
+    }
+
+    /// Returns `true` once after toasts were posted or the recording indicator changed.
+    pub fn take_changed(&mut self) -> bool {
+        std::mem::take(&mut self.changed)
+    }
+
+    /// Returns `true` if a toast or the recording indicator is still shown.
+    pub fn is_visible(&self) -> bool {
+        self.recording
+            || self
+                .toasts
+                .iter()
+                .any(|(toast, posted)| posted.elapsed() < toast.timeout)
+    }
+
+    /// Lays the toasts out from the top of an output of the given size, oldest first.
+    pub fn view(&self, output_size: Size<i32, Logical>) -> Vec<Widget> {
+        let size = output_size.to_f64();
+        let text_size = (size.h as f32 / 30.0).clamp(16.0, 48.0);
+        let padding = text_size as f64 / 2.0;
+        let width = (size.w * 0.4).max(size.w.min(320.0));
+        let bar_height = text_size as f64 * 0.5;
+
+        let mut widgets = Vec::new();
+        let mut y = padding;
+        for (toast, posted) in &self.toasts {
+            let remaining = toast.timeout.saturating_sub(posted.elapsed());
+            if remaining.is_zero() {
+                continue;
+            }
+            let alpha = (remaining.as_secs_f32() / FADE.as_secs_f32()).min(1.0);
+
+            let content_height = match toast.content {
+                ToastContent::Text(_) => Column::row_height(text_size),
+                ToastContent::Bar { .. } => Column::row_height(text_size) + bar_height,
+            };
+            let panel = Rectangle::from_loc_and_size(
+                ((size.w - width) / 2.0, y),
+                (width, content_height + 2.0 * padding),
+            );
+            let mut column = Column::new(Rectangle::from_loc_and_size(
+                (panel.loc.x + padding, panel.loc.y + padding),
+                (panel.size.w - 2.0 * padding, content_height),
+            ));
+            let color = if toast.warning {
+                widget::WARNING
+            } else {
+                widget::TEXT
+            };
+            match &toast.content {
+                ToastContent::Text(text) => {
+                    column.centered_text(text.as_str(), text_size, color);
+                }
+                ToastContent::Bar { label, level } => {
+                    column
+                        .text(
+                            format!("{} {}%", label, (level.clamp(0.0, 1.0) * 100.0).round()),
+                            text_size,
+                            color,
+                        )
+                        .bar(*level, bar_height, widget::TRACK, widget::HIGHLIGHT);
+                }
+            }
+
+            let mut faded = vec![Widget::rect(panel, widget::PANEL)];
+            faded.extend(column.into_widgets());
+            widgets.push(Widget::Faded {
+                alpha,
+                widgets: faded,
+            });
+            y += panel.size.h + padding;
+        }
+
+        if self.recording {
+            // A red dot and "REC" in the top right corner
+            let dot = text_size as f64 * 0.6;
+            let label_width = text_width("REC", text_size);
+            let height = Column::row_height(text_size);
+            let panel = Rectangle::from_loc_and_size(
+                (size.w - padding * 4.0 - dot - label_width, padding),
+                (dot + label_width + padding * 3.0, height + padding),
+            );
+            widgets.push(Widget::rect(panel, widget::PANEL));
+            widgets.push(Widget::rect(
+                Rectangle::from_loc_and_size(
+                    (panel.loc.x + padding, panel.loc.y + (panel.size.h - dot) / 2.0),
+                    (dot, dot),
+                ),
+                widget::WARNING,
+            ));
+            let mut column = Column::new(Rectangle::from_loc_and_size(
+                (panel.loc.x + padding * 2.0 + dot, panel.loc.y + padding / 2.0),
+                (label_width, height),
+            ));
+            column.text("REC", text_size, widget::TEXT);
+            widgets.extend(column.into_widgets());
+        }
+        widgets
+    }
+}
@@ -0,0 +1,152 @@
+use smithay::utils::{Logical, Point, Rectangle, Size};
+
+use super::drawing::text_width;
+
+/// A RGBA color, with straight (not premultiplied) alpha.
+pub type Color = [u8; 4];
+
+pub const TEXT: Color = [0xee, 0xee, 0xee, 0xff];
+pub const TEXT_DIM: Color = [0x99, 0x99, 0x99, 0xff];
+pub const WARNING: Color = [0xe0, 0x50, 0x40, 0xff];
+pub const PANEL: Color = [0x20, 0x20, 0x24, 0xe8];
+pub const HIGHLIGHT: Color = [0x3a, 0x6e, 0xc8, 0xff];
+/// The empty part of bars.
+pub const TRACK: Color = [0x50, 0x50, 0x58, 0xff];
+/// Drawn over the whole output to dim the game behind the menu.
+pub const DIM: Color = [0x00, 0x00, 0x00, 0xa0];
+
+/// Height of a line of text, relative to its font size.
+const LINE_HEIGHT: f64 = 1.4;
+
+/// Something drawn by the compositor itself, in output-local logical coordinates.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Widget {
+    Rect {
+        geometry: Rectangle<f64, Logical>,
+        color: Color,
+    },
+    /// A single line of text, `location` being its top-left corner.
+    Text {
+        text: String,
+        location: Point<f64, Logical>,
+        size: f32,
+        color: Color,
+    },
+    /// Widgets drawn with their opacity multiplied by `alpha`.
+    Faded { alpha: f32, widgets: Vec<Widget> },
+}
+
+impl Widget {
+    pub fn rect(geometry: Rectangle<f64, Logical>, color: Color) -> Widget {
+        Widget::Rect { geometry, color }
+    }
+
+    /// A rectangle covering the whole output.
+    pub fn fill(output_size: Size<i32, Logical>, color: Color) -> Widget {
+        Widget::rect(Rectangle::from_loc_and_size((0.0, 0.0), output_size.to_f64()), color)
+    }
+}
+
+/// Returns a rectangle of the given size centered in `area`.
+pub fn centered(area: Rectangle<f64, Logical>, size: Size<f64, Logical>) -> Rectangle<f64, Logical> {
+    Rectangle::from_loc_and_size(
+        (
+            area.loc.x + (area.size.w - size.w) / 2.0,
+            area.loc.y + (area.size.h - size.h) / 2.0,
+        ),
+        size,
+    )
+}
+
+/// Lays rows out from top to bottom inside an area.
+#[derive(Debug)]
+pub struct Column {
+    area: Rectangle<f64, Logical>,
+    /// Offset of the next row from the top of the area.
+    y: f64,
+    widgets: Vec<Widget>,
+}
+
+impl Column {
+    pub fn new(area: Rectangle<f64, Logical>) -> Column {
+        Column {
+            area,
+            y: 0.0,
+            widgets: Vec::new(),
+        }
+    }
+
+    /// Height taken by a row of text of the given size.
+    pub fn row_height(size: f32) -> f64 {
+        size as f64 * LINE_HEIGHT
+    }
+
+    /// Vertical space left for more rows.
+    pub fn remaining(&self) -> f64 {
+        (self.area.size.h - self.y).max(0.0)
+    }
+
+    pub fn space(&mut self, height: f64) -> &mut Column {
+        self.y += height;
+        self
+    }
+
+    pub fn text(&mut self, text: impl Into<String>, size: f32, color: Color) -> &mut Column {
+        let height = Self::row_height(size);
+        self.widgets.push(Widget::Text {
+            text: text.into(),
+            location: (
+                self.area.loc.x,
+                self.area.loc.y + self.y + (height - size as f64) / 2.0,
+            )
+                .into(),
+            size,
+            color,
+        });
+        self.y += height;
+        self
+    }
+
+    /// A row of text horizontally centered in the area.
+    pub fn centered_text(&mut self, text: impl Into<String>, size: f32, color: Color) -> &mut Column {
+        let text = text.into();
+        let offset = ((self.area.size.w - text_width(&text, size)) / 2.0).max(0.0);
+        self.text(text, size, color);
+        if let Some(Widget::Text { location, .. }) = self.widgets.last_mut() {
+            location.x += offset;
+        }
+        self
+    }
+
+    /// A row of text with a background spanning the width of the area.
+    pub fn highlighted_text(&mut self, text: impl Into<String>, size: f32, color: Color, background: Color) -> &mut Column {
+        let padding = size as f64 / 2.0;
+        self.widgets.push(Widget::rect(
+            Rectangle::from_loc_and_size(
+                (self.area.loc.x - padding, self.area.loc.y + self.y),
+                (self.area.size.w + 2.0 * padding, Self::row_height(size)),
+            ),
+            background,
+        ));
+        self.text(text, size, color)
+    }
+
+    /// A horizontal bar spanning the width of the area, filled up to `level` between 0 and 1.
+    pub fn bar(&mut self, level: f64, height: f64, track: Color, fill: Color) -> &mut Column {
+        let geometry = Rectangle::from_loc_and_size(
+            (self.area.loc.x, self.area.loc.y + self.y),
+            (self.area.size.w, height),
+        );
+        self.widgets.push(Widget::rect(geometry, track));
+        self.widgets.push(Widget::rect(
+            Rectangle::from_loc_and_size(geometry.loc, (geometry.size.w * level.clamp(0.0, 1.0), height)),
+            fill,
+        ));
+        self.y += height;
+        self
+    }
+
+    pub fn into_widgets(self) -> Vec<Widget> {
+        self.widgets
+    }
+}
@@ -0,0 +1,296 @@
+use std::{
+    ops::Deref,
+    time::{Duration, Instant},
+};
+
+use smithay::{
+    backend::input::{InputBackend, InputEvent},
+    reexports::{
+        calloop::{
+            timer::{Timer, TimerHandle},
+            LoopHandle,
+        },
+        wayland_protocols::unstable::idle_inhibit::v1::server::{
+            zwp_idle_inhibit_manager_v1::{self, ZwpIdleInhibitManagerV1},
+            zwp_idle_inhibitor_v1::ZwpIdleInhibitorV1,
+        },
+        wayland_server::{protocol::wl_surface::WlSurface, Display, Filter, Main},
+    },
+    wayland::compositor,
+};
+
+use crate::{
+    config::DisplayConfig,
+    protocols::ext_idle_notify_v1::{
+        ext_idle_notification_v1::ExtIdleNotificationV1,
+        ext_idle_notifier_v1::{self, ExtIdleNotifierV1},
+    },
+    state::{AnvilState, Backend},
+};
+
+/// Whether an input event comes from the user, rather than from devices being plugged in.
+pub fn is_user_activity<B: InputBackend>(event: &InputEvent<B>) -> bool {
+    !matches!(
+        event,
+        InputEvent::DeviceAdded { .. } | InputEvent::DeviceRemoved { .. } | InputEvent::Special(_)
+    )
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum IdleStage {
+    Active,
+    /// The backlight was turned down.
+    Dimmed,
+    /// The outputs are turned off.
+    Blanked,
+}
+
+/// An `ext_idle_notification_v1` of a client.
+#[derive(Debug)]
+struct IdleNotification {
+    resource: ExtIdleNotificationV1,
+    timeout: Duration,
+    /// The notification only counts input from its creation on.
+    created: Instant,
+    idled: bool,
+}
+
+/// Dims, then blanks the screen after some time without input, and tells the clients
+/// that asked about it.
+#[derive(Debug)]
+pub struct IdleTracker {
+    last_activity: Instant,
+    stage: IdleStage,
+    /// Whether the game shown asked for the screen to stay on.
+    inhibited: bool,
+    /// The surfaces of the idle inhibitors.
+    inhibitors: Vec<(ZwpIdleInhibitorV1, WlSurface)>,
+    notifications: Vec<IdleNotification>,
+    /// The brightness to go back to when waking up.
+    dimmed_from: Option<f64>,
+    dim_after: Option<Duration>,
+    blank_after: Option<Duration>,
+    dim_level: f64,
+    timer: TimerHandle<()>,
+    /// When the timer fires next.
+    next_check: Option<Instant>,
+}
+
+impl IdleTracker {
+    /// Creates the tracker from the `[display]` config, checking for idleness from the event loop.
+    pub fn new<BackendData: Backend + 'static>(
+        config: &DisplayConfig,
+        handle: &LoopHandle<'static, AnvilState<BackendData>>,
+    ) -> IdleTracker {
+        let timer = Timer::new().expect("Failed to create the idle timer");
+        let timer_handle = timer.handle();
+        handle
+            .insert_source(timer, |(), _, state| state.check_idle())
+            .expect("Failed to init the idle timer");
+
+        let timeout = |secs: u64| (secs > 0).then(|| Duration::from_secs(secs));
+        let mut tracker = IdleTracker {
+            last_activity: Instant::now(),
+            stage: IdleStage::Active,
+            inhibited: false,
+            inhibitors: Vec::new(),
+            notifications: Vec::new(),
+            dimmed_from: None,
+            dim_after: timeout(config.dim_after_secs),
+            blank_after: timeout(config.blank_after_secs),
+            dim_level: config.dim_brightness as f64 / 100.0,
+            timer: timer_handle,
+            next_check: None,
+        };
+        tracker.schedule(Instant::now());
+        tracker
+    }
+
+    /// Checks again when the next timeout after `now` is reached, unless the timer already
+    /// fires earlier.
+    fn schedule(&mut self, now: Instant) {
+        let deadlines = [self.dim_after, self.blank_after]
+            .iter()
+            .flatten()
+            .map(|timeout| self.last_activity + *timeout)
+            .chain(
+                self.notifications
+                    .iter()
+                    .map(|notification| notification.created.max(self.last_activity) + notification.timeout),
+            );
+        let next = match deadlines.filter(|deadline| *deadline > now).min() {
+            Some(next) => next,
+            None => return,
+        };
+        let pending = self
+            .next_check
+            .map(|pending| pending > now && pending <= next)
+            .unwrap_or(false);
+        if !pending {
+            self.next_check = Some(next);
+            self.timer.add_timeout(next - now, ());
+        }
+    }
+}
+
+/// Returns the surface a subsurface belongs to.
+fn root_surface(surface: &WlSurface) -> WlSurface {
+    let mut root = surface.clone();
+    while let Some(parent) = compositor::get_parent(&root) {
+        root = parent;
+    }
+    root
+}
+
+/// Creates the `zwp_idle_inhibit_manager_v1` and `ext_idle_notifier_v1` globals.
+pub fn init_idle_globals<BackendData: Backend + 'static>(display: &mut Display) {
+    display.create_global::<ZwpIdleInhibitManagerV1, _>(
+        1,
+        Filter::new(|(manager, _): (Main<ZwpIdleInhibitManagerV1>, u32), _, _| {
+            manager.quick_assign(|_, request, mut ddata| {
+                if let zwp_idle_inhibit_manager_v1::Request::CreateInhibitor { id, surface } = request {
+                    let state = ddata.get::<AnvilState<BackendData>>().unwrap();
+                    id.quick_assign(|_, _, _| {});
+                    id.assign_destructor(Filter::new(|inhibitor: ZwpIdleInhibitorV1, _, mut ddata| {
+                        if let Some(state) = ddata.get::<AnvilState<BackendData>>() {
+                            state.idle.inhibitors.retain(|(i, _)| *i != inhibitor);
+                            state.refresh_idle_inhibit();
+                        }
+                    }));
+                    state.idle.inhibitors.push((id.deref().clone(), surface));
+                    state.refresh_idle_inhibit();
+                }
+            });
+        }),
+    );
+
+    display.create_global::<ExtIdleNotifierV1, _>(
+        1,
+        Filter::new(|(notifier, _): (Main<ExtIdleNotifierV1>, u32), _, _| {
+            notifier.quick_assign(|_, request, mut ddata| {
+                if let ext_idle_notifier_v1::Request::GetIdleNotification { id, timeout, .. } = request {
+                    let state = ddata.get::<AnvilState<BackendData>>().unwrap();
+                    id.quick_assign(|_, _, _| {});
+                    id.assign_destructor(Filter::new(
+                        |notification: ExtIdleNotificationV1, _, mut ddata| {
+                            if let Some(state) = ddata.get::<AnvilState<BackendData>>() {
+                                state.idle.notifications.retain(|n| n.resource != notification);
+                            }
+                        },
+                    ));
+                    state.idle.notifications.push(IdleNotification {
+                        resource: id.deref().clone(),
+                        timeout: Duration::from_millis(timeout.max(1) as u64),
+                        created: Instant::now(),
+                        idled: false,
+                    });
+                    state.idle.schedule(Instant::now());
+                }
+            });
+        }),
+    );
+}
+
+impl<BackendData: 'static> AnvilState<BackendData> {
+    /// Honours the idle inhibitors of the game shown, if the menu is hidden.
+    pub fn refresh_idle_inhibit(&mut self) {
+        let mut top = None;
+        let window_map = self.window_map.borrow();
+        if !window_map.menu_on_top {
+            window_map.with_top_window(|toplevel, _, _| top = toplevel.get_surface().cloned());
+        }
+        drop(window_map);
+
+        let inhibited = top
+            .map(|top| {
+                self.idle
+                    .inhibitors
+                    .iter()
+                    .any(|(_, surface)| surface.as_ref().is_alive() && root_surface(surface) == top)
+            })
+            .unwrap_or(false);
+        if inhibited != self.idle.inhibited {
+            debug!(self.log, "Idle inhibited: {}", inhibited);
+            let idle = &mut self.idle;
+            idle.inhibited = inhibited;
+            idle.last_activity = Instant::now();
+            idle.schedule(idle.last_activity);
+            if inhibited {
+                self.undim();
+            }
+        }
+    }
+
+    fn undim(&mut self) {
+        if self.idle.stage == IdleStage::Dimmed {
+            self.idle.stage = IdleStage::Active;
+        }
+        if let (Some(level), Some(backlight)) = (self.idle.dimmed_from.take(), &mut self.backlight) {
+            backlight.set_level(level);
+        }
+    }
+}
+
+impl<BackendData: Backend + 'static> AnvilState<BackendData> {
+    /// Records user input, and wakes the screen and the idle clients up if needed.
+    pub fn notify_activity(&mut self) {
+        let idle = &mut self.idle;
+        idle.last_activity = Instant::now();
+        for notification in idle.notifications.iter_mut().filter(|n| n.idled) {
+            notification.idled = false;
+            notification.resource.resumed();
+        }
+        idle.schedule(idle.last_activity);
+
+        if idle.stage == IdleStage::Blanked {
+            info!(self.log, "Waking the screen up");
+            idle.stage = IdleStage::Active;
+            self.backend_data.set_blanked(false);
+        }
+        self.undim();
+    }
+
+    fn check_idle(&mut self) {
+        let now = Instant::now();
+        let idle = &mut self.idle;
+        if idle.next_check.map(|next| next <= now).unwrap_or(false) {
+            idle.next_check = None;
+        }
+        if idle.inhibited {
+            idle.last_activity = now;
+        }
+        let elapsed = now - idle.last_activity;
+        let reached = |timeout: Option<Duration>| timeout.map(|t| elapsed >= t).unwrap_or(false);
+
+        let last_activity = idle.last_activity;
+        for notification in idle.notifications.iter_mut().filter(|n| !n.idled) {
+            if now >= notification.created.max(last_activity) + notification.timeout {
+                notification.idled = true;
+                notification.resource.idled();
+            }
+        }
+
+        if reached(idle.blank_after) {
+            self.blank_screen();
+        } else if reached(idle.dim_after) && idle.stage == IdleStage::Active {
+            idle.stage = IdleStage::Dimmed;
+            if let Some(backlight) = &mut self.backlight {
+                if backlight.level() > idle.dim_level {
+                    debug!(self.log, "Dimming the screen");
+                    idle.dimmed_from = Some(backlight.level());
+                    backlight.set_level(idle.dim_level);
+                }
+            }
+        }
+        self.idle.schedule(now);
+    }
+
+    /// Turns the screen off until the next input.
+    pub fn blank_screen(&mut self) {
+        if self.idle.stage != IdleStage::Blanked {
+            info!(self.log, "Blanking the screen");
+            self.idle.stage = IdleStage::Blanked;
+            self.backend_data.set_blanked(true);
+        }
+    }
+}
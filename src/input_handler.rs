@@ -1,6 +1,10 @@
 use std::{process::Command, sync::atomic::Ordering};
 
-use crate::AnvilState;
+use crate::{
+    bindings::{Action, Bindings},
+    idle::is_user_activity,
+    AnvilState,
+};
 
 #[cfg(feature = "udev")]
 use crate::udev::UdevData;
@@ -30,14 +34,28 @@ use smithay::{
         input::{
             Device, DeviceCapability, PointerMotionEvent, ProximityState, TabletToolButtonEvent,
             TabletToolEvent, TabletToolProximityEvent, TabletToolTipEvent, TabletToolTipState,
+            TouchDownEvent, TouchEvent, TouchMotionEvent,
         },
         session::Session,
     },
-    utils::{Logical, Point},
+    utils::{Logical, Point, Rectangle, Size},
     wayland::tablet_manager::{TabletDescriptor, TabletSeatTrait},
 };
 
-impl<Backend> AnvilState<Backend> {
+/// The evdev code of the left mouse button.
+#[cfg(feature = "udev")]
+const BTN_LEFT: u32 = 0x110;
+
+/// The finger followed on a touchscreen, any other being ignored until it is lifted.
+#[cfg(feature = "udev")]
+#[derive(Debug, Clone, Copy)]
+pub struct ActiveTouch {
+    slot: Option<input::TouchSlot>,
+    /// Whether it went down on the menu, rather than on a game.
+    on_menu: bool,
+}
+
+impl<Backend: 'static> AnvilState<Backend> {
     fn keyboard_key_to_action<B: InputBackend>(&mut self, evt: B::KeyboardKeyEvent) -> KeyAction {
         let keycode = evt.key_code();
         let state = evt.state();
@@ -46,6 +64,7 @@ impl<Backend> AnvilState<Backend> {
         let log = &self.log;
         let time = Event::time(&evt);
         let suppressed_keys = &mut self.suppressed_keys;
+        let bindings = &self.bindings;
         self.keyboard
             .input(keycode, state, serial, time, |modifiers, handle| {
                 let keysym = handle.modified_sym();
@@ -62,7 +81,7 @@ impl<Backend> AnvilState<Backend> {
                 // so that we can decide on a release if the key
                 // should be forwarded to the client or not.
                 if let KeyState::Pressed = state {
-                    let action = process_keyboard_shortcut(*modifiers, keysym);
+                    let action = process_keyboard_shortcut(bindings, *modifiers, keysym);
 
                     if action.is_some() {
                         suppressed_keys.push(keysym);
@@ -91,12 +110,10 @@ impl<Backend> AnvilState<Backend> {
             input::ButtonState::Pressed => {
                 // change the keyboard focus unless the pointer is grabbed
                 if !self.pointer.is_grabbed() {
-                    let under = self
-                        .window_map
+                    self.window_map
                         .borrow_mut()
                         .get_surface_and_bring_to_top(self.pointer_location);
-                    self.keyboard
-                        .set_focus(under.as_ref().map(|&(ref s, _)| s), serial);
+                    self.refresh_game_visibility();
                 }
                 wl_pointer::ButtonState::Pressed
             }
@@ -146,6 +163,9 @@ impl<Backend> AnvilState<Backend> {
 #[cfg(feature = "backend_winit")]
 impl AnvilState<WinitData> {
     pub fn process_input_event<B: InputBackend>(&mut self, event: InputEvent<B>) {
+        if is_user_activity(&event) {
+            self.notify_activity();
+        }
         match event {
             InputEvent::Keyboard { event, .. } => match self.keyboard_key_to_action::<B>(event) {
                 KeyAction::None => {}
@@ -163,6 +183,7 @@ impl AnvilState<WinitData> {
                         );
                     }
                 }
+                KeyAction::Action(action) => self.run_action(action),
                 KeyAction::ScaleUp => {
                     let current_scale = {
                         self.output_map
@@ -234,6 +255,10 @@ impl AnvilState<WinitData> {
 #[cfg(feature = "udev")]
 impl AnvilState<UdevData> {
     pub fn process_input_event<B: InputBackend>(&mut self, event: InputEvent<B>) {
+        if is_user_activity(&event) {
+            self.notify_activity();
+        }
+        self.schedule_render();
         match event {
             InputEvent::Keyboard { event, .. } => match self.keyboard_key_to_action::<B>(event) {
                 KeyAction::None => {}
@@ -258,6 +283,7 @@ impl AnvilState<UdevData> {
                         );
                     }
                 }
+                KeyAction::Action(action) => self.run_action(action),
                 KeyAction::Screen(num) => {
                     let geometry = self.output_map.borrow().find_by_index(num).map(|o| o.geometry());
 
@@ -322,7 +348,14 @@ impl AnvilState<UdevData> {
             InputEvent::TabletToolProximity { event, .. } => self.on_tablet_tool_proximity::<B>(event),
             InputEvent::TabletToolTip { event, .. } => self.on_tablet_tool_tip::<B>(event),
             InputEvent::TabletToolButton { event, .. } => self.on_tablet_button::<B>(event),
+            InputEvent::TouchDown { event, .. } => self.on_touch_down::<B>(event),
+            InputEvent::TouchMotion { event, .. } => self.on_touch_motion::<B>(event),
+            InputEvent::TouchUp { event, .. } => self.on_touch_up(event.slot(), event.time(), true),
+            InputEvent::TouchCancel { event, .. } => self.on_touch_up(event.slot(), event.time(), false),
             InputEvent::DeviceAdded { device } => {
+                if device.has_capability(DeviceCapability::Pointer) {
+                    self.backend_data.pointer_devices += 1;
+                }
                 if device.has_capability(DeviceCapability::TabletTool) {
                     self.seat
                         .tablet_seat()
@@ -330,6 +363,13 @@ impl AnvilState<UdevData> {
                 }
             }
             InputEvent::DeviceRemoved { device } => {
+                if device.has_capability(DeviceCapability::Pointer) {
+                    let devices = self.backend_data.pointer_devices.saturating_sub(1);
+                    self.backend_data.pointer_devices = devices;
+                    if devices == 0 {
+                        self.backend_data.cursor_visible = false;
+                    }
+                }
                 if device.has_capability(DeviceCapability::TabletTool) {
                     let tablet_seat = self.seat.tablet_seat();
 
@@ -350,6 +390,7 @@ impl AnvilState<UdevData> {
     fn on_pointer_move<B: InputBackend>(&mut self, evt: B::PointerMotionEvent) {
         let serial = SCOUNTER.next_serial();
         self.pointer_location += evt.delta();
+        self.backend_data.cursor_visible = true;
 
         // clamp to screen limits
         // this event is never generated by winit
@@ -366,10 +407,12 @@ impl AnvilState<UdevData> {
         let tablet_seat = self.seat.tablet_seat();
         let window_map = self.window_map.borrow();
 
-        let output_geometry = output_map.with_primary().map(|o| o.geometry());
+        let position = output_map
+            .with_primary()
+            .map(|o| o.panel_position(|size| evt.position_transformed(size)));
 
-        if let Some(rect) = output_geometry {
-            *pointer_location = evt.position_transformed(rect.size) + rect.loc.to_f64();
+        if let Some(position) = position {
+            *pointer_location = position;
 
             let under = window_map.get_surface_under(*pointer_location);
             let tablet = tablet_seat.get_tablet(&TabletDescriptor::from(&evt.device()));
@@ -412,13 +455,15 @@ impl AnvilState<UdevData> {
         let tablet_seat = self.seat.tablet_seat();
         let window_map = self.window_map.borrow();
 
-        let output_geometry = output_map.with_primary().map(|o| o.geometry());
+        let position = output_map
+            .with_primary()
+            .map(|o| o.panel_position(|size| evt.position_transformed(size)));
 
-        if let Some(rect) = output_geometry {
+        if let Some(position) = position {
             let tool = evt.tool();
             tablet_seat.add_tool(&tool);
 
-            *pointer_location = evt.position_transformed(rect.size) + rect.loc.to_f64();
+            *pointer_location = position;
 
             let under = window_map.get_surface_under(*pointer_location);
             let tablet = tablet_seat.get_tablet(&TabletDescriptor::from(&evt.device()));
@@ -479,6 +524,98 @@ impl AnvilState<UdevData> {
         }
     }
 
+    /// Returns where a touch is on the touchscreen, which is taken to cover the primary
+    /// output, and the geometry of the output.
+    fn touch_position(
+        &self,
+        position: impl FnOnce(Size<i32, Logical>) -> Point<f64, Logical>,
+    ) -> Option<(Point<f64, Logical>, Rectangle<i32, Logical>)> {
+        let output_map = self.output_map.borrow();
+        let output = output_map.with_primary()?;
+        Some((output.panel_position(position), output.geometry()))
+    }
+
+    /// The menu takes the touches while it is shown. Smithay's seat has no `wl_touch`, so
+    /// games get the first finger as the pointer, pressing the left button.
+    fn on_touch_down<B: InputBackend>(&mut self, evt: B::TouchDownEvent) {
+        if self.backend_data.touch.is_some() {
+            return;
+        }
+        let (position, geometry) = match self.touch_position(|size| evt.position_transformed(size)) {
+            Some(touch) => touch,
+            None => return,
+        };
+        let on_menu = self.window_map.borrow().menu_on_top;
+        self.backend_data.touch = Some(ActiveTouch {
+            slot: evt.slot(),
+            on_menu,
+        });
+
+        if on_menu {
+            self.on_menu_touch_down(position - geometry.loc.to_f64(), geometry.size);
+            return;
+        }
+        // The finger shows where it points
+        self.backend_data.cursor_visible = false;
+        self.pointer_location = position;
+        let under = self.window_map.borrow().get_surface_under(position);
+        self.pointer
+            .motion(position, under, SCOUNTER.next_serial(), evt.time());
+
+        if !self.pointer.is_grabbed() {
+            // The window touched is shown, which moves the keyboard focus to it
+            self.window_map
+                .borrow_mut()
+                .get_surface_and_bring_to_top(position);
+            self.refresh_game_visibility();
+        }
+        let serial = SCOUNTER.next_serial();
+        self.pointer
+            .button(BTN_LEFT, wl_pointer::ButtonState::Pressed, serial, evt.time());
+    }
+
+    fn on_touch_motion<B: InputBackend>(&mut self, evt: B::TouchMotionEvent) {
+        let touch = match self.backend_data.touch {
+            Some(touch) if touch.slot == evt.slot() => touch,
+            _ => return,
+        };
+        let (position, geometry) = match self.touch_position(|size| evt.position_transformed(size)) {
+            Some(touch) => touch,
+            None => return,
+        };
+
+        if touch.on_menu {
+            self.on_menu_touch_motion(position - geometry.loc.to_f64());
+        } else {
+            self.pointer_location = position;
+            let under = self.window_map.borrow().get_surface_under(position);
+            self.pointer
+                .motion(position, under, SCOUNTER.next_serial(), evt.time());
+        }
+    }
+
+    /// Ends a touch, which is only a tap on the menu when the finger was `lifted`.
+    fn on_touch_up(&mut self, slot: Option<input::TouchSlot>, time: u32, lifted: bool) {
+        let touch = match self.backend_data.touch {
+            Some(touch) if touch.slot == slot => touch,
+            _ => return,
+        };
+        self.backend_data.touch = None;
+
+        if !touch.on_menu {
+            self.pointer.button(
+                BTN_LEFT,
+                wl_pointer::ButtonState::Released,
+                SCOUNTER.next_serial(),
+                time,
+            );
+        } else if lifted && self.window_map.borrow().menu_on_top {
+            self.on_menu_touch_up();
+        } else {
+            self.on_menu_touch_cancel();
+        }
+    }
+
     fn clamp_coords(&self, pos: Point<f64, Logical>) -> Point<f64, Logical> {
         if self.output_map.borrow().is_empty() {
             return pos;
@@ -503,6 +640,9 @@ impl AnvilState<UdevData> {
 #[cfg(feature = "x11")]
 impl AnvilState<X11Data> {
     pub fn process_input_event<B: InputBackend>(&mut self, event: InputEvent<B>) {
+        if is_user_activity(&event) {
+            self.notify_activity();
+        }
         match event {
             InputEvent::Keyboard { event } => match self.keyboard_key_to_action::<B>(event) {
                 KeyAction::None => (),
@@ -524,6 +664,8 @@ impl AnvilState<X11Data> {
                     }
                 }
 
+                KeyAction::Action(action) => self.run_action(action),
+
                 KeyAction::ScaleUp => {
                     let current_scale = {
                         self.output_map
@@ -594,12 +736,20 @@ enum KeyAction {
     Screen(usize),
     ScaleUp,
     ScaleDown,
+    /// Run an action from the bindings
+    Action(Action),
     /// Do nothing more
     None,
 }
 
-fn process_keyboard_shortcut(modifiers: ModifiersState, keysym: Keysym) -> Option<KeyAction> {
-    if modifiers.ctrl && modifiers.alt && keysym == xkb::KEY_BackSpace
+fn process_keyboard_shortcut(
+    bindings: &Bindings,
+    modifiers: ModifiersState,
+    keysym: Keysym,
+) -> Option<KeyAction> {
+    if let Some(action) = bindings.key_action(&modifiers, keysym) {
+        Some(KeyAction::Action(action))
+    } else if modifiers.ctrl && modifiers.alt && keysym == xkb::KEY_BackSpace
         || modifiers.logo && keysym == xkb::KEY_q
     {
         // ctrl+alt+backspace = quit
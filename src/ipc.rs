@@ -0,0 +1,265 @@
+use std::{
+    io::{self, Read, Write},
+    os::unix::net::{UnixListener, UnixStream},
+    path::PathBuf,
+};
+
+use smithay::reexports::calloop::{generic::Generic, Interest, LoopHandle, Mode, PostAction};
+
+use crate::{gui::osd::Toast, state::AnvilState};
+
+/// Lines longer than this are rejected, and the connection closed.
+const MAX_LINE: usize = 4096;
+/// Clients that do not read their replies are dropped once this much is waiting.
+const MAX_PENDING_REPLIES: usize = 64 * 1024;
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("Unknown command: {0}")]
+    UnknownCommand(String),
+    #[error("Missing argument")]
+    MissingArgument,
+    #[error("Invalid percentage: {0}")]
+    InvalidPercentage(String),
+}
+
+/// A command sent by an external tool, one per line.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    /// `toast <text>`
+    Toast(String),
+    /// `warning <text>`
+    Warning(String),
+    /// `bar <label> <percent>`
+    Bar { label: String, level: f64 },
+}
+
+impl Command {
+    pub fn parse(line: &str) -> Result<Command, Error> {
+        let line = line.trim();
+        let (name, args) = line.split_once(' ').unwrap_or((line, ""));
+        let args = args.trim();
+        match name {
+            "toast" | "warning" if args.is_empty() => Err(Error::MissingArgument),
+            "toast" => Ok(Command::Toast(args.to_owned())),
+            "warning" => Ok(Command::Warning(args.to_owned())),
+            "bar" => {
+                let (label, percent) = args.rsplit_once(' ').ok_or(Error::MissingArgument)?;
+                let percent = percent
+                    .trim_end_matches('%')
+                    .parse::<f64>()
+                    .map_err(|_| Error::InvalidPercentage(percent.to_owned()))?;
+                Ok(Command::Bar {
+                    label: label.trim().to_owned(),
+                    level: percent / 100.0,
+                })
+            }
+            _ => Err(Error::UnknownCommand(name.to_owned())),
+        }
+    }
+}
+
+/// The listening socket, removed when dropped.
+#[derive(Debug)]
+pub struct IpcSocket {
+    path: PathBuf,
+}
+
+impl Drop for IpcSocket {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Returns `$XDG_RUNTIME_DIR/waystation-<wayland socket>.sock`.
+fn socket_path(socket_name: Option<&str>) -> Option<PathBuf> {
+    let runtime_dir = PathBuf::from(std::env::var_os("XDG_RUNTIME_DIR")?);
+    Some(runtime_dir.join(format!("waystation-{}.sock", socket_name.unwrap_or("0"))))
+}
+
+/// Starts listening for commands on a unix socket, and exports its path as
+/// `WAYSTATION_SOCKET` for the games.
+///
+/// Every command is answered with `ok` or `error <reason>` on its own line.
+pub fn init_ipc<BackendData: 'static>(
+    handle: &LoopHandle<'static, AnvilState<BackendData>>,
+    socket_name: Option<&str>,
+    log: &slog::Logger,
+) -> Option<IpcSocket> {
+    let path = match socket_path(socket_name) {
+        Some(path) => path,
+        None => {
+            warn!(log, "XDG_RUNTIME_DIR is not set, not listening for commands");
+            return None;
+        }
+    };
+    // A previous instance may have left its socket behind
+    let _ = std::fs::remove_file(&path);
+    let listener = match UnixListener::bind(&path).and_then(|listener| {
+        listener.set_nonblocking(true)?;
+        Ok(listener)
+    }) {
+        Ok(listener) => listener,
+        Err(err) => {
+            error!(log, "Unable to listen for commands: {}", err; "path" => path.display().to_string());
+            return None;
+        }
+    };
+
+    let result = handle.insert_source(
+        Generic::new(listener, Interest::READ, Mode::Level),
+        |_, listener, state: &mut AnvilState<BackendData>| {
+            loop {
+                match listener.accept() {
+                    Ok((stream, _)) => state.add_ipc_client(stream),
+                    Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                    Err(err) => warn!(state.log, "Unable to accept a connection: {}", err),
+                }
+            }
+            Ok(PostAction::Continue)
+        },
+    );
+    if let Err(err) = result {
+        error!(log, "Unable to listen for commands: {}", err);
+        return None;
+    }
+
+    info!(log, "Listening for commands"; "path" => path.display().to_string());
+    std::env::set_var("WAYSTATION_SOCKET", &path);
+    Some(IpcSocket { path })
+}
+
+/// Writes as much of the pending replies as the socket takes without blocking.
+fn flush_replies(stream: &mut UnixStream, replies: &mut Vec<u8>) -> io::Result<()> {
+    while !replies.is_empty() {
+        match stream.write(replies) {
+            Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+            Ok(len) => {
+                replies.drain(..len);
+            }
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(())
+}
+
+impl<BackendData: 'static> AnvilState<BackendData> {
+    fn add_ipc_client(&mut self, stream: UnixStream) {
+        if let Err(err) = stream.set_nonblocking(true) {
+            warn!(self.log, "Unable to set up a connection: {}", err);
+            return;
+        }
+        let mut buf = Vec::new();
+        let mut replies = Vec::new();
+        // Edge triggered, so that the socket being writable only wakes us up when replies
+        // could not be sent at once
+        let result = self.handle.insert_source(
+            Generic::new(stream, Interest::BOTH, Mode::Edge),
+            move |_, stream, state: &mut AnvilState<BackendData>| {
+                let mut chunk = [0u8; 1024];
+                // Commands sent right before closing the connection still run
+                let closed = loop {
+                    match stream.read(&mut chunk) {
+                        Ok(0) => break true,
+                        Ok(len) => buf.extend_from_slice(&chunk[..len]),
+                        Err(err) if err.kind() == io::ErrorKind::WouldBlock => break false,
+                        Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                        Err(_) => return Ok(PostAction::Remove),
+                    }
+                };
+
+                while let Some(end) = buf.iter().position(|&b| b == b'\n') {
+                    let line = String::from_utf8_lossy(&buf[..end]).into_owned();
+                    buf.drain(..=end);
+                    match Command::parse(&line) {
+                        Ok(command) => {
+                            state.run_ipc_command(command);
+                            replies.extend_from_slice(b"ok\n");
+                        }
+                        Err(err) => replies.extend_from_slice(format!("error {}\n", err).as_bytes()),
+                    }
+                }
+                let too_long = buf.len() > MAX_LINE;
+                if too_long {
+                    replies.extend_from_slice(b"error Line too long\n");
+                }
+
+                if flush_replies(stream, &mut replies).is_err()
+                    || closed
+                    || too_long
+                    || replies.len() > MAX_PENDING_REPLIES
+                {
+                    return Ok(PostAction::Remove);
+                }
+                Ok(PostAction::Continue)
+            },
+        );
+        if let Err(err) = result {
+            warn!(self.log, "Unable to watch a connection: {}", err);
+        }
+    }
+
+    fn run_ipc_command(&mut self, command: Command) {
+        debug!(self.log, "Command received"; "command" => format!("{:?}", command));
+        match command {
+            Command::Toast(text) => self.osd.post(Toast::text(text)),
+            Command::Warning(text) => self.osd.post(Toast::text(text).warning()),
+            Command::Bar { label, level } => self.osd.post(Toast::bar(label, level)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_toasts() {
+        assert_eq!(
+            Command::parse("toast Hello there\n").unwrap(),
+            Command::Toast("Hello there".to_owned())
+        );
+        assert_eq!(
+            Command::parse("  warning   Low battery  ").unwrap(),
+            Command::Warning("Low battery".to_owned())
+        );
+    }
+
+    #[test]
+    fn parses_bars() {
+        assert_eq!(
+            Command::parse("bar Fan speed 40%").unwrap(),
+            Command::Bar {
+                label: "Fan speed".to_owned(),
+                level: 0.4
+            }
+        );
+        assert_eq!(
+            Command::parse("bar Volume 75").unwrap(),
+            Command::Bar {
+                label: "Volume".to_owned(),
+                level: 0.75
+            }
+        );
+    }
+
+    #[test]
+    fn rejects_missing_arguments() {
+        assert!(matches!(Command::parse("toast"), Err(Error::MissingArgument)));
+        assert!(matches!(
+            Command::parse("warning   "),
+            Err(Error::MissingArgument)
+        ));
+        assert!(matches!(Command::parse("bar"), Err(Error::MissingArgument)));
+        assert!(matches!(Command::parse("bar 50%"), Err(Error::MissingArgument)));
+    }
+
+    #[test]
+    fn rejects_invalid_commands() {
+        assert!(matches!(Command::parse("bar Volume loud"), Err(Error::InvalidPercentage(p)) if p == "loud"));
+        assert!(matches!(Command::parse("reboot now"), Err(Error::UnknownCommand(c)) if c == "reboot"));
+        assert!(matches!(Command::parse(""), Err(Error::UnknownCommand(c)) if c.is_empty()));
+    }
+}
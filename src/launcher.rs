@@ -0,0 +1,321 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    process::Command,
+    time::SystemTime,
+};
+
+use crate::config::{self, AppConfig, Config, PausePolicy, ScalingMode};
+
+/// A game or application that can be started from the menu.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AppEntry {
+    pub name: String,
+    /// Program followed by its arguments.
+    pub exec: Vec<String>,
+    pub working_dir: Option<PathBuf>,
+    pub env: HashMap<String, String>,
+    pub icon: Option<PathBuf>,
+    pub pause: PausePolicy,
+    pub scaling: ScalingMode,
+    /// The refresh rate wanted while shown, in Hz.
+    pub refresh_rate: Option<f64>,
+}
+
+impl AppEntry {
+    fn from_config(app: AppConfig) -> Option<AppEntry> {
+        Some(AppEntry {
+            exec: shlex::split(&app.exec).filter(|argv| !argv.is_empty())?,
+            name: app.name,
+            working_dir: app.working_dir.map(config::expand_home),
+            env: app.env,
+            icon: app.icon.map(config::expand_home),
+            pause: app.pause,
+            scaling: app.scaling,
+            refresh_rate: app.refresh_rate,
+        })
+    }
+
+    /// Parses the `[Desktop Entry]` group of a freedesktop `.desktop` file.
+    ///
+    /// Environment overrides can be given with the `X-Waystation-Env=KEY=value;KEY2=value2;` key,
+    /// the pause policy with `X-Waystation-Pause=frames;stop;mute;`, the scaling mode with
+    /// `X-Waystation-Scaling=integer`, and the refresh rate with `X-Waystation-RefreshRate=50`.
+    fn from_desktop_file(contents: &str) -> Option<AppEntry> {
+        let mut in_entry_group = false;
+        let mut keys = HashMap::new();
+        for line in contents.lines().map(str::trim) {
+            if line.starts_with('[') {
+                in_entry_group = line == "[Desktop Entry]";
+            } else if in_entry_group && !line.starts_with('#') {
+                if let Some((key, value)) = line.split_once('=') {
+                    keys.insert(key.trim(), value.trim());
+                }
+            }
+        }
+
+        if keys.get("Type").map(|t| *t != "Application").unwrap_or(true)
+            || keys.get("Hidden") == Some(&"true")
+            || keys.get("NoDisplay") == Some(&"true")
+        {
+            return None;
+        }
+
+        // Field codes (%f, %U, ...) are meaningless for us since we never pass files
+        let exec = shlex::split(keys.get("Exec")?)?
+            .into_iter()
+            .filter(|arg| !(arg.len() == 2 && arg.starts_with('%') && arg != "%%"))
+            .map(|arg| arg.replace("%%", "%"))
+            .collect::<Vec<_>>();
+        if exec.is_empty() {
+            return None;
+        }
+
+        let env = keys
+            .get("X-Waystation-Env")
+            .map(|vars| {
+                vars.split(';')
+                    .filter_map(|var| var.split_once('='))
+                    .map(|(key, value)| (key.to_owned(), value.to_owned()))
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        let mut pause = PausePolicy::default();
+        for flag in keys.get("X-Waystation-Pause").iter().flat_map(|flags| flags.split(';')) {
+            match flag.trim() {
+                "frames" => pause.frames = true,
+                "stop" => pause.stop = true,
+                "mute" => pause.mute = true,
+                _ => {}
+            }
+        }
+
+        Some(AppEntry {
+            name: keys.get("Name")?.to_string(),
+            exec,
+            working_dir: keys.get("Path").map(PathBuf::from),
+            env,
+            icon: keys.get("Icon").map(PathBuf::from),
+            pause,
+            scaling: keys
+                .get("X-Waystation-Scaling")
+                .and_then(|name| ScalingMode::from_name(name))
+                .unwrap_or_default(),
+            refresh_rate: keys
+                .get("X-Waystation-RefreshRate")
+                .and_then(|rate| rate.parse().ok())
+                .filter(|&rate: &f64| rate > 0.0),
+        })
+    }
+
+    /// Builds the `Command` used to start this application.
+    pub fn command(&self) -> Command {
+        let mut command = Command::new(&self.exec[0]);
+        command.args(&self.exec[1..]).envs(&self.env);
+        if let Some(working_dir) = &self.working_dir {
+            command.current_dir(working_dir);
+        }
+        command
+    }
+}
+
+/// The list of applications shown in the menu.
+///
+/// Built from the config file and the `.desktop` files of the configured directory, and
+/// re-scanned whenever one of them changes.
+#[derive(Debug)]
+pub struct Launcher {
+    apps: Vec<AppEntry>,
+    desktop_dir: Option<PathBuf>,
+    /// Modification times of the files the current list was built from.
+    sources: Vec<(PathBuf, Option<SystemTime>)>,
+    log: slog::Logger,
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+impl Launcher {
+    pub fn new(log: slog::Logger) -> Launcher {
+        let mut launcher = Launcher {
+            apps: Vec::new(),
+            desktop_dir: None,
+            sources: Vec::new(),
+            log,
+        };
+        launcher.scan();
+        launcher
+    }
+
+    pub fn apps(&self) -> &[AppEntry] {
+        &self.apps
+    }
+
+    fn scan(&mut self) {
+        let config_path = Config::path();
+        let config = Config::load(&self.log);
+
+        self.sources = vec![(config_path.clone(), modified(&config_path))];
+        self.desktop_dir = config.launcher.desktop_dir.map(config::expand_home);
+        self.apps = config
+            .launcher
+            .apps
+            .into_iter()
+            .filter_map(|app| {
+                let name = app.name.clone();
+                let entry = AppEntry::from_config(app);
+                if entry.is_none() {
+                    warn!(self.log, "Ignoring app with an empty or invalid exec line"; "name" => name);
+                }
+                entry
+            })
+            .collect();
+
+        if let Some(dir) = self.desktop_dir.clone() {
+            self.sources.push((dir.clone(), modified(&dir)));
+
+            let mut files = std::fs::read_dir(&dir)
+                .map(|entries| {
+                    entries
+                        .filter_map(|e| e.ok().map(|e| e.path()))
+                        .filter(|path| path.extension().map(|ext| ext == "desktop").unwrap_or(false))
+                        .collect::<Vec<_>>()
+                })
+                .unwrap_or_else(|err| {
+                    warn!(self.log, "Unable to read desktop entry directory: {}", err; "dir" => dir.display().to_string());
+                    Vec::new()
+                });
+            files.sort();
+
+            for path in files {
+                match std::fs::read_to_string(&path) {
+                    Ok(contents) => self.apps.extend(AppEntry::from_desktop_file(&contents)),
+                    Err(err) => warn!(self.log, "Unable to read desktop entry: {}", err; "path" => path.display().to_string()),
+                }
+                let mtime = modified(&path);
+                self.sources.push((path, mtime));
+            }
+        }
+
+        info!(self.log, "Loaded {} launcher entries", self.apps.len());
+    }
+
+    /// Re-scans the config file and desktop entries if any of them changed.
+    ///
+    /// Returns `true` if the list was rebuilt.
+    pub fn refresh(&mut self) -> bool {
+        let changed = self
+            .sources
+            .iter()
+            .any(|(path, mtime)| modified(path) != *mtime);
+        if changed {
+            self.scan();
+        }
+        changed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(keys: &str) -> Option<AppEntry> {
+        AppEntry::from_desktop_file(&format!("[Desktop Entry]\nType=Application\n{}", keys))
+    }
+
+    #[test]
+    fn parses_entry() {
+        let app = parse(
+            "Name=RetroArch\nExec=retroarch --fullscreen\nPath=/games\nIcon=/usr/share/pixmaps/ra.svg\n",
+        )
+        .unwrap();
+        assert_eq!(app.name, "RetroArch");
+        assert_eq!(app.exec, ["retroarch", "--fullscreen"]);
+        assert_eq!(app.working_dir, Some(PathBuf::from("/games")));
+        assert_eq!(app.icon, Some(PathBuf::from("/usr/share/pixmaps/ra.svg")));
+        assert!(app.env.is_empty());
+        assert_eq!(app.pause, PausePolicy::default());
+        assert_eq!(app.scaling, ScalingMode::default());
+        assert_eq!(app.refresh_rate, None);
+    }
+
+    #[test]
+    fn only_reads_desktop_entry_group() {
+        let contents = "# Comment\n[Desktop Entry]\nType=Application\nName=Game\nExec=game\n\
+                        [Desktop Action Other]\nName=Other\nExec=other\n";
+        let app = AppEntry::from_desktop_file(contents).unwrap();
+        assert_eq!(app.name, "Game");
+        assert_eq!(app.exec, ["game"]);
+    }
+
+    #[test]
+    fn removes_field_codes() {
+        let app = parse("Name=Game\nExec=game %U --level \"my level\" %f\n").unwrap();
+        assert_eq!(app.exec, ["game", "--level", "my level"]);
+    }
+
+    #[test]
+    fn unescapes_percent() {
+        let app = parse("Name=Game\nExec=game --volume=100%% %%\n").unwrap();
+        assert_eq!(app.exec, ["game", "--volume=100%", "%"]);
+    }
+
+    #[test]
+    fn skips_hidden_entries() {
+        assert_eq!(parse("Name=Game\nExec=game\nHidden=true\n"), None);
+        assert_eq!(parse("Name=Game\nExec=game\nNoDisplay=true\n"), None);
+        assert!(parse("Name=Game\nExec=game\nNoDisplay=false\n").is_some());
+    }
+
+    #[test]
+    fn skips_incomplete_entries() {
+        assert_eq!(parse("Exec=game\n"), None);
+        assert_eq!(parse("Name=Game\n"), None);
+        assert_eq!(parse("Name=Game\nExec=%U\n"), None);
+        assert_eq!(parse("Name=Game\nExec=game \"unterminated\n"), None);
+        assert_eq!(
+            AppEntry::from_desktop_file("[Desktop Entry]\nType=Link\nName=Site\nExec=site\n"),
+            None
+        );
+        assert_eq!(
+            AppEntry::from_desktop_file("[Desktop Entry]\nName=Game\nExec=game\n"),
+            None
+        );
+    }
+
+    #[test]
+    fn parses_waystation_keys() {
+        let app = parse(
+            "Name=Game\nExec=game\nX-Waystation-Env=SDL_VIDEODRIVER=wayland;LANG=C;\n\
+             X-Waystation-Pause=stop;mute;\nX-Waystation-Scaling=integer\nX-Waystation-RefreshRate=50\n",
+        )
+        .unwrap();
+        assert_eq!(
+            app.env.get("SDL_VIDEODRIVER").map(String::as_str),
+            Some("wayland")
+        );
+        assert_eq!(app.env.get("LANG").map(String::as_str), Some("C"));
+        assert_eq!(app.env.len(), 2);
+        assert!(!app.pause.frames && app.pause.stop && app.pause.mute);
+        assert_eq!(app.scaling, ScalingMode::Integer);
+        assert_eq!(app.refresh_rate, Some(50.0));
+    }
+
+    #[test]
+    fn ignores_invalid_refresh_rate() {
+        assert_eq!(
+            parse("Name=Game\nExec=game\nX-Waystation-RefreshRate=fast\n")
+                .unwrap()
+                .refresh_rate,
+            None
+        );
+        assert_eq!(
+            parse("Name=Game\nExec=game\nX-Waystation-RefreshRate=0\n")
+                .unwrap()
+                .refresh_rate,
+            None
+        );
+    }
+}
@@ -11,14 +11,33 @@ extern crate slog;
 
 pub mod gui;
 
+pub mod audio;
+pub mod backlight;
+pub mod bindings;
 #[cfg(feature = "udev")]
 pub mod cursor;
+pub mod config;
+pub mod damage;
 pub mod drawing;
+pub mod gamepad;
+pub mod games;
+pub mod idle;
 pub mod input_handler;
+pub mod ipc;
+pub mod launcher;
 pub mod output_map;
+pub mod power;
+pub mod presentation;
+mod protocols;
+pub mod recording;
 #[cfg(any(feature = "udev", feature = "backend_winit", feature = "x11"))]
 pub mod render;
+#[cfg(feature = "udev")]
+pub mod scanout;
+pub mod screencopy;
+pub mod screenshot;
 pub mod shell;
+pub mod sleep;
 pub mod state;
 #[cfg(feature = "udev")]
 pub mod udev;
@@ -1,4 +1,7 @@
-use std::{cell::RefCell, rc::Rc};
+use std::{
+    cell::{Cell, RefCell},
+    rc::Rc,
+};
 
 use smithay::{
     reexports::{
@@ -8,17 +11,17 @@ use smithay::{
                 wl_output,
                 wl_surface::{self, WlSurface},
             },
-            Display, Global, UserDataMap,
+            Client, Display, Global, UserDataMap,
         },
     },
-    utils::{Logical, Point, Rectangle, Size},
+    utils::{Logical, Physical, Point, Rectangle, Size},
     wayland::{
         compositor::{with_surface_tree_downward, SubsurfaceCachedState, TraversalAction},
         output::{self, Mode, PhysicalProperties},
     },
 };
 
-use crate::shell::SurfaceData;
+use crate::{damage, shell::SurfaceData};
 
 #[derive(Debug)]
 pub struct Output {
@@ -28,9 +31,13 @@ pub struct Output {
     surfaces: Vec<WlSurface>,
     layer_surfaces: RefCell<Vec<wl_surface::WlSurface>>,
     current_mode: Mode,
+    /// How the content is turned to be upright on the panel.
+    transform: wl_output::Transform,
     scale: f32,
     output_scale: i32,
     location: Point<i32, Logical>,
+    /// What changed since the output was last rendered, relative to the output.
+    damage: Cell<Option<Rectangle<i32, Logical>>>,
     userdata: UserDataMap,
 }
 
@@ -67,8 +74,10 @@ impl Output {
             surfaces: Vec::new(),
             layer_surfaces: Default::default(),
             current_mode: mode,
+            transform: wl_output::Transform::Normal,
             scale,
             output_scale,
+            damage: Cell::new(None),
             userdata: Default::default(),
         }
     }
@@ -85,13 +94,18 @@ impl Output {
     }
 
     pub fn size(&self) -> Size<i32, Logical> {
-        self.current_mode
-            .size
+        self.physical_size()
             .to_f64()
             .to_logical(self.scale as f64)
             .to_i32_round()
     }
 
+    /// The size of the upright content in pixels, which is the size of the mode turned by the
+    /// transform.
+    pub fn physical_size(&self) -> Size<i32, Physical> {
+        transform_size(self.current_mode.size, self.transform)
+    }
+
     pub fn location(&self) -> Point<i32, Logical> {
         self.location
     }
@@ -108,6 +122,71 @@ impl Output {
         self.current_mode
     }
 
+    pub fn transform(&self) -> wl_output::Transform {
+        self.transform
+    }
+
+    /// Maps a position on the panel, as given by `position` for a panel of the size passed
+    /// to it, to global coordinates.
+    pub fn panel_position<F>(&self, position: F) -> Point<f64, Logical>
+    where
+        F: FnOnce(Size<i32, Logical>) -> Point<f64, Logical>,
+    {
+        use wl_output::Transform;
+
+        let panel = transform_size(self.size(), self.transform);
+        let (x, y) = position(panel).into();
+        let (pw, ph) = panel.to_f64().into();
+        let upright = match self.transform {
+            Transform::_90 => (ph - y, x),
+            Transform::_180 => (pw - x, ph - y),
+            Transform::_270 => (y, pw - x),
+            Transform::Flipped => (pw - x, y),
+            Transform::Flipped90 => (y, x),
+            Transform::Flipped180 => (x, ph - y),
+            Transform::Flipped270 => (ph - y, pw - x),
+            _ => (x, y),
+        };
+        Point::<f64, Logical>::from(upright) + self.location.to_f64()
+    }
+
+    /// Advertises another mode the output can be switched to.
+    pub fn add_mode(&self, mode: Mode) {
+        self.output.add_mode(mode);
+    }
+
+    /// Damages the part of the output covered by `rect`, in global coordinates.
+    pub fn damage(&self, rect: Rectangle<i32, Logical>) {
+        if let Some(rect) = damage::clip(rect, self.geometry()) {
+            let rect = Rectangle::from_loc_and_size(rect.loc - self.location, rect.size);
+            let damage = self.damage.get().map_or(rect, |damage| damage.merge(rect));
+            self.damage.set(Some(damage));
+        }
+    }
+
+    pub fn damage_all(&self) {
+        self.damage
+            .set(Some(Rectangle::from_loc_and_size((0, 0), self.size())));
+    }
+
+    /// Returns what changed since the last call, relative to the output.
+    pub fn take_damage(&self) -> Option<Rectangle<i32, Logical>> {
+        self.damage.take()
+    }
+
+    /// Returns `true` if something changed since the damage was last taken.
+    pub fn is_damaged(&self) -> bool {
+        self.damage.get().is_some()
+    }
+
+    /// Calls `f` with the `wl_output` objects of this output bound by `client`.
+    pub fn with_client_outputs<F>(&self, client: Client, f: F)
+    where
+        F: FnMut(&wl_output::WlOutput),
+    {
+        self.output.with_client_outputs(client, f)
+    }
+
     /// Add a layer surface to this output
     pub fn add_layer_surface(&self, layer: wl_surface::WlSurface) {
         self.layer_surfaces.borrow_mut().push(layer);
@@ -119,6 +198,17 @@ impl Output {
     }
 }
 
+/// Returns the size of a buffer of `size` once turned by `transform`.
+pub fn transform_size<Kind>(size: Size<i32, Kind>, transform: wl_output::Transform) -> Size<i32, Kind> {
+    match transform {
+        wl_output::Transform::_90
+        | wl_output::Transform::_270
+        | wl_output::Transform::Flipped90
+        | wl_output::Transform::Flipped270 => (size.h, size.w).into(),
+        _ => size,
+    }
+}
+
 impl Drop for Output {
     fn drop(&mut self) {
         self.global.take().unwrap().destroy();
@@ -240,6 +330,7 @@ impl OutputMap {
         for output in self.outputs.iter() {
             window_map.layers.arange_layers(output);
         }
+        window_map.fullscreen_area = self.with_primary().map(|o| o.geometry()).unwrap_or_default();
     }
 
     pub fn add<N>(&mut self, name: N, physical: PhysicalProperties, mode: Mode) -> &Output
@@ -300,6 +391,23 @@ impl OutputMap {
         self.outputs.is_empty()
     }
 
+    /// Damages the outputs covered by `rect`, in global coordinates.
+    pub fn damage(&self, rect: Rectangle<i32, Logical>) {
+        for output in &self.outputs {
+            output.damage(rect);
+        }
+    }
+
+    pub fn damage_all(&self) {
+        for output in &self.outputs {
+            output.damage_all();
+        }
+    }
+
+    pub fn is_damaged(&self) -> bool {
+        self.outputs.iter().any(Output::is_damaged)
+    }
+
     pub fn with_primary(&self) -> Option<&Output> {
         self.outputs.get(0)
     }
@@ -339,6 +447,10 @@ impl OutputMap {
         self.outputs.get(index)
     }
 
+    pub fn iter(&self) -> impl Iterator<Item = &Output> {
+        self.outputs.iter()
+    }
+
     pub fn update<F>(&mut self, mode: Option<Mode>, scale: Option<f32>, mut f: F)
     where
         F: FnMut(&Output) -> bool,
@@ -417,6 +529,28 @@ impl OutputMap {
         self.update_by_name(Some(mode), None, name)
     }
 
+    /// Turns the content of an output by `transform` to make it upright on its panel.
+    pub fn update_transform_by_name<N: AsRef<str>>(&mut self, transform: wl_output::Transform, name: N) {
+        if let Some(output) = self.outputs.iter_mut().find(|o| o.name() == name.as_ref()) {
+            output
+                .output
+                .change_current_state(None, Some(transform), None, None);
+            output.transform = transform;
+            output.damage_all();
+        }
+        self.arrange();
+    }
+
+    /// Switches an output to another of its modes, which stay advertised.
+    pub fn switch_mode_by_name<N: AsRef<str>>(&mut self, mode: Mode, name: N) {
+        if let Some(output) = self.outputs.iter_mut().find(|o| o.name() == name.as_ref()) {
+            output.output.change_current_state(Some(mode), None, None, None);
+            output.current_mode = mode;
+            output.damage_all();
+        }
+        self.arrange();
+    }
+
     pub fn refresh(&mut self) {
         // Clean-up dead surfaces
         self.outputs.iter_mut().for_each(|o| {
@@ -0,0 +1,338 @@
+use std::{collections::HashSet, process::Command, time::Duration};
+
+use smithay::{
+    backend::input::KeyState,
+    reexports::calloop::{
+        generic::Generic,
+        timer::{Timer, TimerHandle},
+        Interest, LoopHandle, Mode, PostAction,
+    },
+    wayland::{seat::FilterResult, SERIAL_COUNTER as SCOUNTER},
+};
+
+use crate::{bindings::Action, config::CriticalAction, gui::osd::Toast, state::AnvilState};
+
+/// How often the batteries are read, since not every driver reports charge changes.
+const POLL_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How long the save hotkey is held down.
+const SAVE_PRESS: Duration = Duration::from_millis(200);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ChargeState {
+    Charging,
+    Discharging,
+    Full,
+    NotCharging,
+    Unknown,
+}
+
+impl ChargeState {
+    fn parse(status: &str) -> ChargeState {
+        match status {
+            "Charging" => ChargeState::Charging,
+            "Discharging" => ChargeState::Discharging,
+            "Full" => ChargeState::Full,
+            "Not charging" => ChargeState::NotCharging,
+            _ => ChargeState::Unknown,
+        }
+    }
+}
+
+/// A battery from `/sys/class/power_supply`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Battery {
+    /// The sysfs name, stable while the battery is present.
+    pub id: String,
+    pub name: String,
+    /// Batteries of the device itself have the `System` scope, controllers have `Device`.
+    pub is_controller: bool,
+    /// Charge in percent.
+    pub capacity: u8,
+    pub state: ChargeState,
+}
+
+impl Battery {
+    fn from_device(device: &udev::Device) -> Option<Battery> {
+        let property = |name: &str| {
+            device
+                .property_value(name)
+                .map(|value| value.to_string_lossy().into_owned())
+        };
+        if property("POWER_SUPPLY_TYPE")? != "Battery" {
+            return None;
+        }
+        if property("POWER_SUPPLY_PRESENT")
+            .map(|present| present == "0")
+            .unwrap_or(false)
+        {
+            return None;
+        }
+        // Some controllers only report a coarse level
+        let capacity = property("POWER_SUPPLY_CAPACITY")
+            .and_then(|capacity| capacity.parse::<u8>().ok())
+            .or_else(|| match property("POWER_SUPPLY_CAPACITY_LEVEL")?.as_str() {
+                "Critical" => Some(5),
+                "Low" => Some(15),
+                "Normal" => Some(50),
+                "High" => Some(80),
+                "Full" => Some(100),
+                _ => None,
+            })?;
+        let id = device.sysname().to_string_lossy().into_owned();
+        Some(Battery {
+            name: property("POWER_SUPPLY_MODEL_NAME").unwrap_or_else(|| id.clone()),
+            id,
+            is_controller: property("POWER_SUPPLY_SCOPE")
+                .map(|scope| scope == "Device")
+                .unwrap_or(false),
+            capacity: capacity.min(100),
+            state: property("POWER_SUPPLY_STATUS")
+                .map(|status| ChargeState::parse(&status))
+                .unwrap_or(ChargeState::Unknown),
+        })
+    }
+
+    fn is_discharging(&self) -> bool {
+        matches!(self.state, ChargeState::Discharging | ChargeState::Unknown)
+    }
+
+    /// A short description for the menu header, like `75%` or `75% (charging)`.
+    pub fn status_text(&self) -> String {
+        match self.state {
+            ChargeState::Charging => format!("{}% (charging)", self.capacity),
+            _ => format!("{}%", self.capacity),
+        }
+    }
+}
+
+fn scan_batteries() -> std::io::Result<Vec<Battery>> {
+    let mut enumerator = udev::Enumerator::new()?;
+    enumerator.match_subsystem("power_supply")?;
+    let mut batteries = enumerator
+        .scan_devices()?
+        .filter_map(|device| Battery::from_device(&device))
+        .collect::<Vec<_>>();
+    batteries.sort_by(|a, b| {
+        a.is_controller
+            .cmp(&b.is_controller)
+            .then_with(|| a.id.cmp(&b.id))
+    });
+    Ok(batteries)
+}
+
+#[derive(Debug, Clone, Copy)]
+enum PowerTimeout {
+    Poll,
+    /// Release the save hotkey pressed when the battery became critical.
+    ReleaseSave,
+    /// The game had time to save, run the critical action.
+    Critical,
+}
+
+/// Keeps track of the device and controller batteries.
+#[derive(Debug)]
+pub struct PowerMonitor {
+    batteries: Vec<Battery>,
+    /// Batteries a low charge warning was shown for, until they charge again.
+    warned: HashSet<String>,
+    /// Whether the critical action is underway, until the device charges again.
+    critical: bool,
+    timer: TimerHandle<PowerTimeout>,
+}
+
+impl PowerMonitor {
+    /// Starts reading the batteries from the event loop, and whenever udev reports a change.
+    pub fn new<BackendData: 'static>(
+        handle: &LoopHandle<'static, AnvilState<BackendData>>,
+        log: &slog::Logger,
+    ) -> PowerMonitor {
+        let timer = Timer::new().expect("Failed to create the power timer");
+        let timer_handle = timer.handle();
+        handle
+            .insert_source(timer, |timeout, timer_handle, state| match timeout {
+                PowerTimeout::Poll => {
+                    state.refresh_power();
+                    timer_handle.add_timeout(POLL_INTERVAL, PowerTimeout::Poll);
+                }
+                PowerTimeout::ReleaseSave => state.press_save_hotkey(false),
+                PowerTimeout::Critical => state.run_critical_action(),
+            })
+            .expect("Failed to init the power timer");
+        timer_handle.add_timeout(POLL_INTERVAL, PowerTimeout::Poll);
+        handle.insert_idle(|state| state.refresh_power());
+
+        let monitor = udev::MonitorBuilder::new()
+            .and_then(|builder| builder.match_subsystem("power_supply"))
+            .and_then(|builder| builder.listen());
+        match monitor {
+            Ok(monitor) => {
+                handle
+                    .insert_source(
+                        Generic::new(monitor, Interest::READ, Mode::Level),
+                        |_, monitor, state: &mut AnvilState<BackendData>| {
+                            if monitor.iter().count() > 0 {
+                                state.refresh_power();
+                            }
+                            Ok(PostAction::Continue)
+                        },
+                    )
+                    .expect("Failed to init the power supply monitor");
+            }
+            Err(err) => warn!(log, "Unable to watch power supplies: {}", err),
+        }
+
+        PowerMonitor {
+            batteries: Vec::new(),
+            warned: HashSet::new(),
+            critical: false,
+            timer: timer_handle,
+        }
+    }
+
+    /// The batteries, the device ones first.
+    pub fn batteries(&self) -> &[Battery] {
+        &self.batteries
+    }
+
+    /// The battery of the device itself, if it has one.
+    pub fn device_battery(&self) -> Option<&Battery> {
+        self.batteries.iter().find(|battery| !battery.is_controller)
+    }
+}
+
+impl<BackendData: 'static> AnvilState<BackendData> {
+    /// Reads the batteries again, and warns about the ones running low.
+    pub fn refresh_power(&mut self) {
+        let batteries = match scan_batteries() {
+            Ok(batteries) => batteries,
+            Err(err) => {
+                warn!(self.log, "Unable to read the batteries: {}", err);
+                return;
+            }
+        };
+
+        let config = &self.config.power;
+        let mut toasts = Vec::new();
+        for battery in &batteries {
+            let low = battery.is_discharging() && battery.capacity <= config.low_battery;
+            if !low {
+                self.power.warned.remove(&battery.id);
+            } else if self.power.warned.insert(battery.id.clone()) {
+                info!(self.log, "Battery low"; "name" => &battery.name, "capacity" => battery.capacity);
+                let text = if battery.is_controller {
+                    format!("{} battery low ({}%)", battery.name, battery.capacity)
+                } else {
+                    format!("Battery low ({}%)", battery.capacity)
+                };
+                toasts.push(Toast::text(text).warning().tag(battery.id.clone()));
+            }
+        }
+        let critical = batteries
+            .iter()
+            .find(|battery| !battery.is_controller)
+            .map(|battery| battery.is_discharging() && battery.capacity <= config.critical_battery)
+            .unwrap_or(false);
+        self.power
+            .warned
+            .retain(|id| batteries.iter().any(|battery| &battery.id == id));
+        self.power.batteries = batteries;
+        // The menu shows the battery levels
+        self.schedule_render();
+
+        for toast in toasts {
+            self.osd.post(toast);
+        }
+        if !critical {
+            self.power.critical = false;
+        } else if !self.power.critical {
+            self.power.critical = true;
+            self.on_critical_battery();
+        }
+    }
+
+    /// Makes the game shown save, and runs the critical action once it had time to.
+    fn on_critical_battery(&mut self) {
+        let action = self.config.power.critical_action;
+        warn!(self.log, "Battery critical"; "action" => format!("{:?}", action));
+        if action == CriticalAction::None {
+            self.osd.post(Toast::text("Battery critical").warning());
+            return;
+        }
+        let text = if action == CriticalAction::Suspend {
+            "Battery critical, saving and suspending"
+        } else {
+            "Battery critical, saving and shutting down"
+        };
+        self.osd.post(
+            Toast::text(text)
+                .warning()
+                .timeout(Duration::from_millis(self.config.power.save_delay_ms)),
+        );
+
+        // The game must be shown to get the inputs, so the menu is hidden if it is open
+        let top_game = self
+            .window_map
+            .borrow()
+            .topmost_client_pid()
+            .and_then(|client_pid| self.games.find_by_client_pid(client_pid))
+            .map(|game| game.pid());
+        if let Some(pid) = top_game {
+            self.resume_game(pid);
+            self.press_save_hotkey(true);
+            self.power
+                .timer
+                .add_timeout(SAVE_PRESS, PowerTimeout::ReleaseSave);
+        }
+        self.power.timer.add_timeout(
+            Duration::from_millis(self.config.power.save_delay_ms),
+            PowerTimeout::Critical,
+        );
+    }
+
+    /// Presses or releases the configured save keys and buttons.
+    fn press_save_hotkey(&mut self, pressed: bool) {
+        let time = self.start_time.elapsed().as_millis() as u32;
+        let (state, keys) = if pressed {
+            (KeyState::Pressed, self.config.power.save_keys.clone())
+        } else {
+            (
+                KeyState::Released,
+                self.config.power.save_keys.iter().rev().copied().collect(),
+            )
+        };
+        for keycode in keys {
+            self.keyboard
+                .input::<(), _>(keycode, state, SCOUNTER.next_serial(), time, |_, _| {
+                    FilterResult::Forward
+                });
+        }
+
+        let buttons = self.config.power.save_buttons.clone();
+        if let Some(gamepad) = self
+            .gamepads
+            .target()
+            .and_then(|pid| self.games.get_mut(pid))
+            .and_then(|game| game.gamepad_mut())
+        {
+            gamepad.set_buttons(&buttons, pressed);
+        }
+    }
+
+    fn run_critical_action(&mut self) {
+        // The device was plugged in meanwhile
+        if !self.power.critical {
+            return;
+        }
+        match self.config.power.critical_action {
+            CriticalAction::None => {}
+            CriticalAction::Suspend => self.run_action(Action::Suspend),
+            CriticalAction::Shutdown => {
+                info!(self.log, "Shutting down");
+                if let Err(err) = Command::new("systemctl").arg("poweroff").spawn() {
+                    error!(self.log, "Unable to shut down: {}", err);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,175 @@
+use std::{mem, ops::Deref, time::Duration};
+
+use smithay::{
+    reexports::{
+        nix::libc,
+        wayland_protocols::presentation_time::server::{
+            wp_presentation::{self, WpPresentation},
+            wp_presentation_feedback::{Kind, WpPresentationFeedback},
+        },
+        wayland_server::{protocol::wl_surface::WlSurface, Display, Filter, Main},
+    },
+    utils::{Logical, Rectangle},
+    wayland::compositor::{with_states, with_surface_tree_downward, Cacheable, TraversalAction},
+};
+
+use crate::{output_map::Output, state::AnvilState, window_map::WindowMap};
+
+/// The feedbacks requested for the content of a surface, double-buffered like the rest of
+/// its state.
+#[derive(Debug, Default)]
+pub struct PresentationFeedbackState {
+    feedbacks: Vec<WpPresentationFeedback>,
+}
+
+impl Cacheable for PresentationFeedbackState {
+    fn commit(&mut self) -> Self {
+        PresentationFeedbackState {
+            feedbacks: mem::take(&mut self.feedbacks),
+        }
+    }
+
+    fn merge_into(self, into: &mut Self) {
+        // The content they were requested for is replaced before being drawn
+        for feedback in into.feedbacks.drain(..) {
+            feedback.discarded();
+        }
+        into.feedbacks = self.feedbacks;
+    }
+}
+
+/// Creates the `wp_presentation` global, timed with `CLOCK_MONOTONIC`.
+pub fn init_presentation_global(display: &mut Display) {
+    display.create_global::<WpPresentation, _>(
+        1,
+        Filter::new(|(presentation, _): (Main<WpPresentation>, u32), _, _| {
+            presentation.quick_assign(|_, request, _| {
+                if let wp_presentation::Request::Feedback { surface, callback } = request {
+                    callback.quick_assign(|_, _, _| {});
+                    let feedback = callback.deref().clone();
+                    let added = with_states(&surface, |states| {
+                        states
+                            .cached_state
+                            .pending::<PresentationFeedbackState>()
+                            .feedbacks
+                            .push(feedback.clone());
+                    });
+                    if added.is_err() {
+                        feedback.discarded();
+                    }
+                }
+            });
+            presentation.clock_id(libc::CLOCK_MONOTONIC as u32);
+        }),
+    );
+}
+
+/// Returns the current time of `CLOCK_MONOTONIC`.
+pub fn monotonic_time() -> Duration {
+    let mut time = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    unsafe {
+        libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut time);
+    }
+    Duration::new(time.tv_sec as u64, time.tv_nsec as u32)
+}
+
+/// The feedbacks of the content drawn in a frame of an output, until the frame is shown.
+///
+/// Feedbacks of a frame dropped before being shown are discarded.
+#[derive(Debug, Default)]
+pub struct FrameFeedbacks {
+    feedbacks: Vec<(WlSurface, WpPresentationFeedback)>,
+    /// Whether a client buffer was shown directly, rather than composited.
+    zero_copy: bool,
+}
+
+impl FrameFeedbacks {
+    /// Takes the feedbacks of the surfaces just drawn on the output at `output_geometry`.
+    pub fn collect(
+        window_map: &WindowMap,
+        output_geometry: Rectangle<i32, Logical>,
+        zero_copy: bool,
+    ) -> Self {
+        let mut feedbacks = Vec::new();
+        window_map.with_drawn_surfaces(output_geometry, |root, _| {
+            with_surface_tree_downward(
+                root,
+                (),
+                |_, _, _| TraversalAction::DoChildren(()),
+                |surface, states, _| {
+                    let mut state = states.cached_state.current::<PresentationFeedbackState>();
+                    feedbacks.extend(
+                        state
+                            .feedbacks
+                            .drain(..)
+                            .map(|feedback| (surface.clone(), feedback)),
+                    );
+                },
+                |_, _, _| true,
+            );
+        });
+        FrameFeedbacks { feedbacks, zero_copy }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.feedbacks.is_empty()
+    }
+
+    /// Tells the clients that the frame was shown on `output` at `time`, the `seq`th refresh
+    /// of the output, given its `refresh` period if known.
+    pub fn presented(
+        mut self,
+        output: &Output,
+        time: Duration,
+        refresh: Duration,
+        seq: u64,
+        mut flags: Kind,
+    ) {
+        if self.zero_copy {
+            flags |= Kind::ZeroCopy;
+        }
+        let secs = time.as_secs();
+        for (surface, feedback) in self.feedbacks.drain(..) {
+            if let Some(client) = surface.as_ref().client() {
+                output.with_client_outputs(client, |wl_output| feedback.sync_output(wl_output));
+            }
+            feedback.presented(
+                (secs >> 32) as u32,
+                secs as u32,
+                time.subsec_nanos(),
+                refresh.as_nanos() as u32,
+                (seq >> 32) as u32,
+                seq as u32,
+                flags,
+            );
+        }
+    }
+}
+
+impl Drop for FrameFeedbacks {
+    fn drop(&mut self) {
+        for (_, feedback) in self.feedbacks.drain(..) {
+            feedback.discarded();
+        }
+    }
+}
+
+impl<BackendData: 'static> AnvilState<BackendData> {
+    /// Tells the clients drawn in the window of a nested backend that they were just shown,
+    /// which is as much as is known about it.
+    pub fn nested_frame_presented(&self, output_name: &str) {
+        let output_map = self.output_map.borrow();
+        if let Some(output) = output_map.find_by_name(output_name) {
+            FrameFeedbacks::collect(&*self.window_map.borrow(), output.geometry(), false).presented(
+                output,
+                monotonic_time(),
+                Duration::ZERO,
+                0,
+                Kind::empty(),
+            );
+        }
+    }
+}
@@ -0,0 +1,19 @@
+// Server side of the protocols generated by the build script, wrapped the same way as in
+// `wayland-protocols`.
+#![allow(dead_code, non_camel_case_types, unused_unsafe, unused_variables)]
+#![allow(non_upper_case_globals, non_snake_case, unused_imports)]
+#![allow(missing_docs, clippy::all)]
+
+pub mod ext_idle_notify_v1 {
+    pub(crate) use smithay::reexports::wayland_server::{
+        protocol::wl_seat, sys, AnonymousObject, Main, Resource, ResourceMap,
+    };
+    pub(crate) use wayland_commons::{
+        map::{Object, ObjectMetadata},
+        smallvec,
+        wire::{Argument, ArgumentType, Message, MessageDesc},
+        Interface, MessageGroup,
+    };
+
+    include!(concat!(env!("OUT_DIR"), "/ext_idle_notify_v1.rs"));
+}
@@ -0,0 +1,327 @@
+use std::{
+    collections::VecDeque,
+    io::Write,
+    path::{Path, PathBuf},
+    process::{Child, Command, Stdio},
+    sync::{
+        mpsc::{self, Receiver, SyncSender, TrySendError},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use image::{codecs::jpeg::JpegEncoder, ColorType, DynamicImage, RgbaImage};
+use smithay::reexports::calloop::{
+    timer::{Timer, TimerHandle},
+    LoopHandle,
+};
+
+use crate::{
+    config::{self, RecordingConfig},
+    gui::osd::Toast,
+    screenshot::unique_path,
+    state::AnvilState,
+};
+
+/// Frames waiting to be encoded, newer frames are dropped past this.
+const QUEUE_LENGTH: usize = 4;
+
+enum RecorderCommand {
+    Frame(RgbaImage),
+    Start(PathBuf, String),
+    Stop,
+    SaveReplay(PathBuf, String),
+}
+
+#[derive(Debug)]
+enum RecordingEvent {
+    Saved(PathBuf),
+    ReplaySaved(PathBuf),
+    Failed(String),
+    ReplayFailed(String),
+}
+
+/// Records the screen to video files through `ffmpeg`, and keeps the last seconds for
+/// instant replays.
+///
+/// Frames are encoded as JPEG by a separate thread, and muxed by `ffmpeg` without
+/// encoding them again by default.
+#[derive(Debug)]
+pub struct Recorder {
+    directory: PathBuf,
+    interval: Duration,
+    replay: bool,
+    recording: bool,
+    /// When the next frame is due.
+    next_frame: Instant,
+    commands: SyncSender<RecorderCommand>,
+}
+
+impl Recorder {
+    pub fn new<BackendData: 'static>(
+        config: &RecordingConfig,
+        handle: &LoopHandle<'static, AnvilState<BackendData>>,
+    ) -> Recorder {
+        let timer = Timer::new().expect("Failed to create the recording timer");
+        let timer_handle = timer.handle();
+        handle
+            .insert_source(timer, |event, _, state| match event {
+                RecordingEvent::Saved(path) => {
+                    info!(state.log, "Recording saved"; "path" => path.display().to_string());
+                    state.osd.post(Toast::text("Recording saved"));
+                }
+                RecordingEvent::ReplaySaved(path) => {
+                    info!(state.log, "Replay saved"; "path" => path.display().to_string());
+                    state.osd.post(Toast::text("Replay saved"));
+                }
+                RecordingEvent::Failed(err) => {
+                    warn!(state.log, "Recording failed: {}", err);
+                    state.recorder.recording = false;
+                    state.osd.set_recording(false);
+                    state.osd.post(Toast::text("Recording failed").warning());
+                }
+                RecordingEvent::ReplayFailed(err) => {
+                    warn!(state.log, "Unable to save the replay: {}", err);
+                    state.osd.post(Toast::text("Unable to save the replay").warning());
+                }
+            })
+            .expect("Failed to init the recording timer");
+
+        let fps = config.fps.max(1);
+        let (commands, receiver) = mpsc::sync_channel(QUEUE_LENGTH);
+        let encoder = Encoder {
+            fps,
+            quality: config.quality.clamp(1, 100),
+            ffmpeg_args: config.ffmpeg_args.clone(),
+            replay: VecDeque::new(),
+            replay_frames: (config.replay_secs * fps) as usize,
+            recording: None,
+            timer: timer_handle,
+        };
+        std::thread::Builder::new()
+            .name("recording".into())
+            .spawn(move || encoder.run(receiver))
+            .expect("Failed to start the recording thread");
+
+        Recorder {
+            directory: config::expand_home(config.directory.clone()),
+            interval: Duration::from_secs(1) / fps,
+            replay: config.replay_secs > 0,
+            recording: false,
+            next_frame: Instant::now(),
+            commands,
+        }
+    }
+
+    /// Returns `true` while frames are taken, for a recording or the instant replay.
+    pub fn is_capturing(&self) -> bool {
+        self.recording || self.replay
+    }
+
+    /// Returns `true` if the frame about to be rendered is due, at the recording frame rate.
+    pub fn wants_frame(&mut self) -> bool {
+        if !self.recording && !self.replay {
+            return false;
+        }
+        let now = Instant::now();
+        // Frames come at the refresh rate, which jitters a bit
+        if now + self.interval / 4 < self.next_frame {
+            return false;
+        }
+        self.next_frame = if now > self.next_frame + self.interval {
+            now + self.interval
+        } else {
+            self.next_frame + self.interval
+        };
+        true
+    }
+
+    pub fn add_frame(&mut self, image: RgbaImage) {
+        if let Err(TrySendError::Full(_)) = self.commands.try_send(RecorderCommand::Frame(image)) {
+            slog_scope::debug!("Dropped a frame, the recording thread is busy");
+        }
+    }
+
+    fn send(&self, command: RecorderCommand) -> Result<(), &'static str> {
+        self.commands.try_send(command).map_err(|err| match err {
+            TrySendError::Full(_) => "The recording thread is busy",
+            TrySendError::Disconnected(_) => "The recording thread stopped",
+        })
+    }
+}
+
+/// The recording thread.
+struct Encoder {
+    fps: u32,
+    quality: u8,
+    ffmpeg_args: Vec<String>,
+    /// The last frames, encoded.
+    replay: VecDeque<Arc<Vec<u8>>>,
+    replay_frames: usize,
+    recording: Option<(Child, PathBuf)>,
+    timer: TimerHandle<RecordingEvent>,
+}
+
+impl Encoder {
+    fn run(mut self, commands: Receiver<RecorderCommand>) {
+        for command in commands {
+            match command {
+                RecorderCommand::Frame(image) => self.add_frame(image),
+                RecorderCommand::Start(directory, name) => {
+                    self.stop();
+                    match spawn_ffmpeg(self.fps, &self.ffmpeg_args, &directory, &name) {
+                        Ok(recording) => self.recording = Some(recording),
+                        Err(err) => self.fail(err),
+                    }
+                }
+                RecorderCommand::Stop => self.stop(),
+                RecorderCommand::SaveReplay(directory, name) => {
+                    let frames = self.replay.iter().cloned().collect::<Vec<_>>();
+                    let (fps, args, timer) = (self.fps, self.ffmpeg_args.clone(), self.timer.clone());
+                    // Recording goes on meanwhile
+                    std::thread::spawn(move || {
+                        let event = match save_replay(fps, &args, &directory, &name, &frames) {
+                            Ok(path) => RecordingEvent::ReplaySaved(path),
+                            Err(err) => RecordingEvent::ReplayFailed(err),
+                        };
+                        timer.add_timeout(Duration::ZERO, event);
+                    });
+                }
+            }
+        }
+        self.stop();
+    }
+
+    fn add_frame(&mut self, image: RgbaImage) {
+        let (width, height) = image.dimensions();
+        let rgb = DynamicImage::ImageRgba8(image).to_rgb8();
+        let mut jpeg = Vec::new();
+        if let Err(err) = JpegEncoder::new_with_quality(&mut jpeg, self.quality).encode(
+            &rgb,
+            width,
+            height,
+            ColorType::Rgb8,
+        ) {
+            slog_scope::warn!("Unable to encode a frame: {}", err);
+            return;
+        }
+
+        if let Some((child, _)) = &mut self.recording {
+            let written = child.stdin.as_mut().map(|stdin| stdin.write_all(&jpeg));
+            if let Some(Err(err)) = written {
+                self.fail(format!("Unable to write to ffmpeg: {}", err));
+            }
+        }
+        if self.replay_frames > 0 {
+            if self.replay.len() >= self.replay_frames {
+                self.replay.pop_front();
+            }
+            self.replay.push_back(Arc::new(jpeg));
+        }
+    }
+
+    fn stop(&mut self) {
+        if let Some((child, path)) = self.recording.take() {
+            let event = match finish_ffmpeg(child) {
+                Ok(()) => RecordingEvent::Saved(path),
+                Err(err) => RecordingEvent::Failed(err),
+            };
+            self.timer.add_timeout(Duration::ZERO, event);
+        }
+    }
+
+    fn fail(&mut self, err: String) {
+        if let Some((mut child, _)) = self.recording.take() {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+        self.timer
+            .add_timeout(Duration::ZERO, RecordingEvent::Failed(err));
+    }
+}
+
+/// Starts muxing JPEG frames to a new file.
+fn spawn_ffmpeg(fps: u32, args: &[String], directory: &Path, name: &str) -> Result<(Child, PathBuf), String> {
+    std::fs::create_dir_all(directory).map_err(|err| err.to_string())?;
+    let path = unique_path(directory, name, "mkv");
+    let child = Command::new("ffmpeg")
+        .args(["-loglevel", "error", "-f", "mjpeg", "-framerate"])
+        .arg(fps.to_string())
+        .args(["-i", "-"])
+        .args(args)
+        .arg(&path)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .spawn()
+        .map_err(|err| format!("Unable to run ffmpeg: {}", err))?;
+    Ok((child, path))
+}
+
+fn finish_ffmpeg(mut child: Child) -> Result<(), String> {
+    // ffmpeg finishes the file at the end of its input
+    drop(child.stdin.take());
+    match child.wait() {
+        Ok(status) if status.success() => Ok(()),
+        Ok(status) => Err(format!("ffmpeg exited with {}", status)),
+        Err(err) => Err(err.to_string()),
+    }
+}
+
+fn save_replay(
+    fps: u32,
+    args: &[String],
+    directory: &Path,
+    name: &str,
+    frames: &[Arc<Vec<u8>>],
+) -> Result<PathBuf, String> {
+    if frames.is_empty() {
+        return Err("No frames to save".into());
+    }
+    let (mut child, path) = spawn_ffmpeg(fps, args, directory, name)?;
+    let stdin = child.stdin.as_mut().unwrap();
+    for frame in frames {
+        if let Err(err) = stdin.write_all(frame) {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(format!("Unable to write to ffmpeg: {}", err));
+        }
+    }
+    finish_ffmpeg(child)?;
+    Ok(path)
+}
+
+impl<BackendData: 'static> AnvilState<BackendData> {
+    /// Starts recording the screen to a file named after the game shown, or stops it.
+    pub fn toggle_recording(&mut self) {
+        let recording = !self.recorder.recording;
+        let command = if recording {
+            RecorderCommand::Start(self.recorder.directory.clone(), self.capture_name())
+        } else {
+            RecorderCommand::Stop
+        };
+        match self.recorder.send(command) {
+            Ok(()) => {
+                info!(self.log, "Recording: {}", recording);
+                self.recorder.recording = recording;
+                self.osd.set_recording(recording);
+            }
+            Err(err) => {
+                warn!(self.log, "Unable to toggle the recording: {}", err);
+                self.osd.post(Toast::text(err).warning());
+            }
+        }
+    }
+
+    /// Saves the last seconds shown to a file.
+    pub fn save_replay(&mut self) {
+        if !self.recorder.replay {
+            self.osd.post(Toast::text("Instant replay is off").warning());
+            return;
+        }
+        let command = RecorderCommand::SaveReplay(self.recorder.directory.clone(), self.capture_name());
+        if let Err(err) = self.recorder.send(command) {
+            warn!(self.log, "Unable to save the replay: {}", err);
+            self.osd.post(Toast::text(err).warning());
+        }
+    }
+}
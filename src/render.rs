@@ -2,16 +2,20 @@ use slog::Logger;
 use smithay::{
     backend::{
         renderer::{
-            gles2::{Gles2Frame, Gles2Renderer},
+            gles2::{ffi, Gles2Error, Gles2Frame, Gles2Renderer},
             Frame,
         },
         SwapBuffersError,
     },
-    utils::{Logical, Rectangle},
+    utils::{Logical, Physical, Rectangle},
     wayland::shell::wlr_layer::Layer,
 };
 
-use crate::{drawing::{draw_layers, draw_top_window, draw_windows}, window_map::WindowMap};
+use crate::{
+    drawing::{draw_layers, draw_top_window},
+    gui::{widget::Widget, OverlayRenderer},
+    window_map::WindowMap,
+};
 
 pub fn render_layers_and_windows(
     renderer: &mut Gles2Renderer,
@@ -19,6 +23,8 @@ pub fn render_layers_and_windows(
     window_map: &WindowMap,
     output_geometry: Rectangle<i32, Logical>,
     output_scale: f32,
+    overlay: &mut OverlayRenderer,
+    widgets: &[Widget],
     logger: &Logger,
 ) -> Result<(), SwapBuffersError> {
 
@@ -50,5 +56,27 @@ pub fn render_layers_and_windows(
         )?;
     }
 
+    // The menu and everything else drawn by the compositor goes above all clients
+    overlay.draw(renderer, frame, widgets, output_scale)?;
+
     Ok(())
 }
+
+/// Restricts drawing to an area of the framebuffer bound, or lifts the restriction.
+///
+/// The area is in framebuffer coordinates, whose first row is the bottom of what is drawn
+/// unless the frame is flipped.
+pub fn scissor(
+    renderer: &mut Gles2Renderer,
+    area: Option<Rectangle<i32, Physical>>,
+) -> Result<(), Gles2Error> {
+    renderer.with_context(|_, gl| unsafe {
+        match area {
+            Some(area) => {
+                gl.Enable(ffi::SCISSOR_TEST);
+                gl.Scissor(area.loc.x, area.loc.y, area.size.w, area.size.h);
+            }
+            None => gl.Disable(ffi::SCISSOR_TEST),
+        }
+    })
+}
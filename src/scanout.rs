@@ -0,0 +1,452 @@
+use std::{cell::RefCell, os::unix::io::AsRawFd};
+
+use slog::Logger;
+use smithay::{
+    backend::allocator::{dmabuf::Dmabuf, Buffer, Fourcc, Modifier},
+    reexports::{
+        drm::{
+            self,
+            buffer::{Handle as BufferHandle, PlanarBuffer},
+            control::{crtc, framebuffer, Device as ControlDevice, PageFlipFlags},
+        },
+        nix::libc,
+        wayland_server::protocol::{wl_buffer::WlBuffer, wl_output, wl_surface::WlSurface},
+    },
+    utils::{Logical, Physical, Rectangle, Size},
+    wayland::{
+        compositor::{self, with_states, RectangleKind, RegionAttributes, SurfaceAttributes},
+        shell::wlr_layer::Layer,
+    },
+};
+
+use crate::{
+    gui::osd::Osd,
+    screenshot::CaptureRequest,
+    shell::SurfaceData,
+    window_map::{Placement, WindowMap},
+};
+
+/// `DRM_MODE_FB_MODIFIERS`, the framebuffer comes with explicit modifiers.
+const FB_MODIFIERS: u32 = 1 << 1;
+
+/// `DRM_IOCTL_GEM_CLOSE`, releases a GEM handle of the DRM file.
+const GEM_CLOSE: libc::c_ulong = 0x4008_6409;
+
+/// `struct drm_gem_close`
+#[repr(C)]
+struct GemClose {
+    handle: u32,
+    pad: u32,
+}
+
+/// Framebuffers kept for the client buffers, games cycle through a few of them.
+const CACHED_FRAMEBUFFERS: usize = 4;
+
+/// Formats without an alpha channel, always shown as opaque.
+const OPAQUE_FORMATS: &[Fourcc] = &[
+    Fourcc::Xrgb8888,
+    Fourcc::Xbgr8888,
+    Fourcc::Rgb565,
+    Fourcc::Bgr565,
+    Fourcc::Xrgb2101010,
+    Fourcc::Xbgr2101010,
+];
+
+/// The planes of a client dmabuf, imported on the DRM device.
+struct ImportedPlanes {
+    size: (u32, u32),
+    format: Fourcc,
+    pitches: [u32; 4],
+    handles: [Option<BufferHandle>; 4],
+    offsets: [u32; 4],
+}
+
+impl PlanarBuffer for ImportedPlanes {
+    fn size(&self) -> (u32, u32) {
+        self.size
+    }
+
+    fn format(&self) -> Fourcc {
+        self.format
+    }
+
+    fn pitches(&self) -> [u32; 4] {
+        self.pitches
+    }
+
+    fn handles(&self) -> [Option<BufferHandle>; 4] {
+        self.handles
+    }
+
+    fn offsets(&self) -> [u32; 4] {
+        self.offsets
+    }
+}
+
+fn close_handles<D: AsRawFd>(drm: &D, handles: &[BufferHandle]) {
+    for handle in handles {
+        let request = GemClose {
+            handle: u32::from(*handle),
+            pad: 0,
+        };
+        unsafe {
+            libc::ioctl(drm.as_raw_fd(), GEM_CLOSE as _, &request);
+        }
+    }
+}
+
+/// A client buffer added as a DRM framebuffer.
+struct ClientFramebuffer {
+    buffer: WlBuffer,
+    framebuffer: framebuffer::Handle,
+    /// The GEM handles of the planes, closed with the framebuffer.
+    handles: Vec<BufferHandle>,
+}
+
+impl ClientFramebuffer {
+    fn import<D: ControlDevice>(
+        drm: &D,
+        buffer: &WlBuffer,
+        dmabuf: &Dmabuf,
+    ) -> Result<ClientFramebuffer, drm::SystemError> {
+        let format = dmabuf.format();
+        let mut planes = ImportedPlanes {
+            size: (dmabuf.width(), dmabuf.height()),
+            format: format.code,
+            pitches: [0; 4],
+            handles: [None; 4],
+            offsets: [0; 4],
+        };
+        let mut handles = Vec::new();
+        let fds = dmabuf.handles().zip(dmabuf.offsets()).zip(dmabuf.strides());
+        for (i, ((fd, offset), stride)) in fds.enumerate().take(4) {
+            let handle = match drm.prime_fd_to_buffer(fd) {
+                Ok(handle) => handle,
+                Err(err) => {
+                    close_handles(drm, &handles);
+                    return Err(err);
+                }
+            };
+            // Planes of the same buffer share the handle
+            if !handles.contains(&handle) {
+                handles.push(handle);
+            }
+            planes.handles[i] = Some(handle);
+            planes.pitches[i] = stride;
+            planes.offsets[i] = offset;
+        }
+
+        let result = if format.modifier == Modifier::Invalid {
+            drm.add_planar_framebuffer(&planes, &[None; 4], 0)
+        } else {
+            let modifiers = planes.handles.map(|handle| handle.map(|_| format.modifier));
+            drm.add_planar_framebuffer(&planes, &modifiers, FB_MODIFIERS)
+        };
+        match result {
+            Ok(framebuffer) => Ok(ClientFramebuffer {
+                buffer: buffer.clone(),
+                framebuffer,
+                handles,
+            }),
+            Err(err) => {
+                close_handles(drm, &handles);
+                Err(err)
+            }
+        }
+    }
+
+    fn destroy<D: ControlDevice>(self, drm: &D) {
+        let _ = drm.destroy_framebuffer(self.framebuffer);
+        close_handles(drm, &self.handles);
+    }
+}
+
+/// Returns `true` if the region covers a surface of the given size.
+fn covers(region: Option<&RegionAttributes>, size: Size<i32, Physical>) -> bool {
+    let region = match region {
+        Some(region) => region,
+        None => return false,
+    };
+    let covering = |rect: &Rectangle<i32, Logical>| {
+        rect.loc.x <= 0
+            && rect.loc.y <= 0
+            && rect.loc.x + rect.size.w >= size.w
+            && rect.loc.y + rect.size.h >= size.h
+    };
+    region
+        .rects
+        .iter()
+        .all(|(kind, _)| matches!(kind, RectangleKind::Add))
+        && region.rects.iter().any(|(_, rect)| covering(rect))
+}
+
+/// Returns the surface of the game if it is the only thing shown on the output, or why it is not.
+pub fn scanout_candidate(
+    window_map: &WindowMap,
+    osd: &Osd,
+    output_geometry: Rectangle<i32, Logical>,
+    output_scale: f32,
+    cursor_drawn: bool,
+    capture: CaptureRequest,
+) -> Result<WlSurface, &'static str> {
+    if window_map.menu_on_top {
+        return Err("the menu is shown");
+    }
+    if osd.is_visible() {
+        return Err("the OSD is shown");
+    }
+    if cursor_drawn {
+        return Err("the cursor is shown");
+    }
+    // Captures are read back from the composited frame
+    if capture.without_cursor() || capture.with_cursor() {
+        return Err("the output is captured");
+    }
+    if output_scale != 1.0 {
+        return Err("the output is scaled");
+    }
+
+    let mut layers = false;
+    for layer in [Layer::Top, Layer::Overlay] {
+        window_map
+            .layers
+            .with_layers_from_bottom_to_top(&layer, |layer_surface| {
+                layers |= output_geometry.overlaps(layer_surface.bbox);
+            });
+    }
+    if layers {
+        return Err("layer surfaces are shown over the game");
+    }
+
+    let placement = window_map.top_placement().ok_or("no game is shown")?;
+    if placement != Placement::at(output_geometry.loc) {
+        return Err("the game is scaled");
+    }
+    let mut top = None;
+    window_map.with_top_window(|toplevel, _, _| top = toplevel.get_surface().cloned());
+    let surface = top.ok_or("no game is shown")?;
+    let mut popups = false;
+    window_map.with_child_popups(&surface, |_| popups = true);
+    if popups {
+        return Err("the game has popups");
+    }
+    Ok(surface)
+}
+
+/// Attaches the buffer of a fullscreen game directly to the primary plane of an output,
+/// when nothing else is shown over it, rather than compositing it.
+///
+/// The buffer is taken from its surface while it is on screen, since the surface releases
+/// its buffer to the client on the next commit.
+#[derive(Default)]
+pub struct Scanout {
+    framebuffers: Vec<ClientFramebuffer>,
+    /// The buffer on screen, and the surface it came from.
+    current: Option<(WlSurface, WlBuffer)>,
+    /// The buffer of the page flip in flight.
+    queued: Option<(WlSurface, WlBuffer)>,
+    /// Buffers released to their clients after the next page flip.
+    retired: Vec<WlBuffer>,
+    /// Buffers the primary plane did not take, which are composited from then on.
+    rejected: Vec<WlBuffer>,
+    direct: bool,
+    /// Why the output was last composited.
+    reason: Option<&'static str>,
+    direct_frames: u64,
+    composited_frames: u64,
+}
+
+impl Scanout {
+    /// A page flip of the output completed.
+    pub fn frame_submitted(&mut self) {
+        if let Some(next) = self.queued.take() {
+            if let Some((_, previous)) = self.current.replace(next) {
+                if Some(&previous) != self.current.as_ref().map(|(_, buffer)| buffer) {
+                    self.retired.push(previous);
+                }
+            }
+        }
+        for buffer in self.retired.drain(..) {
+            buffer.release();
+        }
+    }
+
+    /// Shows the buffer of `surface` on the primary plane, or returns why it cannot be.
+    pub fn try_scanout<D: ControlDevice>(
+        &mut self,
+        drm: &D,
+        crtc: crtc::Handle,
+        surface: &WlSurface,
+        mode_size: Size<i32, Physical>,
+        log: &Logger,
+    ) -> Result<(), &'static str> {
+        let (buffer, dmabuf) = self.scanout_buffer(surface, mode_size)?;
+        if self.rejected.contains(&buffer) {
+            return Err("the primary plane refused the buffer");
+        }
+        let framebuffer = match self.framebuffer(drm, &buffer, &dmabuf) {
+            Ok(framebuffer) => framebuffer,
+            Err(err) => {
+                debug!(log, "Unable to add a framebuffer for the game buffer: {}", err);
+                self.reject(buffer);
+                return Err("the buffer cannot be added as a framebuffer");
+            }
+        };
+        if let Err(err) = drm.page_flip(crtc, framebuffer, &[PageFlipFlags::PageFlipEvent], None) {
+            debug!(log, "Unable to scan the game buffer out: {}", err);
+            if matches!(err, drm::SystemError::InvalidArgument) {
+                self.reject(buffer);
+            }
+            return Err("the page flip failed");
+        }
+
+        with_states(surface, |states| {
+            if let Some(data) = states.data_map.get::<RefCell<SurfaceData>>() {
+                let mut data = data.borrow_mut();
+                if data.buffer.as_ref() == Some(&buffer) {
+                    data.buffer = None;
+                }
+            }
+        })
+        .ok();
+        self.queued = Some((surface.clone(), buffer));
+
+        self.direct_frames += 1;
+        if !self.direct {
+            self.direct = true;
+            self.reason = None;
+            debug!(log, "Scanning the game out directly";
+                "direct_frames" => self.direct_frames,
+                "composited_frames" => self.composited_frames,
+            );
+        }
+        Ok(())
+    }
+
+    /// Records that the output is composited, giving the buffers on screen back to their
+    /// surfaces so they get drawn.
+    pub fn composited(&mut self, reason: &'static str, log: &Logger) {
+        self.composited_frames += 1;
+        if self.direct || self.reason != Some(reason) {
+            self.direct = false;
+            self.reason = Some(reason);
+            debug!(log, "Compositing the output: {}", reason;
+                "direct_frames" => self.direct_frames,
+                "composited_frames" => self.composited_frames,
+            );
+        }
+
+        for (surface, buffer) in self.current.take().into_iter().chain(self.queued.take()) {
+            let restored = with_states(&surface, |states| {
+                let data = match states.data_map.get::<RefCell<SurfaceData>>() {
+                    Some(data) => data,
+                    None => return false,
+                };
+                let mut data = data.borrow_mut();
+                // The surface releases it once drawn, unless a newer buffer came meanwhile
+                if data.buffer.is_none() && data.texture.is_none() {
+                    data.buffer = Some(buffer.clone());
+                    true
+                } else {
+                    false
+                }
+            })
+            .unwrap_or(false);
+            if !restored && !self.retired.contains(&buffer) {
+                self.retired.push(buffer);
+            }
+        }
+    }
+
+    /// Returns the buffer of the surface if the primary plane can show it as is.
+    fn scanout_buffer(
+        &self,
+        surface: &WlSurface,
+        mode_size: Size<i32, Physical>,
+    ) -> Result<(WlBuffer, Dmabuf), &'static str> {
+        if !compositor::get_children(surface).is_empty() {
+            return Err("the game has subsurfaces");
+        }
+        with_states(surface, |states| {
+            let data = states
+                .data_map
+                .get::<RefCell<SurfaceData>>()
+                .ok_or("the game has no buffer")?
+                .borrow();
+            let attributes = states.cached_state.current::<SurfaceAttributes>();
+            let buffer = match (&data.buffer, &self.current) {
+                (Some(buffer), _) => buffer.clone(),
+                // Still on screen, nothing was committed since
+                (None, Some((current, buffer))) if current == surface && data.texture.is_none() => {
+                    buffer.clone()
+                }
+                (None, _) if data.texture.is_some() => return Err("the game buffer is already imported"),
+                (None, _) => return Err("the game has no buffer"),
+            };
+            let dmabuf = buffer
+                .as_ref()
+                .user_data()
+                .get::<Dmabuf>()
+                .cloned()
+                .ok_or("the game buffer is not a dmabuf")?;
+            if data.buffer_scale != 1 || attributes.buffer_transform != wl_output::Transform::Normal {
+                return Err("the game buffer is scaled or transformed");
+            }
+            if (dmabuf.width() as i32, dmabuf.height() as i32) != (mode_size.w, mode_size.h) {
+                return Err("the game buffer does not match the mode");
+            }
+            if !OPAQUE_FORMATS.contains(&dmabuf.format().code)
+                && !covers(attributes.opaque_region.as_ref(), mode_size)
+            {
+                return Err("the game buffer is translucent");
+            }
+            Ok((buffer, dmabuf))
+        })
+        .unwrap_or(Err("the game surface is gone"))
+    }
+
+    /// Returns the framebuffer of a client buffer, adding it if needed.
+    fn framebuffer<D: ControlDevice>(
+        &mut self,
+        drm: &D,
+        buffer: &WlBuffer,
+        dmabuf: &Dmabuf,
+    ) -> Result<framebuffer::Handle, drm::SystemError> {
+        if let Some(cached) = self.framebuffers.iter().find(|fb| &fb.buffer == buffer) {
+            return Ok(cached.framebuffer);
+        }
+
+        // Removing the framebuffer shown would turn the output off
+        let in_use = |fb: &ClientFramebuffer| {
+            [&self.current, &self.queued]
+                .iter()
+                .any(|shown| shown.as_ref().map(|(_, b)| b == &fb.buffer).unwrap_or(false))
+        };
+        let mut i = 0;
+        while i < self.framebuffers.len() {
+            let fb = &self.framebuffers[i];
+            if !fb.buffer.as_ref().is_alive() && !in_use(fb) {
+                self.framebuffers.remove(i).destroy(drm);
+            } else {
+                i += 1;
+            }
+        }
+        if self.framebuffers.len() >= CACHED_FRAMEBUFFERS {
+            if let Some(oldest) = self.framebuffers.iter().position(|fb| !in_use(fb)) {
+                self.framebuffers.remove(oldest).destroy(drm);
+            }
+        }
+
+        let imported = ClientFramebuffer::import(drm, buffer, dmabuf)?;
+        let framebuffer = imported.framebuffer;
+        self.framebuffers.push(imported);
+        Ok(framebuffer)
+    }
+
+    fn reject(&mut self, buffer: WlBuffer) {
+        if self.rejected.len() >= CACHED_FRAMEBUFFERS {
+            self.rejected.remove(0);
+        }
+        self.rejected.push(buffer);
+    }
+}
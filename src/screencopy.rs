@@ -0,0 +1,532 @@
+use std::{
+    ops::Deref,
+    os::unix::{fs::MetadataExt, io::RawFd},
+    path::PathBuf,
+};
+
+use image::{imageops, RgbaImage};
+use smithay::{
+    backend::{
+        allocator::{dmabuf::Dmabuf, Buffer, Fourcc},
+        renderer::{
+            buffer_type,
+            gles2::{Gles2Error, Gles2Renderer},
+            Bind, BufferType, Frame, Renderer, Transform, Unbind,
+        },
+    },
+    reexports::{
+        nix::{
+            libc,
+            sys::{
+                mman::{mmap, munmap, MapFlags, ProtFlags},
+                stat::{major, minor},
+            },
+        },
+        wayland_protocols::wlr::unstable::screencopy::v1::server::{
+            zwlr_screencopy_frame_v1::{self, Flags, ZwlrScreencopyFrameV1},
+            zwlr_screencopy_manager_v1::{self, ZwlrScreencopyManagerV1},
+        },
+        wayland_server::{
+            protocol::{wl_buffer::WlBuffer, wl_output::WlOutput, wl_shm},
+            Client, Display, Filter, Main,
+        },
+    },
+    utils::{Logical, Physical, Rectangle, Size},
+    wayland::shm,
+};
+
+use crate::{drawing::import_bitmap, screenshot::Captures, state::AnvilState};
+
+/// A frame requested by a client, waiting for its buffer, then for its output to be rendered.
+#[derive(Debug)]
+struct CopyFrame {
+    resource: ZwlrScreencopyFrameV1,
+    manager: ZwlrScreencopyManagerV1,
+    output: String,
+    /// The part of the output copied, in pixels.
+    region: Rectangle<i32, Physical>,
+    overlay_cursor: bool,
+    buffer: Option<WlBuffer>,
+    /// Whether the copy waits for the region to change since the last one.
+    with_damage: bool,
+}
+
+/// The `zwlr_screencopy_manager_v1` frames of the capture tools.
+#[derive(Debug, Default)]
+pub struct Screencopy {
+    frames: Vec<CopyFrame>,
+    /// The last frame copied with damage through each manager, to find what changed since.
+    last_frames: Vec<(ZwlrScreencopyManagerV1, String, bool, RgbaImage)>,
+}
+
+impl Screencopy {
+    /// Returns `true` if frames of an output are ready to be copied when it is rendered next.
+    ///
+    /// Frames waiting for a change are only copied once the output is `damaged`, so that an idle
+    /// output is not read back on every vblank.
+    pub fn is_pending(&self, output: &str, overlay_cursor: bool, damaged: bool) -> bool {
+        self.frames.iter().any(|f| {
+            f.buffer.is_some()
+                && f.output == output
+                && f.overlay_cursor == overlay_cursor
+                && (!f.with_damage || damaged || !self.has_last_frame(f))
+        })
+    }
+
+    /// Returns `true` if a frame was already copied with damage for the manager of `frame`.
+    fn has_last_frame(&self, frame: &CopyFrame) -> bool {
+        self.last_frames
+            .iter()
+            .any(|(m, o, c, _)| *m == frame.manager && *o == frame.output && *c == frame.overlay_cursor)
+    }
+}
+
+/// Returns the canonical path of the executable of a client.
+fn client_executable(client: &Client) -> Option<PathBuf> {
+    let pid = client.credentials()?.pid;
+    std::fs::read_link(format!("/proc/{}/exe", pid)).ok()
+}
+
+/// Creates the `zwlr_screencopy_manager_v1` global, only shown to the allowed executables.
+///
+/// Executables are matched by their full path, as any game could name its binary `grim`.
+pub fn init_screencopy_global<BackendData: 'static>(
+    display: &mut Display,
+    allowed: Vec<PathBuf>,
+    log: slog::Logger,
+) {
+    let allowed = allowed
+        .into_iter()
+        .filter_map(|path| {
+            if !path.is_absolute() {
+                warn!(log, "Ignoring screencopy executable without a full path"; "path" => path.display().to_string());
+                return None;
+            }
+            std::fs::canonicalize(&path).ok()
+        })
+        .collect::<Vec<_>>();
+    display.create_global_with_filter::<ZwlrScreencopyManagerV1, _>(
+        3,
+        Filter::new(|(manager, _): (Main<ZwlrScreencopyManagerV1>, u32), _, _| {
+            manager.quick_assign(|manager, request, mut ddata| {
+                let state = ddata.get::<AnvilState<BackendData>>().unwrap();
+                match request {
+                    zwlr_screencopy_manager_v1::Request::CaptureOutput {
+                        frame,
+                        overlay_cursor,
+                        output,
+                    } => state.new_screencopy_frame(&manager, frame, overlay_cursor != 0, &output, None),
+                    zwlr_screencopy_manager_v1::Request::CaptureOutputRegion {
+                        frame,
+                        overlay_cursor,
+                        output,
+                        x,
+                        y,
+                        width,
+                        height,
+                    } => state.new_screencopy_frame(
+                        &manager,
+                        frame,
+                        overlay_cursor != 0,
+                        &output,
+                        Some(Rectangle::from_loc_and_size((x, y), (width, height))),
+                    ),
+                    _ => {}
+                }
+            });
+            manager.assign_destructor(Filter::new(|manager: ZwlrScreencopyManagerV1, _, mut ddata| {
+                if let Some(state) = ddata.get::<AnvilState<BackendData>>() {
+                    state.screencopy.last_frames.retain(|(m, ..)| *m != manager);
+                }
+            }));
+        }),
+        move |client| {
+            let exe = client_executable(&client);
+            let allow = exe.as_ref().map(|exe| allowed.contains(exe)).unwrap_or(false);
+            if !allow {
+                debug!(log, "Hiding screencopy from client"; "executable" => exe.map(|exe| exe.display().to_string()));
+            }
+            allow
+        },
+    );
+}
+
+/// Converts a region of an output to pixels, cropped to the output.
+fn to_physical(
+    region: Rectangle<i32, Logical>,
+    scale: f64,
+    size: Size<i32, Physical>,
+) -> Option<Rectangle<i32, Physical>> {
+    let scale = |value: i32| (value as f64 * scale).round() as i32;
+    let (x1, y1) = (scale(region.loc.x).max(0), scale(region.loc.y).max(0));
+    let (x2, y2) = (
+        scale(region.loc.x + region.size.w).min(size.w),
+        scale(region.loc.y + region.size.h).min(size.h),
+    );
+    (x2 > x1 && y2 > y1).then(|| Rectangle::from_loc_and_size((x1, y1), (x2 - x1, y2 - y1)))
+}
+
+/// Returns the bounding box of the pixels of `region` that differ between two frames,
+/// relative to the region.
+fn find_damage(
+    previous: &RgbaImage,
+    current: &RgbaImage,
+    region: Rectangle<i32, Physical>,
+) -> Option<Rectangle<i32, Physical>> {
+    let full = Rectangle::from_loc_and_size((0, 0), region.size);
+    if previous.dimensions() != current.dimensions() {
+        return Some(full);
+    }
+    let stride = current.width() as usize * 4;
+    let (mut x1, mut y1, mut x2, mut y2) = (i32::MAX, i32::MAX, 0, 0);
+    for y in 0..region.size.h {
+        let start = (region.loc.y + y) as usize * stride + region.loc.x as usize * 4;
+        let end = start + region.size.w as usize * 4;
+        let (old, new) = (&previous.as_raw()[start..end], &current.as_raw()[start..end]);
+        if old == new {
+            continue;
+        }
+        let changed = |(a, b): (&[u8], &[u8])| a != b;
+        let pixels = || old.chunks_exact(4).zip(new.chunks_exact(4));
+        x1 = x1.min(pixels().position(changed).unwrap_or(0) as i32);
+        x2 = x2.max(
+            pixels()
+                .rposition(changed)
+                .map(|x| x as i32 + 1)
+                .unwrap_or(region.size.w),
+        );
+        y1 = y1.min(y);
+        y2 = y + 1;
+    }
+    (y2 > y1).then(|| Rectangle::from_loc_and_size((x1, y1), (x2 - x1, y2 - y1)))
+}
+
+/// Finds the file mapped at `address` among the descriptors of the compositor, with its offset there.
+///
+/// Smithay keeps the descriptor of each shm pool, but only lends its read-only mapping. The file is
+/// found from its device and inode in `/proc/self/maps`.
+fn mapped_file(address: usize) -> Option<(RawFd, libc::off_t)> {
+    let maps = std::fs::read_to_string("/proc/self/maps").ok()?;
+    let (offset, dev, inode) = maps.lines().find_map(|line| {
+        let mut fields = line.split_whitespace();
+        let (start, end) = fields.next()?.split_once('-')?;
+        let start = usize::from_str_radix(start, 16).ok()?;
+        if address < start || address >= usize::from_str_radix(end, 16).ok()? {
+            return None;
+        }
+        let offset = u64::from_str_radix(fields.nth(1)?, 16).ok()?;
+        let (dev_major, dev_minor) = fields.next()?.split_once(':')?;
+        let dev = (
+            u64::from_str_radix(dev_major, 16).ok()?,
+            u64::from_str_radix(dev_minor, 16).ok()?,
+        );
+        let inode = fields.next()?.parse::<u64>().ok()?;
+        Some((offset + (address - start) as u64, dev, inode))
+    })?;
+
+    let fd = std::fs::read_dir("/proc/self/fd")
+        .ok()?
+        .filter_map(|entry| entry.ok())
+        .find_map(|entry| {
+            let fd = entry.file_name().to_str()?.parse::<RawFd>().ok()?;
+            let metadata = std::fs::metadata(entry.path()).ok()?;
+            let matches = metadata.ino() == inode && (major(metadata.dev()), minor(metadata.dev())) == dev;
+            matches.then(|| fd)
+        })?;
+    Some((fd, offset as libc::off_t))
+}
+
+/// A writable mapping of the pool of a shm buffer, kept in the user data of the buffer as the
+/// capture tools copy to the same buffers over and over.
+#[derive(Debug)]
+struct PoolMapping {
+    ptr: *mut libc::c_void,
+    len: usize,
+}
+
+impl PoolMapping {
+    /// Maps the pool lent by smithay again, writable this time.
+    fn new(pool: &[u8]) -> Result<PoolMapping, String> {
+        let (fd, offset) =
+            mapped_file(pool.as_ptr() as usize).ok_or_else(|| "The buffer file was not found".to_owned())?;
+        let ptr = unsafe {
+            mmap(
+                std::ptr::null_mut(),
+                pool.len(),
+                ProtFlags::PROT_READ | ProtFlags::PROT_WRITE,
+                MapFlags::MAP_SHARED,
+                fd,
+                offset,
+            )
+        }
+        .map_err(|err| format!("The buffer is not writable: {}", err))?;
+        Ok(PoolMapping { ptr, len: pool.len() })
+    }
+}
+
+impl Drop for PoolMapping {
+    fn drop(&mut self) {
+        let _ = unsafe { munmap(self.ptr, self.len) };
+    }
+}
+
+/// Writes a region of a frame to a `xrgb8888` or `argb8888` shm buffer.
+fn copy_shm(buffer: &WlBuffer, image: &RgbaImage, region: Rectangle<i32, Physical>) -> Result<(), String> {
+    shm::with_buffer_contents(buffer, |pool, data| {
+        // Pools are mapped read-only, so the pool file is mapped again to write to it
+        let user_data = buffer.as_ref().user_data();
+        if user_data.get::<PoolMapping>().is_none() {
+            let mapping = PoolMapping::new(pool)?;
+            user_data.set(move || mapping);
+        }
+        let mapping = user_data
+            .get::<PoolMapping>()
+            .ok_or_else(|| "The buffer is not writable".to_owned())?;
+        let pool = unsafe { std::slice::from_raw_parts_mut(mapping.ptr as *mut u8, mapping.len) };
+
+        let stride = image.width() as usize * 4;
+        for y in 0..region.size.h as usize {
+            let src = (region.loc.y as usize + y) * stride + region.loc.x as usize * 4;
+            let src = &image.as_raw()[src..src + region.size.w as usize * 4];
+            let dst = data.offset as usize + y * data.stride as usize;
+            let dst = &mut pool[dst..dst + region.size.w as usize * 4];
+            for (dst, src) in dst.chunks_exact_mut(4).zip(src.chunks_exact(4)) {
+                // Stored as BGRA in little endian
+                dst.copy_from_slice(&[src[2], src[1], src[0], 0xff]);
+            }
+        }
+        Ok(())
+    })
+    .map_err(|err| format!("{:?}", err))?
+}
+
+/// Renders a region of a frame to a dmabuf of the capture tool.
+fn copy_dmabuf(
+    renderer: &mut Gles2Renderer,
+    dmabuf: &Dmabuf,
+    image: &RgbaImage,
+    region: Rectangle<i32, Physical>,
+) -> Result<(), Gles2Error> {
+    let cropped = imageops::crop_imm(
+        image,
+        region.loc.x as u32,
+        region.loc.y as u32,
+        region.size.w as u32,
+        region.size.h as u32,
+    )
+    .to_image();
+    let texture = import_bitmap(renderer, &cropped)?;
+    renderer.bind(dmabuf.clone())?;
+    // Rendered from the top row like the scanout buffers, so the frame is not y-inverted
+    let result = renderer
+        .render(region.size, Transform::Flipped180, |_, frame| {
+            frame.render_texture_at(&texture, (0, 0).into(), 1, 1.0, Transform::Normal, 1.0)
+        })
+        .and_then(|result| result);
+    renderer.unbind()?;
+    result
+}
+
+/// Returns the `CLOCK_MONOTONIC` time, split as in the `ready` event.
+fn ready_time() -> (u32, u32, u32) {
+    let mut time = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut time) };
+    let secs = time.tv_sec as u64;
+    ((secs >> 32) as u32, secs as u32, time.tv_nsec as u32)
+}
+
+impl<BackendData: 'static> AnvilState<BackendData> {
+    fn new_screencopy_frame(
+        &mut self,
+        manager: &ZwlrScreencopyManagerV1,
+        frame: Main<ZwlrScreencopyFrameV1>,
+        overlay_cursor: bool,
+        output: &WlOutput,
+        region: Option<Rectangle<i32, Logical>>,
+    ) {
+        frame.quick_assign(|frame, request, mut ddata| {
+            let state = ddata.get::<AnvilState<BackendData>>().unwrap();
+            match request {
+                zwlr_screencopy_frame_v1::Request::Copy { buffer } => {
+                    state.attach_screencopy_buffer(&frame, buffer, false)
+                }
+                zwlr_screencopy_frame_v1::Request::CopyWithDamage { buffer } => {
+                    state.attach_screencopy_buffer(&frame, buffer, true)
+                }
+                _ => {}
+            }
+        });
+        frame.assign_destructor(Filter::new(|frame: ZwlrScreencopyFrameV1, _, mut ddata| {
+            if let Some(state) = ddata.get::<AnvilState<BackendData>>() {
+                state.screencopy.frames.retain(|f| f.resource != frame);
+            }
+        }));
+
+        let output = self
+            .output_map
+            .borrow()
+            .find_by_output(output)
+            .and_then(|output| {
+                let size = output.physical_size();
+                let region = match region {
+                    Some(region) => to_physical(region, output.scale() as f64, size)?,
+                    None => Rectangle::from_loc_and_size((0, 0), size),
+                };
+                Some((output.name().to_owned(), region))
+            });
+        let (output, region) = match output {
+            Some(output) => output,
+            None => {
+                frame.failed();
+                return;
+            }
+        };
+
+        let (width, height) = (region.size.w as u32, region.size.h as u32);
+        frame.buffer(wl_shm::Format::Xrgb8888, width, height, width * 4);
+        if frame.as_ref().version() >= 3 {
+            frame.linux_dmabuf(Fourcc::Xrgb8888 as u32, width, height);
+            frame.buffer_done();
+        }
+        self.screencopy.frames.push(CopyFrame {
+            resource: frame.deref().clone(),
+            manager: manager.clone(),
+            output,
+            region,
+            overlay_cursor,
+            buffer: None,
+            with_damage: false,
+        });
+    }
+
+    fn attach_screencopy_buffer(
+        &mut self,
+        frame: &ZwlrScreencopyFrameV1,
+        buffer: WlBuffer,
+        with_damage: bool,
+    ) {
+        let copy = match self.screencopy.frames.iter_mut().find(|f| f.resource == *frame) {
+            Some(copy) => copy,
+            // The output was gone
+            None => return,
+        };
+        if copy.buffer.is_some() {
+            frame.as_ref().post_error(
+                zwlr_screencopy_frame_v1::Error::AlreadyUsed as u32,
+                "The frame was already copied".into(),
+            );
+            return;
+        }
+
+        let size = copy.region.size;
+        let valid = match buffer_type(&buffer) {
+            Some(BufferType::Shm) => shm::with_buffer_contents(&buffer, |_, data| {
+                matches!(data.format, wl_shm::Format::Xrgb8888 | wl_shm::Format::Argb8888)
+                    && (data.width, data.height) == (size.w, size.h)
+                    && data.stride >= size.w * 4
+            })
+            .unwrap_or(false),
+            Some(BufferType::Dma) => buffer
+                .as_ref()
+                .user_data()
+                .get::<Dmabuf>()
+                .map(|dmabuf| {
+                    matches!(dmabuf.format().code, Fourcc::Xrgb8888 | Fourcc::Argb8888)
+                        && (dmabuf.width(), dmabuf.height()) == (size.w as u32, size.h as u32)
+                })
+                .unwrap_or(false),
+            _ => false,
+        };
+        if !valid {
+            frame.as_ref().post_error(
+                zwlr_screencopy_frame_v1::Error::InvalidBuffer as u32,
+                "The buffer does not match the frame".into(),
+            );
+            return;
+        }
+        copy.buffer = Some(buffer);
+        copy.with_damage = with_damage;
+        self.schedule_render();
+    }
+
+    /// Copies the frames waiting for an output that was just rendered.
+    pub fn copy_screencopy_frames(
+        &mut self,
+        output: &str,
+        captures: &Captures,
+        renderer: &mut Gles2Renderer,
+    ) {
+        let screencopy = &mut self.screencopy;
+        let mut i = 0;
+        while i < screencopy.frames.len() {
+            let copy = &screencopy.frames[i];
+            let image = match copy.overlay_cursor {
+                true => captures.with_cursor.as_ref(),
+                false => captures.without_cursor.as_ref(),
+            };
+            let image = match image {
+                Some(image) if copy.buffer.is_some() && copy.output == output => image,
+                _ => {
+                    i += 1;
+                    continue;
+                }
+            };
+
+            let last_frame = screencopy
+                .last_frames
+                .iter()
+                .position(|(m, o, c, _)| *m == copy.manager && o == output && *c == copy.overlay_cursor);
+            let damage = match last_frame {
+                _ if !copy.with_damage => None,
+                Some(last) => match find_damage(&screencopy.last_frames[last].3, image, copy.region) {
+                    Some(damage) => Some(damage),
+                    // Waits for something to change
+                    None => {
+                        i += 1;
+                        continue;
+                    }
+                },
+                None => Some(Rectangle::from_loc_and_size((0, 0), copy.region.size)),
+            };
+
+            let copy = screencopy.frames.remove(i);
+            let buffer = copy.buffer.as_ref().unwrap();
+            let result = match buffer.as_ref().user_data().get::<Dmabuf>() {
+                Some(dmabuf) => {
+                    copy_dmabuf(renderer, dmabuf, image, copy.region).map_err(|err| err.to_string())
+                }
+                None => copy_shm(buffer, image, copy.region),
+            };
+            if let Err(err) = result {
+                warn!(self.log, "Unable to copy a frame for screencopy: {}", err);
+                copy.resource.failed();
+                continue;
+            }
+
+            copy.resource.flags(Flags::empty());
+            if let Some(damage) = damage {
+                copy.resource.damage(
+                    damage.loc.x as u32,
+                    damage.loc.y as u32,
+                    damage.size.w as u32,
+                    damage.size.h as u32,
+                );
+                let last = (
+                    copy.manager.clone(),
+                    output.to_owned(),
+                    copy.overlay_cursor,
+                    image.clone(),
+                );
+                match last_frame {
+                    Some(index) => screencopy.last_frames[index] = last,
+                    None => screencopy.last_frames.push(last),
+                }
+            }
+            let (secs_hi, secs_lo, nsecs) = ready_time();
+            copy.resource.ready(secs_hi, secs_lo, nsecs);
+        }
+    }
+}
@@ -0,0 +1,274 @@
+use std::{
+    mem,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use image::{imageops, DynamicImage, RgbaImage};
+use smithay::{
+    backend::renderer::gles2::{ffi, Gles2Error, Gles2Renderer},
+    reexports::{
+        calloop::{
+            timer::{Timer, TimerHandle},
+            LoopHandle,
+        },
+        nix::libc,
+        wayland_server::protocol::wl_output,
+    },
+    utils::{Physical, Size},
+};
+
+use crate::{
+    config::{self, ScreenshotConfig},
+    gui::osd::Toast,
+    recording::Recorder,
+    screencopy::Screencopy,
+    state::AnvilState,
+};
+
+#[derive(Debug)]
+enum SaveResult {
+    Saved(PathBuf),
+    Failed(String),
+}
+
+/// The frames to read back while an output is rendered.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct CaptureRequest {
+    pub screenshot: bool,
+    /// Screencopy frames wait for the output, without the cursor.
+    pub screencopy: bool,
+    /// Screencopy frames wait for the output, with the cursor.
+    pub screencopy_cursor: bool,
+    /// A frame of the recording is due.
+    pub recording: bool,
+}
+
+impl CaptureRequest {
+    /// Returns what to read back from the frame of an output about to be rendered, `damaged` since
+    /// the previous one.
+    pub fn new(
+        screenshots: &mut Screenshots,
+        screencopy: &Screencopy,
+        recorder: &mut Recorder,
+        output: &str,
+        damaged: bool,
+    ) -> CaptureRequest {
+        CaptureRequest {
+            screenshot: screenshots.take_request(),
+            screencopy: screencopy.is_pending(output, false, damaged),
+            screencopy_cursor: screencopy.is_pending(output, true, damaged),
+            recording: recorder.wants_frame(),
+        }
+    }
+
+    pub fn without_cursor(&self) -> bool {
+        self.screenshot || self.screencopy || self.recording
+    }
+
+    pub fn with_cursor(&self) -> bool {
+        self.screencopy_cursor
+    }
+}
+
+/// The frames read back from an output, as requested.
+#[derive(Debug, Default)]
+pub struct Captures {
+    pub without_cursor: Option<RgbaImage>,
+    pub with_cursor: Option<RgbaImage>,
+}
+
+/// Takes screenshots of the next frame rendered, and saves them as PNG.
+#[derive(Debug)]
+pub struct Screenshots {
+    directory: PathBuf,
+    /// Whether the next frame rendered is captured.
+    requested: bool,
+    timer: TimerHandle<SaveResult>,
+}
+
+impl Screenshots {
+    pub fn new<BackendData: 'static>(
+        config: &ScreenshotConfig,
+        handle: &LoopHandle<'static, AnvilState<BackendData>>,
+    ) -> Screenshots {
+        let timer = Timer::new().expect("Failed to create the screenshot timer");
+        let timer_handle = timer.handle();
+        handle
+            .insert_source(timer, |result, _, state| match result {
+                SaveResult::Saved(path) => {
+                    info!(state.log, "Screenshot saved"; "path" => path.display().to_string());
+                    state.osd.post(Toast::text("Screenshot saved"));
+                }
+                SaveResult::Failed(err) => {
+                    warn!(state.log, "Unable to save the screenshot: {}", err);
+                    state
+                        .osd
+                        .post(Toast::text("Unable to save the screenshot").warning());
+                }
+            })
+            .expect("Failed to init the screenshot timer");
+
+        Screenshots {
+            directory: config::expand_home(config.directory.clone()),
+            requested: false,
+            timer: timer_handle,
+        }
+    }
+
+    pub fn request(&mut self) {
+        self.requested = true;
+    }
+
+    fn take_request(&mut self) -> bool {
+        mem::take(&mut self.requested)
+    }
+}
+
+/// Reads back the framebuffer currently bound.
+///
+/// Framebuffers are read from the bottom row up, so a frame rendered without flipping
+/// the rows, like the one of a window, is `bottom_up`.
+pub fn capture(
+    renderer: &mut Gles2Renderer,
+    size: Size<i32, Physical>,
+    bottom_up: bool,
+) -> Result<RgbaImage, Gles2Error> {
+    let (width, height) = (size.w.max(0) as u32, size.h.max(0) as u32);
+    let mut pixels = vec![0u8; width as usize * height as usize * 4];
+    renderer.with_context(|_, gl| unsafe {
+        gl.PixelStorei(ffi::PACK_ALIGNMENT, 1);
+        gl.ReadPixels(
+            0,
+            0,
+            width as i32,
+            height as i32,
+            ffi::RGBA,
+            ffi::UNSIGNED_BYTE,
+            pixels.as_mut_ptr() as *mut _,
+        );
+    })?;
+    let mut image = RgbaImage::from_raw(width, height, pixels).expect("Wrong screenshot buffer size");
+    if bottom_up {
+        imageops::flip_vertical_in_place(&mut image);
+    }
+    Ok(image)
+}
+
+/// Turns a frame read back from an output with `transform` upright.
+pub fn upright(image: RgbaImage, transform: wl_output::Transform) -> RgbaImage {
+    use wl_output::Transform;
+
+    let image = match transform {
+        Transform::_90 | Transform::Flipped90 => imageops::rotate90(&image),
+        Transform::_180 | Transform::Flipped180 => imageops::rotate180(&image),
+        Transform::_270 | Transform::Flipped270 => imageops::rotate270(&image),
+        _ => image,
+    };
+    match transform {
+        Transform::Flipped | Transform::Flipped90 | Transform::Flipped180 | Transform::Flipped270 => {
+            imageops::flip_horizontal(&image)
+        }
+        _ => image,
+    }
+}
+
+/// Returns the local time as `2021-12-31_23-59-59`.
+fn timestamp() -> String {
+    unsafe {
+        let time = libc::time(std::ptr::null_mut());
+        let mut tm: libc::tm = mem::zeroed();
+        libc::localtime_r(&time, &mut tm);
+        format!(
+            "{:04}-{:02}-{:02}_{:02}-{:02}-{:02}",
+            tm.tm_year + 1900,
+            tm.tm_mon + 1,
+            tm.tm_mday,
+            tm.tm_hour,
+            tm.tm_min,
+            tm.tm_sec
+        )
+    }
+}
+
+/// Keeps the name of a game usable as a file name.
+fn file_name(name: &str) -> String {
+    name.chars()
+        .map(|c| {
+            if c.is_alphanumeric() || c == '-' || c == '_' || c == ' ' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+/// Returns a path in `directory` for a new file named after `base`.
+pub fn unique_path(directory: &Path, base: &str, extension: &str) -> PathBuf {
+    let mut path = directory.join(format!("{}.{}", base, extension));
+    // Captures taken during the same second
+    let mut i = 1;
+    while path.exists() {
+        i += 1;
+        path = directory.join(format!("{} ({}).{}", base, i, extension));
+    }
+    path
+}
+
+fn save(image: RgbaImage, directory: &Path, name: &str) -> Result<PathBuf, String> {
+    std::fs::create_dir_all(directory).map_err(|err| err.to_string())?;
+    let path = unique_path(directory, name, "png");
+    // The framebuffer alpha is meaningless once on screen
+    DynamicImage::ImageRgba8(image)
+        .to_rgb8()
+        .save(&path)
+        .map_err(|err| err.to_string())?;
+    Ok(path)
+}
+
+impl<BackendData: 'static> AnvilState<BackendData> {
+    /// Hands the frames read back from an output to the screenshots, the screencopy clients
+    /// and the recording.
+    pub fn frame_captured(
+        &mut self,
+        output: &str,
+        request: CaptureRequest,
+        captures: Captures,
+        renderer: &mut Gles2Renderer,
+    ) {
+        self.copy_screencopy_frames(output, &captures, renderer);
+        if let (true, Some(image)) = (request.screenshot, &captures.without_cursor) {
+            self.save_screenshot(image.clone());
+        }
+        if let (true, Some(image)) = (request.recording, captures.without_cursor) {
+            self.recorder.add_frame(image);
+        }
+    }
+
+    /// Names a capture after the game shown and the time.
+    pub fn capture_name(&self) -> String {
+        let game = self
+            .window_map
+            .borrow()
+            .top_client_pid()
+            .and_then(|client_pid| self.games.find_by_client_pid(client_pid))
+            .map(|game| game.app.name.clone())
+            .unwrap_or_else(|| "Waystation".to_owned());
+        format!("{} {}", file_name(&game), timestamp())
+    }
+
+    /// Saves a captured frame from a separate thread.
+    fn save_screenshot(&mut self, image: RgbaImage) {
+        let name = self.capture_name();
+        let directory = self.screenshots.directory.clone();
+        let timer = self.screenshots.timer.clone();
+        std::thread::spawn(move || {
+            let result = match save(image, &directory, &name) {
+                Ok(path) => SaveResult::Saved(path),
+                Err(err) => SaveResult::Failed(err),
+            };
+            timer.add_timeout(Duration::ZERO, result);
+        });
+    }
+}
@@ -16,6 +16,7 @@ use smithay::{backend::renderer::buffer_dimensions, reexports::{
         }, seat::{AxisFrame, GrabStartData, PointerGrab, PointerInnerHandle, Seat}, shell::{legacy::{wl_shell_init, ShellRequest, ShellState as WlShellState, ShellSurfaceKind}, wlr_layer::{LayerShellRequest, LayerSurfaceAttributes}, xdg::{Configure, ShellState as XdgShellState, SurfaceCachedState, ToplevelSurface, XdgPopupSurfaceRoleAttributes, XdgRequest, XdgToplevelSurfaceRoleAttributes, xdg_shell_init}}}};
 
 use crate::{
+    damage,
     output_map::OutputMap,
     state::AnvilState,
     window_map::{Kind as SurfaceKind, PopupKind, WindowMap},
@@ -304,15 +305,6 @@ fn fullscreen_output_geometry(
     output_map.with_primary().map(|o| o.geometry())
 }
 
-fn is_our_pid(surface: &ToplevelSurface) -> bool {
-    surface.get_surface()
-        .map(|surface| surface.as_ref())
-        .and_then(|resource| resource.client())
-        .and_then(|client| client.credentials())
-        .map(|credentials| credentials.pid == std::process::id() as i32)
-        .unwrap_or(false)
-}
-
 pub fn init_shell<BackendData: 'static>(display: Rc<RefCell<Display>>, log: ::slog::Logger) -> ShellHandles {
     // Create the compositor
     compositor_init(
@@ -321,7 +313,9 @@ pub fn init_shell<BackendData: 'static>(display: Rc<RefCell<Display>>, log: ::sl
             let anvil_state = ddata.get::<AnvilState<BackendData>>().unwrap();
             let window_map = anvil_state.window_map.as_ref();
             let output_map = anvil_state.output_map.as_ref();
-            surface_commit(&surface, &*window_map, &*output_map)
+            surface_commit(&surface, &*window_map, &*output_map);
+            // The client may wait for a frame callback even if nothing was damaged
+            anvil_state.schedule_render();
         },
         log.clone(),
     );
@@ -362,18 +356,11 @@ pub fn init_shell<BackendData: 'static>(display: Rc<RefCell<Display>>, log: ::sl
                     // Do not send a configure here, the initial configure
                     // of a xdg_surface has to be sent during the commit if
                     // the surface is not already configured
-                    if is_our_pid(&surface) {
-                        state
-                            .window_map
-                            .borrow_mut()
-                            .set_menu_window(SurfaceKind::Xdg(surface), (0, 0).into());
-                    } else {
-                        let mut window_map = state
-                            .window_map
-                            .borrow_mut();
-                        window_map.insert(SurfaceKind::Xdg(surface), (0, 0).into());
-                        window_map.set_menu_on_top(false);
-                    }
+                    let mut window_map = state
+                        .window_map
+                        .borrow_mut();
+                    window_map.insert(SurfaceKind::Xdg(surface), (0, 0).into());
+                    window_map.set_menu_on_top(false);
                 }
 
                 XdgRequest::NewPopup { surface, positioner } => {
@@ -1016,11 +1003,13 @@ impl SurfaceData {
             .contains(point.to_i32_floor())
     }
 
-    /// Send the frame callback if it had been requested
-    pub fn send_frame(attrs: &mut SurfaceAttributes, time: u32) {
+    /// Send the frame callback if it had been requested, returning whether one was.
+    pub fn send_frame(attrs: &mut SurfaceAttributes, time: u32) -> bool {
+        let requested = !attrs.frame_callbacks.is_empty();
         for callback in attrs.frame_callbacks.drain(..) {
             callback.done(time);
         }
+        requested
     }
 }
 
@@ -1168,4 +1157,6 @@ fn surface_commit(
             window_map.layers.arange_layers(output);
         }
     }
+
+    damage::surface_committed(surface, &*window_map, &*output_map.borrow());
 }
@@ -0,0 +1,326 @@
+use std::time::Duration;
+
+use smithay::reexports::{
+    calloop::{
+        timer::{Timer, TimerHandle},
+        LoopHandle,
+    },
+    nix::sys::signal::Signal,
+};
+
+use crate::state::{AnvilState, Backend};
+
+#[derive(Debug, Clone, Copy)]
+enum SleepEvent {
+    Suspend,
+    /// `systemctl suspend` returned, the device woke up.
+    Resumed,
+}
+
+/// Suspends the device, stopping the games while it sleeps.
+///
+/// With the `logind` feature, a delay inhibitor lets the compositor stop the games and turn
+/// the screen off when logind announces the sleep, whoever asked for it.
+#[derive(Debug)]
+pub struct Sleep {
+    /// The games stopped for the sleep, continued when waking up.
+    stopped: Vec<u32>,
+    sleeping: bool,
+    timer: TimerHandle<SleepEvent>,
+    #[cfg(feature = "logind")]
+    logind: Option<logind::Logind>,
+}
+
+impl Sleep {
+    #[cfg_attr(not(feature = "logind"), allow(unused_variables))]
+    pub fn new<BackendData: Backend + 'static>(
+        handle: &LoopHandle<'static, AnvilState<BackendData>>,
+        log: &slog::Logger,
+    ) -> Sleep {
+        let timer = Timer::new().expect("Failed to create the sleep timer");
+        let timer_handle = timer.handle();
+        handle
+            .insert_source(timer, |event, _, state| match event {
+                SleepEvent::Suspend => state.suspend(),
+                SleepEvent::Resumed => state.wake_from_sleep(),
+            })
+            .expect("Failed to init the sleep timer");
+
+        Sleep {
+            stopped: Vec::new(),
+            sleeping: false,
+            timer: timer_handle,
+            #[cfg(feature = "logind")]
+            logind: logind::Logind::new(handle, log),
+        }
+    }
+}
+
+impl<BackendData: 'static> AnvilState<BackendData> {
+    /// Suspends the device from the event loop.
+    pub fn request_suspend(&mut self) {
+        self.sleep.timer.add_timeout(Duration::ZERO, SleepEvent::Suspend);
+    }
+}
+
+impl<BackendData: Backend + 'static> AnvilState<BackendData> {
+    fn suspend(&mut self) {
+        if self.sleep.sleeping {
+            return;
+        }
+        info!(self.log, "Suspending");
+
+        #[cfg(feature = "logind")]
+        if let Some(logind) = &self.sleep.logind {
+            // logind announces the sleep, which is when the games are stopped
+            match logind.suspend() {
+                Ok(()) => {
+                    self.dispatch_logind_later();
+                    return;
+                }
+                Err(err) => warn!(self.log, "Unable to suspend through logind: {}", err),
+            }
+        }
+
+        self.prepare_for_sleep();
+        let timer = self.sleep.timer.clone();
+        let log = self.log.clone();
+        std::thread::spawn(move || {
+            match std::process::Command::new("systemctl").arg("suspend").status() {
+                Ok(status) if status.success() => {}
+                Ok(status) => warn!(log, "Unable to suspend: systemctl exited with {}", status),
+                Err(err) => warn!(log, "Unable to suspend: {}", err),
+            }
+            timer.add_timeout(Duration::ZERO, SleepEvent::Resumed);
+        });
+    }
+
+    /// Stops the running games and turns the screen off.
+    fn prepare_for_sleep(&mut self) {
+        if self.sleep.sleeping {
+            return;
+        }
+        self.sleep.sleeping = true;
+        let running = self
+            .games
+            .games()
+            .filter(|game| !game.is_stopped())
+            .map(|game| game.pid())
+            .collect::<Vec<_>>();
+        for &pid in &running {
+            self.signal_game_tree(pid, Signal::SIGSTOP);
+        }
+        self.sleep.stopped = running;
+        self.blank_screen();
+    }
+
+    fn wake_from_sleep(&mut self) {
+        if !self.sleep.sleeping {
+            return;
+        }
+        info!(self.log, "Woke up from sleep");
+        self.sleep.sleeping = false;
+        // Games closed meanwhile are gone from the registry
+        for pid in std::mem::take(&mut self.sleep.stopped) {
+            if self.games.get(pid).is_some() {
+                self.signal_game_tree(pid, Signal::SIGCONT);
+            }
+        }
+        self.notify_activity();
+        // The batteries drained meanwhile
+        self.refresh_power();
+    }
+}
+
+#[cfg(feature = "logind")]
+mod logind {
+    use std::{fmt, os::unix::io::AsRawFd, time::Duration};
+
+    use dbus::{
+        arg::OwnedFd,
+        channel::{BusType, Channel},
+        message::MatchRule,
+        Message,
+    };
+    use smithay::reexports::calloop::{generic::Generic, Interest, LoopHandle, Mode, PostAction};
+
+    use crate::state::{AnvilState, Backend};
+
+    const LOGIND: &str = "org.freedesktop.login1";
+    const LOGIND_PATH: &str = "/org/freedesktop/login1";
+    const MANAGER: &str = "org.freedesktop.login1.Manager";
+
+    /// How long a call to logind may take.
+    const CALL_TIMEOUT: Duration = Duration::from_secs(1);
+
+    /// A system bus connection watching for logind suspending the device.
+    pub struct Logind {
+        channel: Channel,
+        /// Delays the sleep until it is dropped.
+        inhibitor: Option<OwnedFd>,
+    }
+
+    impl fmt::Debug for Logind {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.debug_struct("Logind")
+                .field("inhibited", &self.inhibitor.is_some())
+                .finish()
+        }
+    }
+
+    impl Logind {
+        pub fn new<BackendData: Backend + 'static>(
+            handle: &LoopHandle<'static, AnvilState<BackendData>>,
+            log: &slog::Logger,
+        ) -> Option<Logind> {
+            let result = Channel::get_private(BusType::System).and_then(|mut channel| {
+                channel.set_watch_enabled(true);
+                let rule = MatchRule::new_signal(MANAGER, "PrepareForSleep");
+                channel.send_with_reply_and_block(
+                    Message::call_with_args(
+                        "org.freedesktop.DBus",
+                        "/org/freedesktop/DBus",
+                        "org.freedesktop.DBus",
+                        "AddMatch",
+                        (rule.match_str(),),
+                    ),
+                    CALL_TIMEOUT,
+                )?;
+                Ok(channel)
+            });
+            let channel = match result {
+                Ok(channel) => channel,
+                Err(err) => {
+                    warn!(log, "Unable to watch for sleep through logind: {}", err);
+                    return None;
+                }
+            };
+
+            let fd = channel.watch().fd;
+            let result = handle.insert_source(
+                Generic::from_fd(fd, Interest::READ, Mode::Level),
+                |_, _, state: &mut AnvilState<BackendData>| Ok(state.dispatch_logind()),
+            );
+            if let Err(err) = result {
+                warn!(log, "Unable to watch for sleep through logind: {}", err);
+                return None;
+            }
+
+            let mut logind = Logind {
+                channel,
+                inhibitor: None,
+            };
+            logind.inhibit(log);
+            // Signals may have been read while taking the inhibitor
+            handle.insert_idle(|state| {
+                state.dispatch_logind();
+            });
+            Some(logind)
+        }
+
+        /// Takes a delay inhibitor, so the compositor gets to prepare for the next sleep.
+        pub fn inhibit(&mut self, log: &slog::Logger) {
+            if self.inhibitor.is_some() {
+                return;
+            }
+            let reply = self
+                .channel
+                .send_with_reply_and_block(
+                    Message::call_with_args(
+                        LOGIND,
+                        LOGIND_PATH,
+                        MANAGER,
+                        "Inhibit",
+                        (
+                            "sleep",
+                            "Waystation",
+                            "Stop the games and turn the screen off",
+                            "delay",
+                        ),
+                    ),
+                    CALL_TIMEOUT,
+                )
+                .and_then(|reply| Ok(reply.read1::<OwnedFd>()?));
+            match reply {
+                Ok(fd) => {
+                    debug!(log, "Took a sleep inhibitor"; "fd" => fd.as_raw_fd());
+                    self.inhibitor = Some(fd);
+                }
+                Err(err) => warn!(log, "Unable to take a sleep inhibitor: {}", err),
+            }
+        }
+
+        /// Lets the device go to sleep.
+        pub fn release(&mut self) {
+            self.inhibitor = None;
+        }
+
+        pub fn suspend(&self) -> Result<(), dbus::Error> {
+            // Not interactive, so polkit does not ask for a password
+            self.channel
+                .send_with_reply_and_block(
+                    Message::call_with_args(LOGIND, LOGIND_PATH, MANAGER, "Suspend", (false,)),
+                    CALL_TIMEOUT,
+                )
+                .map(|_| ())
+        }
+
+        /// Returns the `PrepareForSleep` signals received, `true` before sleeping and
+        /// `false` after waking up.
+        fn read_signals(&self) -> Result<Vec<bool>, ()> {
+            self.channel.read_write(Some(Duration::ZERO))?;
+            let mut signals = Vec::new();
+            while let Some(message) = self.channel.pop_message() {
+                if message.interface().as_deref() == Some(MANAGER)
+                    && message.member().as_deref() == Some("PrepareForSleep")
+                {
+                    if let Ok(start) = message.read1::<bool>() {
+                        signals.push(start);
+                    }
+                }
+            }
+            Ok(signals)
+        }
+    }
+
+    impl<BackendData: Backend + 'static> AnvilState<BackendData> {
+        /// Handles the signals libdbus read while blocking on a call to logind.
+        ///
+        /// They are queued without the socket staying readable, so the event loop would not
+        /// wake up for them.
+        pub fn dispatch_logind_later(&self) {
+            self.handle.insert_idle(|state| {
+                state.dispatch_logind();
+            });
+        }
+
+        fn dispatch_logind(&mut self) -> PostAction {
+            let signals = match self.sleep.logind.as_ref().map(Logind::read_signals) {
+                Some(Ok(signals)) => signals,
+                Some(Err(())) => {
+                    warn!(self.log, "Lost the connection to logind");
+                    self.sleep.logind = None;
+                    return PostAction::Remove;
+                }
+                None => return PostAction::Remove,
+            };
+            for start in signals {
+                if start {
+                    self.prepare_for_sleep();
+                    // The screen is off and the games stopped, the device can sleep now
+                    if let Some(logind) = &mut self.sleep.logind {
+                        logind.release();
+                    }
+                } else {
+                    self.wake_from_sleep();
+                    let log = self.log.clone();
+                    if let Some(logind) = &mut self.sleep.logind {
+                        logind.inhibit(&log);
+                        self.dispatch_logind_later();
+                    }
+                }
+            }
+            PostAction::Continue
+        }
+    }
+}
@@ -1,6 +1,17 @@
-use std::{cell::RefCell, rc::Rc, sync::{Arc, Mutex, atomic::{AtomicBool, Ordering}, mpsc::{Sender, channel}}};
+use std::{
+    cell::RefCell,
+    fs::OpenOptions,
+    io,
+    os::unix::{
+        fs::OpenOptionsExt,
+        io::{IntoRawFd, RawFd},
+    },
+    path::Path,
+    rc::Rc,
+    sync::{Arc, Mutex, atomic::{AtomicBool, Ordering}},
+};
 
-use smithay::{reexports::{calloop::{Interest, LoopHandle, Mode, PostAction, channel::Event, generic::Generic}, wayland_server::{protocol::wl_surface::WlSurface, Display}}, utils::{Logical, Point}, wayland::{
+use smithay::{reexports::{calloop::{Interest, LoopHandle, Mode, PostAction, generic::Generic}, nix::libc, wayland_server::{protocol::wl_surface::WlSurface, Display}}, utils::{Logical, Point}, wayland::{
         data_device::{default_action_chooser, init_data_device, set_data_device_focus, DataDeviceEvent},
         output::xdg::init_xdg_output_manager,
         seat::{CursorImageStatus, KeyboardHandle, PointerHandle, Seat, XkbConfig},
@@ -12,9 +23,7 @@ use smithay::{reexports::{calloop::{Interest, LoopHandle, Mode, PostAction, chan
 #[cfg(feature = "xwayland")]
 use smithay::xwayland::{XWayland, XWaylandEvent};
 
-use crate::{gui::{self, ToCompositor, ToUi}, output_map::OutputMap, shell::init_shell, window_map::WindowMap};
-
-use std::thread;
+use crate::{audio::Audio, backlight::Backlight, bindings::Bindings, config::Config, gamepad::{self, GamepadManager}, games::GameRegistry, gui::{osd::Osd, Menu}, idle::{self, IdleTracker}, ipc::{self, IpcSocket}, power::PowerMonitor, presentation, recording::Recorder, output_map::OutputMap, screencopy::{self, Screencopy}, screenshot::Screenshots, shell::init_shell, sleep::Sleep, window_map::WindowMap};
 
 #[derive(Debug)]
 pub struct AnvilState<BackendData> {
@@ -27,7 +36,21 @@ pub struct AnvilState<BackendData> {
     pub output_map: Rc<RefCell<crate::output_map::OutputMap>>,
     pub dnd_icon: Arc<Mutex<Option<WlSurface>>>,
     pub log: slog::Logger,
-    pub tx_to_ui: Sender<ToUi>,
+    pub menu: Menu,
+    pub osd: Osd,
+    pub bindings: Bindings,
+    pub gamepads: GamepadManager,
+    pub power: PowerMonitor,
+    pub audio: Audio,
+    /// The backlight of the built-in panel, found by the udev backend.
+    pub backlight: Option<Backlight>,
+    pub idle: IdleTracker,
+    pub sleep: Sleep,
+    pub screenshots: Screenshots,
+    pub screencopy: Screencopy,
+    pub recorder: Recorder,
+    pub games: GameRegistry,
+    pub config: Config,
     // input-related fields
     pub pointer: PointerHandle,
     pub keyboard: KeyboardHandle,
@@ -37,7 +60,10 @@ pub struct AnvilState<BackendData> {
     pub seat_name: String,
     pub seat: Seat,
     pub start_time: std::time::Instant,
+    /// Whether something drawn changed, so that the outputs with nothing to draw render again.
+    pub render_requested: bool,
     // things we must keep alive
+    pub ipc: Option<IpcSocket>,
     #[cfg(feature = "xwayland")]
     pub xwayland: XWayland<AnvilState<BackendData>>,
 }
@@ -51,9 +77,6 @@ impl<BackendData: Backend + 'static> AnvilState<BackendData> {
         listen_on_socket: bool,
     ) -> AnvilState<BackendData> {
 
-        let (tx_from_ui, rx_from_ui) = smithay::reexports::calloop::channel::channel();
-        let (tx, rx_to_ui) = std::sync::mpsc::channel();
-
         // init the wayland connection
         handle
             .insert_source(
@@ -72,23 +95,21 @@ impl<BackendData: Backend + 'static> AnvilState<BackendData> {
                 },
             )
             .expect("Failed to init the wayland event source.");
-        
-        handle
-            .insert_source(
-                rx_from_ui,
-                move |event, _, state: &mut AnvilState<BackendData>| {
-                    match event {
-                        Event::Msg(ToCompositor::SetMenuOnTop(on_top)) => {
-                            state.window_map.borrow_mut().set_menu_on_top(on_top);
-                        },
-                        Event::Closed => panic!("UI channel was closed, it should not close!"),
-                    }
-                }
-            )
-            .expect("Failed to init the from-UI event source.");
+
+        let config = Config::load(&log);
+        let games = GameRegistry::new(&handle);
+        let bindings = Bindings::new(&config.bindings, &handle, &log);
+        let menu = Menu::new(log.clone(), &handle);
+        gamepad::init_gamepads(&handle, &log);
+        let power = PowerMonitor::new(&handle, &log);
+        let audio = Audio::new(&config.audio, &log);
+        let idle = IdleTracker::new(&config.display, &handle);
+        let sleep = Sleep::new(&handle, &log);
+        let screenshots = Screenshots::new(&config.screenshots, &handle);
+        let recorder = Recorder::new(&config.recording, &handle);
 
         // Init a window map, to track the location of our windows
-        let window_map = Rc::new(RefCell::new(WindowMap::new(tx.clone())));
+        let window_map = Rc::new(RefCell::new(WindowMap::new()));
         let output_map = Rc::new(RefCell::new(OutputMap::new(
             display.clone(),
             window_map.clone(),
@@ -103,6 +124,13 @@ impl<BackendData: Backend + 'static> AnvilState<BackendData> {
         init_shell::<BackendData>(display.clone(), log.clone());
 
         init_xdg_output_manager(&mut display.borrow_mut(), log.clone());
+        idle::init_idle_globals::<BackendData>(&mut display.borrow_mut());
+        presentation::init_presentation_global(&mut display.borrow_mut());
+        screencopy::init_screencopy_global::<BackendData>(
+            &mut display.borrow_mut(),
+            config.screencopy.allowed.clone(),
+            log.clone(),
+        );
         init_xdg_activation_global(
             &mut display.borrow_mut(),
             |state, req, mut ddata| {
@@ -140,6 +168,7 @@ impl<BackendData: Backend + 'static> AnvilState<BackendData> {
         } else {
             None
         };
+        let ipc = ipc::init_ipc(&handle, socket_name.as_deref(), &log);
 
         // init data device
 
@@ -204,10 +233,6 @@ impl<BackendData: Backend + 'static> AnvilState<BackendData> {
             xwayland
         };
 
-        thread::spawn(move || {
-            gui::main(rx_to_ui, tx_from_ui);
-        });
-
         AnvilState {
             backend_data,
             running: Arc::new(AtomicBool::new(true)),
@@ -217,7 +242,20 @@ impl<BackendData: Backend + 'static> AnvilState<BackendData> {
             output_map,
             dnd_icon,
             log,
-            tx_to_ui: tx,
+            menu,
+            osd: Osd::new(),
+            bindings,
+            gamepads: GamepadManager::new(),
+            power,
+            audio,
+            backlight: None,
+            idle,
+            sleep,
+            screenshots,
+            screencopy: Screencopy::default(),
+            recorder,
+            games,
+            config,
             socket_name,
             pointer,
             keyboard,
@@ -227,12 +265,38 @@ impl<BackendData: Backend + 'static> AnvilState<BackendData> {
             seat_name,
             seat,
             start_time: std::time::Instant::now(),
+            render_requested: false,
+            ipc,
             #[cfg(feature = "xwayland")]
             xwayland,
         }
     }
 }
 
+impl<BackendData: 'static> AnvilState<BackendData> {
+    /// Makes the outputs render again, as something drawn on them changed. Outputs are only
+    /// drawn on demand on the TTY, the windowed backends draw every frame anyway.
+    pub fn schedule_render(&mut self) {
+        self.render_requested = true;
+    }
+}
+
 pub trait Backend {
     fn seat_name(&self) -> String;
+
+    /// Opens an input device for reading, through the session if the backend has one.
+    fn open_device(&mut self, path: &Path) -> io::Result<RawFd> {
+        OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_NONBLOCK | libc::O_CLOEXEC | libc::O_NOCTTY)
+            .open(path)
+            .map(IntoRawFd::into_raw_fd)
+    }
+
+    fn close_device(&mut self, fd: RawFd) {
+        unsafe { libc::close(fd) };
+    }
+
+    /// Turns the outputs off, or back on. Windowed backends leave them as they are.
+    fn set_blanked(&mut self, _blanked: bool) {}
 }
@@ -1,9 +1,10 @@
 use std::{
     cell::RefCell,
     collections::hash_map::{Entry, HashMap},
-    io::Error as IoError,
+    io::{self, Error as IoError},
+    mem,
     os::unix::io::{AsRawFd, RawFd},
-    path::PathBuf,
+    path::{Path, PathBuf},
     rc::Rc,
     sync::atomic::Ordering,
     time::Duration,
@@ -34,15 +35,16 @@ use smithay::{
         drm::{
             self,
             control::{
-                connector::{Info as ConnectorInfo, State as ConnectorState},
+                connector::{self, Info as ConnectorInfo, State as ConnectorState},
                 crtc,
                 encoder::Info as EncoderInfo,
-                Device as ControlDevice,
+                Device as ControlDevice, Mode as DrmMode, ModeFlags, ModeTypeFlags,
             },
         },
         gbm::Device as GbmDevice,
         input::Libinput,
-        nix::{fcntl::OFlag, sys::stat::dev_t},
+        nix::{fcntl::OFlag, libc, sys::stat::dev_t},
+        wayland_protocols::presentation_time::server::wp_presentation_feedback::Kind,
         wayland_server::{
             protocol::{wl_output, wl_surface},
             Display,
@@ -50,7 +52,7 @@ use smithay::{
     },
     utils::{
         signaling::{Linkable, SignalToken, Signaler},
-        Logical, Point,
+        Logical, Point, Rectangle,
     },
     wayland::{
         output::{Mode, PhysicalProperties},
@@ -67,9 +69,20 @@ use smithay::{
     wayland::dmabuf::init_dmabuf_global,
 };
 
-use crate::{drawing::*, window_map::WindowMap};
 use crate::{
-    render::render_layers_and_windows,
+    backlight::Backlight,
+    config::DisplayConfig,
+    damage::{self, DamageHistory, Scene},
+    drawing::*,
+    gui::{self, widget::Widget, OverlayRenderer},
+    input_handler::ActiveTouch,
+    window_map::WindowMap,
+};
+use crate::{
+    presentation::{self, FrameFeedbacks},
+    render::{self, render_layers_and_windows},
+    scanout::{self, Scanout},
+    screenshot::{self, CaptureRequest, Captures},
     state::{AnvilState, Backend},
 };
 
@@ -80,6 +93,10 @@ impl AsRawFd for SessionFd {
         self.0
     }
 }
+// Page flips of client buffers go through the fd directly, the device is busy dispatching
+// its events when we render.
+impl drm::Device for SessionFd {}
+impl ControlDevice for SessionFd {}
 
 #[derive(Debug, PartialEq)]
 struct UdevOutputId {
@@ -95,12 +112,197 @@ pub struct UdevData {
     signaler: Signaler<SessionSignal>,
     pointer_image: crate::cursor::Cursor,
     render_timer: TimerHandle<(u64, crtc::Handle)>,
+    /// Whether the outputs are turned off, in which case nothing is rendered.
+    blanked: bool,
+    pub touch: Option<ActiveTouch>,
+    /// Whether the cursor is drawn, from when a pointer device moves it until the last one is
+    /// removed or the screen is touched. Games are shown directly on the primary plane without it.
+    pub cursor_visible: bool,
+    /// The number of pointer devices plugged in.
+    pub pointer_devices: usize,
 }
 
 impl Backend for UdevData {
     fn seat_name(&self) -> String {
         self.session.seat()
     }
+
+    fn open_device(&mut self, path: &Path) -> io::Result<RawFd> {
+        self.session
+            .open(path, OFlag::O_RDONLY | OFlag::O_CLOEXEC | OFlag::O_NOCTTY | OFlag::O_NONBLOCK)
+            .map_err(|err| io::Error::new(io::ErrorKind::Other, err.to_string()))
+    }
+
+    fn close_device(&mut self, fd: RawFd) {
+        if let Err(err) = self.session.close(fd) {
+            slog_scope::warn!("Failed to close input device: {}", err);
+        }
+    }
+
+    fn set_blanked(&mut self, blanked: bool) {
+        self.blanked = blanked;
+        for backend in self.backends.values() {
+            let device = backend.event_dispatcher.as_source_ref();
+            for (&crtc, surface) in backend.surfaces.borrow().iter() {
+                for connector in surface.borrow().surface.current_connectors() {
+                    if let Err(err) = set_dpms(&*device, connector, !blanked) {
+                        slog_scope::warn!(
+                            "Failed to set DPMS: {}", err;
+                            "connector" => format!("{:?}", connector)
+                        );
+                    }
+                }
+                // Nothing was queued while blanked, so the next frame must be started here
+                if !blanked {
+                    surface.borrow_mut().idle = false;
+                    self.render_timer.add_timeout(Duration::ZERO, (backend.dev_id, crtc));
+                }
+            }
+        }
+    }
+}
+
+/// Turns a connector on or off through its `DPMS` property, if it has one.
+fn set_dpms(
+    device: &DrmDevice<SessionFd>,
+    connector: connector::Handle,
+    on: bool,
+) -> Result<(), drm::SystemError> {
+    let properties = device.get_properties(connector)?;
+    let (handles, _) = properties.as_props_and_values();
+    for &handle in handles {
+        if device.get_property(handle)?.name().to_str() == Ok("DPMS") {
+            // DRM_MODE_DPMS_ON and DRM_MODE_DPMS_OFF
+            let value = if on { 0 } else { 3 };
+            return device.set_property(connector, handle, value);
+        }
+    }
+    Ok(())
+}
+
+/// Turns adaptive sync on for a crtc through its `VRR_ENABLED` property, if the connector it
+/// drives is `vrr_capable`. Returns whether it is on.
+fn enable_vrr(
+    device: &DrmDevice<SessionFd>,
+    connector: connector::Handle,
+    crtc: crtc::Handle,
+) -> Result<bool, drm::SystemError> {
+    let properties = device.get_properties(connector)?;
+    let (handles, values) = properties.as_props_and_values();
+    let mut capable = false;
+    for (&handle, &value) in handles.iter().zip(values) {
+        if device.get_property(handle)?.name().to_str() == Ok("vrr_capable") {
+            capable = value == 1;
+        }
+    }
+    if !capable {
+        return Ok(false);
+    }
+
+    let properties = device.get_properties(crtc)?;
+    let (handles, _) = properties.as_props_and_values();
+    for &handle in handles {
+        if device.get_property(handle)?.name().to_str() == Ok("VRR_ENABLED") {
+            device.set_property(crtc, handle, 1)?;
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Returns the transform making the content upright on a panel according to its
+/// `panel orientation` property, `Normal` if it has none.
+fn panel_orientation(
+    device: &DrmDevice<SessionFd>,
+    connector: connector::Handle,
+) -> Result<wl_output::Transform, drm::SystemError> {
+    let properties = device.get_properties(connector)?;
+    let (handles, values) = properties.as_props_and_values();
+    for (&handle, &value) in handles.iter().zip(values) {
+        if device.get_property(handle)?.name().to_str() == Ok("panel orientation") {
+            // DRM_MODE_PANEL_ORIENTATION_*
+            return Ok(match value {
+                1 => wl_output::Transform::_180,
+                2 => wl_output::Transform::_90,
+                3 => wl_output::Transform::_270,
+                _ => wl_output::Transform::Normal,
+            });
+        }
+    }
+    Ok(wl_output::Transform::Normal)
+}
+
+/// Returns the transform to render upright content with into the buffers of an output with
+/// `transform`, which are scanned out with their rows flipped.
+fn render_transform(transform: wl_output::Transform) -> Transform {
+    match transform {
+        wl_output::Transform::_90 => Transform::Flipped270,
+        wl_output::Transform::_180 => Transform::Flipped,
+        wl_output::Transform::_270 => Transform::Flipped90,
+        wl_output::Transform::Flipped => Transform::_180,
+        wl_output::Transform::Flipped90 => Transform::_270,
+        wl_output::Transform::Flipped180 => Transform::Normal,
+        wl_output::Transform::Flipped270 => Transform::_90,
+        _ => Transform::Flipped180,
+    }
+}
+
+/// Returns the refresh rate of a mode in mHz, more precisely than its `vrefresh`.
+fn refresh_rate(mode: &DrmMode) -> i32 {
+    let (_, _, htotal) = mode.hsync();
+    let (_, _, vtotal) = mode.vsync();
+    if htotal == 0 || vtotal == 0 {
+        return mode.vrefresh() as i32 * 1000;
+    }
+    (mode.clock() as u64 * 1_000_000 / (htotal as u64 * vtotal as u64)) as i32
+}
+
+fn wl_mode(mode: &DrmMode) -> Mode {
+    let size = mode.size();
+    Mode {
+        size: (size.0 as i32, size.1 as i32).into(),
+        refresh: refresh_rate(mode),
+    }
+}
+
+/// `DRM_IOCTL_WAIT_VBLANK`'s argument, as its reply.
+#[repr(C)]
+struct WaitVblank {
+    kind: u32,
+    sequence: u32,
+    tv_sec: libc::c_long,
+    tv_usec: libc::c_long,
+}
+
+/// `DRM_IOCTL_WAIT_VBLANK`, whose size follows the size of `long`.
+const WAIT_VBLANK: libc::c_ulong =
+    (3 << 30) | ((mem::size_of::<WaitVblank>() as libc::c_ulong) << 16) | (0x64 << 8) | 0x3a;
+/// `_DRM_VBLANK_RELATIVE`, a wait for no vblank returns the last one right away.
+const VBLANK_RELATIVE: u32 = 0x1;
+/// `_DRM_VBLANK_HIGH_CRTC_SHIFT`, where the index of crtcs past the second goes.
+const VBLANK_HIGH_CRTC_SHIFT: u32 = 1;
+/// `_DRM_VBLANK_SECONDARY`, the second crtc.
+const VBLANK_SECONDARY: u32 = 0x2000_0000;
+
+/// Returns the `CLOCK_MONOTONIC` time and the number of the last vblank of a crtc, as
+/// timestamped by the kernel.
+fn last_vblank(device: &SessionFd, pipe: usize) -> io::Result<(Duration, u64)> {
+    let crtc = match pipe {
+        0 => 0,
+        1 => VBLANK_SECONDARY,
+        pipe => (pipe as u32) << VBLANK_HIGH_CRTC_SHIFT,
+    };
+    let mut vblank = WaitVblank {
+        kind: VBLANK_RELATIVE | crtc,
+        sequence: 0,
+        tv_sec: 0,
+        tv_usec: 0,
+    };
+    if unsafe { libc::ioctl(device.as_raw_fd(), WAIT_VBLANK as _, &mut vblank) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let time = Duration::from_secs(vblank.tv_sec as u64) + Duration::from_micros(vblank.tv_usec as u64);
+    Ok((time, vblank.sequence as u64))
 }
 
 pub fn run_udev(log: Logger) {
@@ -144,6 +346,10 @@ pub fn run_udev(log: Logger) {
         signaler: session_signal.clone(),
         pointer_image: crate::cursor::Cursor::load(&log),
         render_timer: timer.handle(),
+        blanked: false,
+        touch: None,
+        cursor_visible: false,
+        pointer_devices: 0,
     };
     let mut state = AnvilState::init(display.clone(), event_loop.handle(), data, log.clone(), true);
 
@@ -178,7 +384,15 @@ pub fn run_udev(log: Logger) {
     );
     libinput_context.udev_assign_seat(&state.seat_name).unwrap();
     let mut libinput_backend = LibinputInputBackend::new(libinput_context, log.clone());
-    libinput_backend.link(session_signal);
+    libinput_backend.link(session_signal.clone());
+
+    // The session revokes the gamepads while it is inactive, open them again when it comes back
+    let gamepad_handle = event_loop.handle();
+    let _gamepad_restart_token = session_signal.register(move |signal| {
+        if let SessionSignal::ActivateSession = signal {
+            gamepad_handle.insert_idle(|anvil_state| anvil_state.scan_gamepads());
+        }
+    });
 
     /*
      * Bind all our objects that get driven by the event loop
@@ -251,8 +465,9 @@ pub fn run_udev(log: Logger) {
             state.running.store(false, Ordering::SeqCst);
         } else {
             display.borrow_mut().flush_clients(&mut state);
-            state.window_map.borrow_mut().refresh();
+            state.refresh_windows();
             state.output_map.borrow_mut().refresh();
+            state.render_changes();
         }
     }
 
@@ -268,18 +483,57 @@ pub type RenderSurface = GbmBufferedSurface<SessionFd>;
 
 struct SurfaceData {
     surface: RenderSurface,
+    scanout: Scanout,
+    damage: DamageHistory,
+    /// The feedbacks of the frame in flight.
+    feedbacks: FrameFeedbacks,
+    /// The index of the crtc, which vblank queries refer to it by.
+    pipe: usize,
+    /// All the modes of the connector, and the one used unless a game asks for another.
+    modes: Vec<DrmMode>,
+    default_mode: DrmMode,
+    /// Whether adaptive sync is on, which makes switching modes pointless.
+    vrr: bool,
+    /// Whether nothing is drawn until something changes, with no frame or render pending.
+    idle: bool,
     #[cfg(feature = "debug")]
     fps: fps_ticker::Fps,
 }
 
+impl SurfaceData {
+    /// Returns the mode to use for a game asking for `refresh`, in Hz: the one of the same
+    /// size as the default mode with the closest refresh rate.
+    fn mode_for(&self, refresh: Option<f64>) -> DrmMode {
+        let refresh = match refresh {
+            Some(refresh) if !self.vrr => (refresh * 1000.0) as i32,
+            _ => return self.default_mode,
+        };
+        self.modes
+            .iter()
+            .filter(|mode| mode.size() == self.default_mode.size())
+            .filter(|mode| !mode.flags().contains(ModeFlags::INTERLACE))
+            .min_by_key(|mode| (refresh_rate(mode) - refresh).abs())
+            .copied()
+            .unwrap_or(self.default_mode)
+    }
+
+    /// How long a frame is shown with the current mode.
+    fn frame_duration(&self) -> Duration {
+        let refresh = refresh_rate(&self.surface.current_mode()).max(1000);
+        Duration::from_nanos(1_000_000_000_000 / refresh as u64)
+    }
+}
+
 struct BackendData {
     _restart_token: SignalToken,
     surfaces: Rc<RefCell<HashMap<crtc::Handle, Rc<RefCell<SurfaceData>>>>>,
     pointer_images: Vec<(xcursor::parser::Image, Gles2Texture)>,
+    overlay: OverlayRenderer,
     #[cfg(feature = "debug")]
     fps_texture: Gles2Texture,
     renderer: Rc<RefCell<Gles2Renderer>>,
     gbm: GbmDevice<SessionFd>,
+    drm: SessionFd,
     registration_token: RegistrationToken,
     event_dispatcher: Dispatcher<'static, DrmDevice<SessionFd>, AnvilState<UdevData>>,
     dev_id: u64,
@@ -290,6 +544,7 @@ fn scan_connectors(
     gbm: &GbmDevice<SessionFd>,
     renderer: &mut Gles2Renderer,
     output_map: &mut crate::output_map::OutputMap,
+    config: &DisplayConfig,
     signaler: &Signaler<SessionSignal>,
     logger: &::slog::Logger,
 ) -> HashMap<crtc::Handle, Rc<RefCell<SurfaceData>>> {
@@ -325,17 +580,20 @@ fn scan_connectors(
                         connector_info.interface_id(),
                         crtc,
                     );
-                    let mut surface = match device.create_surface(
-                        crtc,
-                        connector_info.modes()[0],
-                        &[connector_info.handle()],
-                    ) {
-                        Ok(surface) => surface,
-                        Err(err) => {
-                            warn!(logger, "Failed to create drm surface: {}", err);
-                            continue;
-                        }
-                    };
+                    let modes = connector_info.modes().to_vec();
+                    let default_mode = modes
+                        .iter()
+                        .find(|mode| mode.mode_type().contains(ModeTypeFlags::PREFERRED))
+                        .copied()
+                        .unwrap_or(modes[0]);
+                    let mut surface =
+                        match device.create_surface(crtc, default_mode, &[connector_info.handle()]) {
+                            Ok(surface) => surface,
+                            Err(err) => {
+                                warn!(logger, "Failed to create drm surface: {}", err);
+                                continue;
+                            }
+                        };
                     surface.link(signaler.clone());
 
                     let renderer_formats =
@@ -351,12 +609,14 @@ fn scan_connectors(
                             }
                         };
 
-                    let mode = connector_info.modes()[0];
-                    let size = mode.size();
-                    let mode = Mode {
-                        size: (size.0 as i32, size.1 as i32).into(),
-                        refresh: (mode.vrefresh() * 1000) as i32,
+                    let vrr = match enable_vrr(device, connector_info.handle(), crtc) {
+                        Ok(vrr) => vrr,
+                        Err(err) => {
+                            warn!(logger, "Failed to enable adaptive sync: {}", err);
+                            false
+                        }
                     };
+                    info!(logger, "Adaptive sync: {}", vrr);
 
                     let other_short_name;
                     let interface_short_name = match connector_info.interface() {
@@ -385,16 +645,39 @@ fn scan_connectors(
                             make: "Smithay".into(),
                             model: "Generic DRM".into(),
                         },
-                        mode,
+                        wl_mode(&default_mode),
                     );
+                    for mode in modes.iter().filter(|mode| **mode != default_mode) {
+                        output.add_mode(wl_mode(mode));
+                    }
 
                     output.userdata().insert_if_missing(|| UdevOutputId {
                         crtc,
                         device_id: device.device_id(),
                     });
 
+                    let transform = match config.transform.get(&output_name) {
+                        Some(transform) => transform.wl_transform(),
+                        None => panel_orientation(device, connector_info.handle()).unwrap_or_else(|err| {
+                            warn!(logger, "Failed to read the panel orientation: {}", err);
+                            wl_output::Transform::Normal
+                        }),
+                    };
+                    if transform != wl_output::Transform::Normal {
+                        info!(logger, "Transform of {}: {:?}", output_name, transform);
+                        output_map.update_transform_by_name(transform, &output_name);
+                    }
+
                     entry.insert(Rc::new(RefCell::new(SurfaceData {
                         surface: gbm_surface,
+                        scanout: Scanout::default(),
+                        damage: DamageHistory::default(),
+                        feedbacks: FrameFeedbacks::default(),
+                        pipe: res_handles.crtcs().iter().position(|&c| c == crtc).unwrap_or(0),
+                        modes,
+                        default_mode,
+                        vrr,
+                        idle: false,
                         #[cfg(feature = "debug")]
                         fps: fps_ticker::Fps::default(),
                     })));
@@ -488,15 +771,21 @@ impl AnvilState<UdevData> {
                 &gbm,
                 &mut *renderer.borrow_mut(),
                 &mut *self.output_map.borrow_mut(),
+                &self.config.display,
                 &self.backend_data.signaler,
                 &self.log,
             )));
+            self.find_backlight(device_id, &path);
 
             let dev_id = device.device_id();
             let handle = self.handle.clone();
             let restart_token = self.backend_data.signaler.register(move |signal| match signal {
                 SessionSignal::ActivateSession | SessionSignal::ActivateDevice { .. } => {
-                    handle.insert_idle(move |anvil_state| anvil_state.render(dev_id, None));
+                    handle.insert_idle(move |anvil_state| {
+                        // Whatever was shown meanwhile must be drawn over
+                        anvil_state.output_map.borrow().damage_all();
+                        anvil_state.render(dev_id, None)
+                    });
                 }
                 _ => {}
             });
@@ -541,8 +830,10 @@ impl AnvilState<UdevData> {
                     event_dispatcher,
                     surfaces: backends,
                     renderer,
+                    drm: SessionFd(gbm.as_raw_fd()),
                     gbm,
                     pointer_images: Vec::new(),
+                    overlay: OverlayRenderer::new(),
                     #[cfg(feature = "debug")]
                     fps_texture,
                     dev_id,
@@ -551,6 +842,33 @@ impl AnvilState<UdevData> {
         }
     }
 
+    /// Looks for the backlight of the connectors of a device, unless one was already found.
+    fn find_backlight(&mut self, device_id: dev_t, path: &Path) {
+        if self.backlight.is_some() {
+            return;
+        }
+        let card = match path.file_name() {
+            Some(card) => card.to_string_lossy().into_owned(),
+            None => return,
+        };
+        let step = self.config.display.brightness_step as f64 / 100.0;
+        let backlight = self.output_map.borrow().iter().find_map(|output| {
+            let on_device = output
+                .userdata()
+                .get::<UdevOutputId>()
+                .map(|id| id.device_id == device_id)
+                .unwrap_or(false);
+            if !on_device {
+                return None;
+            }
+            Backlight::for_connector(&card, output.name(), step, &self.log)
+        });
+        self.backlight = backlight.map(|mut backlight| {
+            backlight.restore();
+            backlight
+        });
+    }
+
     fn device_changed(&mut self, device: dev_t) {
         //quick and dirty, just re-init all backends
         if let Some(ref mut backend_data) = self.backend_data.backends.get_mut(&device) {
@@ -573,6 +891,7 @@ impl AnvilState<UdevData> {
                 &backend_data.gbm,
                 &mut *backend_data.renderer.borrow_mut(),
                 &mut *self.output_map.borrow_mut(),
+                &self.config.display,
                 &signaler,
                 &logger,
             );
@@ -617,8 +936,31 @@ impl AnvilState<UdevData> {
         }
     }
 
+    /// Renders the outputs left idle again, once something drawn on them changed.
+    fn render_changes(&mut self) {
+        let osd_changed = self.osd.take_changed();
+        let requested = mem::take(&mut self.render_requested);
+        if !requested && !osd_changed && !self.output_map.borrow().is_damaged() {
+            return;
+        }
+        let data = &self.backend_data;
+        for backend in data.backends.values() {
+            for (&crtc, surface) in backend.surfaces.borrow().iter() {
+                let mut surface = surface.borrow_mut();
+                if surface.idle {
+                    surface.idle = false;
+                    data.render_timer
+                        .add_timeout(Duration::ZERO, (backend.dev_id, crtc));
+                }
+            }
+        }
+    }
+
     // If crtc is `Some()`, render it, else render all crtcs
     fn render(&mut self, dev_id: u64, crtc: Option<crtc::Handle>) {
+        if self.backend_data.blanked {
+            return;
+        }
         let device_backend = match self.backend_data.backends.get_mut(&dev_id) {
             Some(backend) => backend,
             None => {
@@ -642,6 +984,8 @@ impl AnvilState<UdevData> {
                 &mut surfaces_iter
             };
 
+        let device_renderer = device_backend.renderer.clone();
+        let mut captured = Vec::new();
         for (&crtc, surface) in to_render_iter {
             // TODO get scale from the rendersurface when supporting HiDPI
             let frame = self
@@ -650,63 +994,277 @@ impl AnvilState<UdevData> {
                 .get_image(1 /*scale*/, self.start_time.elapsed().as_millis() as u32);
             let renderer = &mut *device_backend.renderer.borrow_mut();
             let pointer_images = &mut device_backend.pointer_images;
-            let pointer_image = pointer_images
+            let pointer_frame = pointer_images
                 .iter()
-                .find_map(|(image, texture)| if image == &frame { Some(texture) } else { None })
-                .cloned()
+                .position(|(image, _)| image == &frame)
                 .unwrap_or_else(|| {
                     let image =
                         ImageBuffer::from_raw(frame.width, frame.height, &*frame.pixels_rgba).unwrap();
                     let texture = import_bitmap(renderer, &image).expect("Failed to import cursor bitmap");
-                    pointer_images.push((frame, texture.clone()));
-                    texture
+                    pointer_images.push((frame.clone(), texture));
+                    pointer_images.len() - 1
                 });
+            let pointer_image = pointer_images[pointer_frame].1.clone();
 
-            let result = render_surface(
-                &mut *surface.borrow_mut(),
-                renderer,
-                device_backend.dev_id,
+            let output_id = UdevOutputId {
+                device_id: device_backend.dev_id,
                 crtc,
-                &mut *self.window_map.borrow_mut(),
-                &*self.output_map.borrow(),
-                self.pointer_location,
-                &pointer_image,
-                #[cfg(feature = "debug")]
-                &device_backend.fps_texture,
-                &*self.dnd_icon.lock().unwrap(),
-                &mut *self.cursor_status.lock().unwrap(),
-                &self.log,
-            );
-            if let Err(err) = result {
-                warn!(self.log, "Error during rendering: {:?}", err);
-                let reschedule = match err {
-                    SwapBuffersError::AlreadySwapped => false,
-                    SwapBuffersError::TemporaryFailure(err) => !matches!(
-                        err.downcast_ref::<DrmError>(),
-                        Some(&DrmError::DeviceInactive)
-                            | Some(&DrmError::Access {
-                                source: drm::SystemError::PermissionDenied,
-                                ..
-                            })
-                    ),
-                    SwapBuffersError::ContextLost(err) => panic!("Rendering loop lost: {}", err),
+            };
+            let output = self
+                .output_map
+                .borrow()
+                .find(|o| o.userdata().get::<UdevOutputId>() == Some(&output_id))
+                .map(|output| {
+                    let widgets = gui::overlay_widgets(
+                        &*self.window_map.borrow(),
+                        &self.menu,
+                        &self.games,
+                        &self.power,
+                        &self.audio,
+                        self.backlight.as_ref(),
+                        &self.osd,
+                        output.geometry().size,
+                    );
+                    (
+                        output.name().to_owned(),
+                        widgets,
+                        output.take_damage(),
+                        (
+                            output.geometry(),
+                            output.scale(),
+                            output.current_mode().size,
+                            output.transform(),
+                        ),
+                    )
+                });
+            let (output_name, widgets, damage, output) = match output {
+                Some((name, widgets, damage, output)) => (name, widgets, damage, Some(output)),
+                None => Default::default(),
+            };
+            // The cursor stays hidden until a pointer device moves it
+            let pointer_location = match output {
+                Some((geometry, _, _, _))
+                    if self.backend_data.cursor_visible
+                        && geometry.to_f64().contains(self.pointer_location) =>
+                {
+                    Some(self.pointer_location)
+                }
+                _ => None,
+            };
+
+            let surface = &mut *surface.borrow_mut();
+            surface.scanout.frame_submitted();
+
+            // The previous frame is on screen now
+            let shown = mem::take(&mut surface.feedbacks);
+            if !shown.is_empty() {
+                let output_map = self.output_map.borrow();
+                if let Some(output) =
+                    output_map.find(|o| o.userdata().get::<UdevOutputId>() == Some(&output_id))
+                {
+                    let refresh = output.current_mode().refresh;
+                    let refresh = Duration::from_nanos(1_000_000_000_000 / refresh.max(1) as u64);
+                    let flags = Kind::Vsync | Kind::HwCompletion;
+                    match last_vblank(&device_backend.drm, surface.pipe) {
+                        Ok((time, seq)) => shown.presented(output, time, refresh, seq, flags | Kind::HwClock),
+                        Err(err) => {
+                            debug!(self.log, "Unable to get the time of the last vblank: {}", err);
+                            shown.presented(output, presentation::monotonic_time(), refresh, 0, flags);
+                        }
+                    }
+                }
+            }
+
+            // The refresh rate follows the game shown, and is back to the default in the menu
+            let refresh = self
+                .window_map
+                .borrow()
+                .top_client_pid()
+                .and_then(|pid| self.games.find_by_client_pid(pid))
+                .and_then(|game| game.app.refresh_rate);
+            let mode = surface.mode_for(refresh);
+            let mode_switched = output.is_some()
+                && mode != surface.surface.pending_mode()
+                && match surface.surface.use_mode(mode) {
+                    Ok(()) => {
+                        info!(self.log, "Switching {} to {:?}", output_name, mode);
+                        self.output_map
+                            .borrow_mut()
+                            .switch_mode_by_name(wl_mode(&mode), &output_name);
+                        surface.damage.invalidate();
+                        true
+                    }
+                    Err(err) => {
+                        warn!(self.log, "Failed to switch to {:?}: {}", mode, err);
+                        // Not tried again
+                        surface.modes.retain(|other| *other != mode);
+                        false
+                    }
                 };
 
-                if reschedule {
-                    debug!(self.log, "Rescheduling");
-                    self.backend_data.render_timer.add_timeout(
-                        Duration::from_millis(1000 /*a seconds*/ / 60 /*refresh rate*/),
-                        (device_backend.dev_id, crtc),
-                    );
+            let frame_damage = match output {
+                Some((geometry, _, _, _)) => {
+                    let cursor = if let Some(pointer_location) = pointer_location {
+                        let (ptr_x, ptr_y) = pointer_location.into();
+                        damage::cursor_area(
+                            Point::<i32, Logical>::from((ptr_x as i32, ptr_y as i32)) - geometry.loc,
+                            &*self.dnd_icon.lock().unwrap(),
+                            &*self.cursor_status.lock().unwrap(),
+                            (frame.width as i32, frame.height as i32).into(),
+                        )
+                        .map(|area| (area, Some(pointer_frame)))
+                    } else {
+                        None
+                    };
+                    let scene = Scene::new(&*self.window_map.borrow(), &widgets, cursor);
+                    surface.damage.frame_damage(scene, damage, geometry.size)
+                }
+                None => None,
+            };
+            let capture = CaptureRequest::new(
+                &mut self.screenshots,
+                &self.screencopy,
+                &mut self.recorder,
+                &output_name,
+                frame_damage.is_some(),
+            );
+            let damage = match output {
+                // Captures read the whole frame back, and the FPS counter changes every frame
+                Some((geometry, _, _, _))
+                    if mode_switched
+                        || capture.without_cursor()
+                        || capture.with_cursor()
+                        || cfg!(feature = "debug") =>
+                {
+                    Some(Rectangle::from_loc_and_size((0, 0), geometry.size))
+                }
+                _ => frame_damage,
+            };
+            let damage = match (output, damage) {
+                (Some((geometry, _, _, _)), None) => {
+                    // Nothing changed, so nothing is drawn until something does. Clients waiting
+                    // for their frame callbacks still get them at the refresh rate, and fading
+                    // toasts and recordings need the next frames.
+                    let frames_sent = self
+                        .window_map
+                        .borrow()
+                        .send_frames(geometry, self.start_time.elapsed().as_millis() as u32);
+                    if frames_sent || self.osd.is_visible() || self.recorder.is_capturing() {
+                        self.backend_data
+                            .render_timer
+                            .add_timeout(surface.frame_duration(), (device_backend.dev_id, crtc));
+                    } else {
+                        surface.idle = true;
+                    }
+                    continue;
+                }
+                (_, damage) => damage.unwrap_or_default(),
+            };
+
+            let direct = output
+                .ok_or("unknown output")
+                // A new mode is only set by a commit of the swapchain
+                .and_then(|output| {
+                    if mode_switched {
+                        Err("the mode is being switched")
+                    } else {
+                        Ok(output)
+                    }
+                })
+                .and_then(|(geometry, scale, mode_size, transform)| {
+                    // Planes are not rotated, and games draw for the upright output
+                    if transform != wl_output::Transform::Normal {
+                        return Err("the output is transformed");
+                    }
+                    let cursor_drawn = pointer_location.is_some()
+                        && (self.dnd_icon.lock().unwrap().is_some()
+                            || !matches!(*self.cursor_status.lock().unwrap(), CursorImageStatus::Hidden));
+                    let top = scanout::scanout_candidate(
+                        &*self.window_map.borrow(),
+                        &self.osd,
+                        geometry,
+                        scale,
+                        cursor_drawn,
+                        capture,
+                    )?;
+                    surface
+                        .scanout
+                        .try_scanout(&device_backend.drm, crtc, &top, mode_size, &self.log)
+                });
+            let zero_copy = direct.is_ok();
+            let result = match direct {
+                Ok(()) => {
+                    // The buffers of the swapchain fall behind what is shown
+                    surface.damage.invalidate();
+                    surface
+                        .surface
+                        .frame_submitted()
+                        .map(|()| Captures::default())
+                        .map_err(Into::<SwapBuffersError>::into)
+                }
+                Err(reason) => {
+                    surface.scanout.composited(reason, &self.log);
+                    render_surface(
+                        surface,
+                        renderer,
+                        device_backend.dev_id,
+                        crtc,
+                        &mut *self.window_map.borrow_mut(),
+                        &*self.output_map.borrow(),
+                        pointer_location,
+                        &pointer_image,
+                        &mut device_backend.overlay,
+                        &widgets,
+                        #[cfg(feature = "debug")]
+                        &device_backend.fps_texture,
+                        &*self.dnd_icon.lock().unwrap(),
+                        &mut *self.cursor_status.lock().unwrap(),
+                        capture,
+                        damage,
+                        &self.log,
+                    )
+                }
+            };
+            match result {
+                Err(err) => {
+                    warn!(self.log, "Error during rendering: {:?}", err);
+                    let reschedule = match err {
+                        SwapBuffersError::AlreadySwapped => false,
+                        SwapBuffersError::TemporaryFailure(err) => !matches!(
+                            err.downcast_ref::<DrmError>(),
+                            Some(&DrmError::DeviceInactive)
+                                | Some(&DrmError::Access {
+                                    source: drm::SystemError::PermissionDenied,
+                                    ..
+                                })
+                        ),
+                        SwapBuffersError::ContextLost(err) => panic!("Rendering loop lost: {}", err),
+                    };
+
+                    if reschedule {
+                        debug!(self.log, "Rescheduling");
+                        self.backend_data
+                            .render_timer
+                            .add_timeout(surface.frame_duration(), (device_backend.dev_id, crtc));
+                    }
+                }
+                Ok(captures) => {
+                    captured.push((output_name, capture, captures));
+                    if let Some((geometry, _, _, _)) = output {
+                        let window_map = self.window_map.borrow();
+                        surface.feedbacks = FrameFeedbacks::collect(&*window_map, geometry, zero_copy);
+                        // Send frame events so that client start drawing their next frame
+                        window_map.send_frames(geometry, self.start_time.elapsed().as_millis() as u32);
+                    }
                 }
-            } else {
-                // TODO: only send drawn windows the frames callback
-                // Send frame events so that client start drawing their next frame
-                self.window_map
-                    .borrow()
-                    .send_frames(self.start_time.elapsed().as_millis() as u32);
             }
         }
+
+        drop(surfaces);
+        let mut renderer = device_renderer.borrow_mut();
+        for (output, capture, captures) in captured {
+            self.frame_captured(&output, capture, captures, &mut renderer);
+        }
     }
 }
 
@@ -718,46 +1276,73 @@ fn render_surface(
     crtc: crtc::Handle,
     window_map: &mut WindowMap,
     output_map: &crate::output_map::OutputMap,
-    pointer_location: Point<f64, Logical>,
+    pointer_location: Option<Point<f64, Logical>>,
     pointer_image: &Gles2Texture,
+    overlay: &mut OverlayRenderer,
+    widgets: &[Widget],
     #[cfg(feature = "debug")] fps_texture: &Gles2Texture,
     dnd_icon: &Option<wl_surface::WlSurface>,
     cursor_status: &mut CursorImageStatus,
+    capture: CaptureRequest,
+    damage: Rectangle<i32, Logical>,
     logger: &slog::Logger,
-) -> Result<(), SwapBuffersError> {
+) -> Result<Captures, SwapBuffersError> {
     surface.surface.frame_submitted()?;
 
     let output = output_map
         .find(|o| o.userdata().get::<UdevOutputId>() == Some(&UdevOutputId { device_id, crtc }))
-        .map(|output| (output.geometry(), output.scale(), output.current_mode()));
+        .map(|output| {
+            (
+                output.geometry(),
+                output.scale(),
+                output.current_mode(),
+                output.transform(),
+            )
+        });
 
-    let (output_geometry, output_scale, mode) = if let Some((geometry, scale, mode)) = output {
-        (geometry, scale, mode)
-    } else {
+    let (output_geometry, output_scale, mode, transform) = match output {
+        Some(output) => output,
         // Somehow we got called with a non existing output
-        return Ok(());
+        None => return Ok(Captures::default()),
     };
 
+    let mut captures = Captures::default();
     let dmabuf = surface.surface.next_buffer()?;
+    let repaint = damage::to_physical(surface.damage.repaint(&dmabuf, damage), output_scale);
+    // The scissor box is in buffer coordinates, which only match the output ones upright
+    let repaint = if transform == wl_output::Transform::Normal {
+        repaint
+    } else {
+        Rectangle::from_loc_and_size((0, 0), mode.size)
+    };
     renderer.bind(dmabuf)?;
 
     // and draw to our buffer
-    match renderer
+    let rendered = renderer
         .render(
             mode.size,
-            Transform::Flipped180, // Scanout is rotated
+            render_transform(transform), // Scanout is rotated
             |renderer, frame| {
+                // The first row of the framebuffer is the top of the output
+                render::scissor(renderer, Some(repaint))?;
                 render_layers_and_windows(
                     renderer,
                     frame,
                     window_map,
                     output_geometry,
                     output_scale,
+                    overlay,
+                    widgets,
                     logger,
                 )?;
+                // The cursor is left out of screenshots
+                if capture.without_cursor() {
+                    let image = screenshot::capture(renderer, mode.size, false)?;
+                    captures.without_cursor = Some(screenshot::upright(image, transform));
+                }
 
                 // set cursor
-                if output_geometry.to_f64().contains(pointer_location) {
+                if let Some(pointer_location) = pointer_location {
                     let (ptr_x, ptr_y) = pointer_location.into();
                     let relative_ptr_location =
                         Point::<i32, Logical>::from((ptr_x as i32, ptr_y as i32)) - output_geometry.loc;
@@ -797,7 +1382,7 @@ fn render_surface(
                                 output_scale,
                                 logger,
                             )?;
-                        } else {
+                        } else if matches!(*cursor_status, CursorImageStatus::Default) {
                             frame.render_texture_at(
                                 pointer_image,
                                 relative_ptr_location
@@ -825,19 +1410,30 @@ fn render_surface(
                         surface.fps.tick();
                     }
                 }
+                if capture.with_cursor() {
+                    let image = screenshot::capture(renderer, mode.size, false)?;
+                    captures.with_cursor = Some(screenshot::upright(image, transform));
+                }
 
                 Ok(())
             },
         )
         .map_err(Into::<SwapBuffersError>::into)
-        .and_then(|x| x)
-        .map_err(Into::<SwapBuffersError>::into)
-    {
+        .and_then(|x| x);
+    render::scissor(renderer, None)?;
+
+    // The damage is not passed on to the kernel as `FB_DAMAGE_CLIPS`, since the plane is
+    // committed by the `GbmBufferedSurface`, which has no way to set it.
+    match rendered {
         Ok(()) => surface
             .surface
             .queue_buffer()
+            .map(|()| captures)
             .map_err(Into::<SwapBuffersError>::into),
-        Err(err) => Err(err),
+        Err(err) => {
+            surface.damage.invalidate();
+            Err(err)
+        }
     }
 }
 
@@ -1,7 +1,7 @@
-use std::{cell::RefCell, sync::mpsc::Sender};
+use std::cell::RefCell;
 use std::sync::Mutex;
 
-use smithay::{reexports::{wayland_protocols::xdg_shell::server::xdg_toplevel, wayland_server::protocol::wl_surface::{self, WlSurface}}, utils::{Logical, Point, Rectangle}, wayland::{
+use smithay::{reexports::{wayland_protocols::xdg_shell::server::xdg_toplevel, wayland_server::protocol::wl_surface::{self, WlSurface}}, utils::{Logical, Point, Rectangle, Size}, wayland::{
         compositor::{with_states, with_surface_tree_downward, SubsurfaceCachedState, TraversalAction},
         shell::{
             legacy::ShellSurface,
@@ -10,8 +10,7 @@ use smithay::{reexports::{wayland_protocols::xdg_shell::server::xdg_toplevel, wa
         },
     }};
 
-use crate::gui::ToUi;
-use crate::shell::SurfaceData;
+use crate::{config::ScalingMode, shell::SurfaceData};
 #[cfg(feature = "xwayland")]
 use crate::xwayland::X11Surface;
 
@@ -45,6 +44,37 @@ impl Kind {
         }
     }
 
+    /// Returns the pid of the process owning this window.
+    ///
+    /// For X11 windows this is the `_NET_WM_PID` of the window, since the Wayland
+    /// client is XWayland itself.
+    pub fn client_pid(&self) -> Option<i32> {
+        match *self {
+            #[cfg(feature = "xwayland")]
+            Kind::X11(ref t) => t.pid(),
+            _ => self
+                .get_surface()
+                .and_then(|surface| surface.as_ref().client())
+                .and_then(|client| client.credentials())
+                .map(|credentials| credentials.pid),
+        }
+    }
+
+    /// Politely asks this window to close.
+    pub fn send_close(&self) {
+        match *self {
+            Kind::Xdg(ref t) => t.send_close(),
+            // wl_shell has no way to ask a surface to close
+            Kind::Wl(_) => {}
+            #[cfg(feature = "xwayland")]
+            Kind::X11(ref t) => {
+                if let Err(err) = t.send_close() {
+                    slog_scope::warn!("Failed to close X11 window: {}", err);
+                }
+            }
+        }
+    }
+
     /// Activate/Deactivate this window
     pub fn set_activated(&self, active: bool) {
         if let Kind::Xdg(ref t) = self {
@@ -119,6 +149,76 @@ impl PopupKind {
     }
 }
 
+/// Where a window is drawn on the outputs, and how much it is scaled.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Placement {
+    /// Where the origin of the window surface is drawn.
+    pub origin: Point<f64, Logical>,
+    /// The horizontal and vertical scale.
+    pub scale: (f64, f64),
+}
+
+impl Placement {
+    /// An unscaled surface at `location`.
+    pub fn at(location: Point<i32, Logical>) -> Placement {
+        Placement {
+            origin: location.to_f64(),
+            scale: (1.0, 1.0),
+        }
+    }
+
+    pub fn is_scaled(&self) -> bool {
+        self.scale != (1.0, 1.0)
+    }
+
+    /// Maps a point relative to the surface to the outputs.
+    pub fn apply(&self, point: Point<f64, Logical>) -> Point<f64, Logical> {
+        (
+            self.origin.x + point.x * self.scale.0,
+            self.origin.y + point.y * self.scale.1,
+        )
+            .into()
+    }
+
+    /// Maps a point on the outputs back to the surface.
+    pub fn invert(&self, point: Point<f64, Logical>) -> Point<f64, Logical> {
+        (
+            (point.x - self.origin.x) / self.scale.0,
+            (point.y - self.origin.y) / self.scale.1,
+        )
+            .into()
+    }
+
+    /// Maps an area of the surface to the outputs, rounding outwards.
+    pub fn apply_rect(&self, rect: Rectangle<i32, Logical>) -> Rectangle<i32, Logical> {
+        let start = self.apply(rect.loc.to_f64());
+        let end = self.apply((rect.loc + Point::from((rect.size.w, rect.size.h))).to_f64());
+        let (x, y) = (start.x.floor() as i32, start.y.floor() as i32);
+        Rectangle::from_loc_and_size((x, y), (end.x.ceil() as i32 - x, end.y.ceil() as i32 - y))
+    }
+
+    /// The placement of a subsurface or popup at `offset` from the surface.
+    pub fn offset(&self, offset: Point<i32, Logical>) -> Placement {
+        Placement {
+            origin: self.apply(offset.to_f64()),
+            ..*self
+        }
+    }
+}
+
+/// Returns the horizontal and vertical scale of a window of `size` shown in `area`.
+fn scale(mode: ScalingMode, size: Size<f64, Logical>, area: Size<f64, Logical>) -> (f64, f64) {
+    let fit = (area.w / size.w).min(area.h / size.h);
+    match mode {
+        ScalingMode::Center => (1.0, 1.0),
+        ScalingMode::Stretch => (area.w / size.w, area.h / size.h),
+        ScalingMode::Fit => (fit, fit),
+        // Windows larger than the output are still shrunk to fit
+        ScalingMode::Integer if fit >= 1.0 => (fit.floor(), fit.floor()),
+        ScalingMode::Integer => (fit, fit),
+    }
+}
+
 #[derive(Debug)]
 struct Window {
     location: Point<i32, Logical>,
@@ -128,6 +228,10 @@ struct Window {
     /// geometry if that's not set explicitly.
     bbox: Rectangle<i32, Logical>,
     toplevel: Kind,
+    /// Whether frame callbacks are withheld from this window.
+    throttled: bool,
+    /// How the window is shown if it does not match the size of the output.
+    scaling: ScalingMode,
 }
 
 impl Window {
@@ -174,6 +278,44 @@ impl Window {
         found.into_inner()
     }
 
+    /// Like `matching`, for a point on the outputs where this window is drawn scaled to `area`.
+    ///
+    /// The surface location returned is made up so that the point relative to it is the one in
+    /// the unscaled surface.
+    fn matching_placed(
+        &self,
+        point: Point<f64, Logical>,
+        area: Rectangle<i32, Logical>,
+    ) -> Option<(wl_surface::WlSurface, Point<i32, Logical>)> {
+        let unscaled = self.placement(area).invert(point) + self.location.to_f64();
+        self.matching(unscaled).map(|(surface, location)| {
+            let local = unscaled - location.to_f64();
+            (surface, (point - local).to_i32_round())
+        })
+    }
+
+    /// Where this window is drawn when shown in `area`, following its scaling mode.
+    fn placement(&self, area: Rectangle<i32, Logical>) -> Placement {
+        if self.toplevel.get_surface().is_none() || area.size.w <= 0 || area.size.h <= 0 {
+            return Placement::at(self.location);
+        }
+        let geometry = self.geometry();
+        if geometry.size.w <= 0 || geometry.size.h <= 0 || geometry.size == area.size {
+            return Placement::at(self.location);
+        }
+        let (size, area) = (geometry.size.to_f64(), area.to_f64());
+        let scale = scale(self.scaling, size, area.size);
+        // The window geometry, without the client-side decorations, is centered
+        let origin = (
+            area.loc.x + (area.size.w - size.w * scale.0) / 2.0 - geometry.loc.x as f64 * scale.0,
+            area.loc.y + (area.size.h - size.h * scale.1) / 2.0 - geometry.loc.y as f64 * scale.1,
+        );
+        Placement {
+            origin: origin.into(),
+            scale,
+        }
+    }
+
     fn self_update(&mut self) {
         let mut bounding_box = Rectangle::from_loc_and_size(self.location, (0, 0));
         if let Some(wl_surface) = self.toplevel.get_surface() {
@@ -216,24 +358,24 @@ impl Window {
         .unwrap()
         .unwrap_or(self.bbox)
     }
+}
 
-    /// Sends the frame callback to all the subsurfaces in this
-    /// window that requested it
-    pub fn send_frame(&self, time: u32) {
-        if let Some(wl_surface) = self.toplevel.get_surface() {
-            with_surface_tree_downward(
-                wl_surface,
-                (),
-                |_, _, &()| TraversalAction::DoChildren(()),
-                |_, states, &()| {
-                    // the surface may not have any user_data if it is a subsurface and has not
-                    // yet been commited
-                    SurfaceData::send_frame(&mut *states.cached_state.current(), time)
-                },
-                |_, _, &()| true,
-            );
-        }
-    }
+/// Sends the frame callback to a surface and all its subsurfaces that requested it
+/// Sends the frame callbacks of a surface tree, returning whether any was requested.
+fn send_frame(wl_surface: &WlSurface, time: u32) -> bool {
+    let mut requested = false;
+    with_surface_tree_downward(
+        wl_surface,
+        (),
+        |_, _, &()| TraversalAction::DoChildren(()),
+        |_, states, &()| {
+            // the surface may not have any user_data if it is a subsurface and has not
+            // yet been commited
+            requested |= SurfaceData::send_frame(&mut *states.cached_state.current(), time);
+        },
+        |_, _, &()| true,
+    );
+    requested
 }
 
 #[derive(Debug)]
@@ -246,23 +388,28 @@ pub struct WindowMap {
     windows: Vec<Window>,
     popups: Vec<Popup>,
 
-    menu_window: Option<Window>,
+    /// Whether the menu is shown over the top window, taking all input.
     pub menu_on_top: bool,
+    /// The area windows are scaled to, the primary output.
+    pub fullscreen_area: Rectangle<i32, Logical>,
 
     pub layers: LayerMap,
+}
 
-    tx: Sender<ToUi>,
+impl Default for WindowMap {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl WindowMap {
-    pub fn new(tx: Sender<ToUi>) -> WindowMap {
+    pub fn new() -> WindowMap {
         Self {
             menu_on_top: true,
+            fullscreen_area: Rectangle::default(),
             windows: Default::default(),
             popups: Default::default(),
-            menu_window: Default::default(),
             layers: Default::default(),
-            tx
         }
     }
     
@@ -271,32 +418,19 @@ impl WindowMap {
             location,
             bbox: Rectangle::default(),
             toplevel,
+            throttled: false,
+            scaling: ScalingMode::default(),
         };
         window.self_update();
         self.windows.insert(0, window);
     }
 
-    pub fn set_menu_window(&mut self, toplevel: Kind, location: Point<i32, Logical>) {
-        let mut window = Window {
-            location,
-            bbox: Rectangle::default(),
-            toplevel,
-        };
-        window.self_update();
-        self.menu_window = Some(window);
-    }
-
     pub fn set_menu_on_top(&mut self, on_top: bool) {
         self.menu_on_top = on_top;
-        self.tx.send(ToUi::SetMenuOnTop(on_top));
-    }
-
-    fn windows(&self) -> impl Iterator<Item = &Window> + '_ {
-        self.windows.iter().chain(self.menu_window.as_ref())
     }
 
     pub fn window_toplevels(&self) -> impl Iterator<Item = Kind> + '_ {
-        self.windows.iter().map(|w| w.toplevel.clone()).chain(self.menu_window.as_ref().map(|w| w.toplevel.clone()))
+        self.windows.iter().map(|w| w.toplevel.clone())
     }
 
     pub fn windows_len(&self) -> usize {
@@ -319,9 +453,11 @@ impl WindowMap {
             return Some(res);
         }
 
-        for w in &self.windows {
-            if let Some(surface) = w.matching(point) {
-                return Some(surface);
+        if !self.menu_on_top {
+            for w in &self.windows {
+                if let Some(surface) = w.matching_placed(point, self.fullscreen_area) {
+                    return Some(surface);
+                }
             }
         }
 
@@ -361,13 +497,33 @@ impl WindowMap {
         }
     }
 
+    /// Brings the topmost window whose client pid matches to the top.
+    ///
+    /// Returns `true` if such a window was found.
+    pub fn bring_client_to_top<F>(&mut self, mut f: F) -> bool
+    where
+        F: FnMut(i32) -> bool,
+    {
+        let found = self
+            .windows
+            .iter()
+            .position(|w| w.toplevel.client_pid().map(&mut f).unwrap_or(false));
+
+        if let Some(id) = found {
+            self.bring_nth_window_to_top(id);
+            true
+        } else {
+            false
+        }
+    }
+
     pub fn get_surface_and_bring_to_top(
         &mut self,
         point: Point<f64, Logical>,
     ) -> Option<(wl_surface::WlSurface, Point<i32, Logical>)> {
         let mut found = None;
         for (i, w) in self.windows.iter().enumerate() {
-            if let Some(surface) = w.matching(point) {
+            if let Some(surface) = w.matching_placed(point, self.fullscreen_area) {
                 found = Some((i, surface));
                 break;
             }
@@ -387,27 +543,37 @@ impl WindowMap {
         for w in self.windows.iter().rev() {
             f(&w.toplevel, w.location, &w.bbox)
         }
-        if self.menu_on_top {
-            if let Some(menu_window) = &self.menu_window {
-                f(&menu_window.toplevel, menu_window.location, &menu_window.bbox);
-            }
-        }
     }
 
+    /// Calls `f` with the topmost window, which is also drawn behind the menu.
     pub fn with_top_window<Func>(&self, mut f: Func)
     where
         Func: FnMut(&Kind, Point<i32, Logical>, &Rectangle<i32, Logical>),
     {
-        if self.menu_on_top {
-            if let Some(menu_window) = &self.menu_window {
-                f(&menu_window.toplevel, menu_window.location, &menu_window.bbox);
-            }
-        } else if self.windows.len() > 0 {
-            let w = &self.windows[0];
+        if let Some(w) = self.windows.first() {
             f(&w.toplevel, w.location, &w.bbox);
         }
     }
 
+    /// Returns where the topmost window is drawn.
+    pub fn top_placement(&self) -> Option<Placement> {
+        self.windows.first().map(|w| w.placement(self.fullscreen_area))
+    }
+
+    /// Returns the client pid of the game window currently shown, if the menu is hidden.
+    pub fn top_client_pid(&self) -> Option<i32> {
+        if self.menu_on_top {
+            None
+        } else {
+            self.topmost_client_pid()
+        }
+    }
+
+    /// Returns the client pid of the topmost window, even while the menu hides it.
+    pub fn topmost_client_pid(&self) -> Option<i32> {
+        self.windows.first().and_then(|w| w.toplevel.client_pid())
+    }
+
     pub fn with_child_popups<Func>(&self, base: &wl_surface::WlSurface, mut f: Func)
     where
         Func: FnMut(&PopupKind),
@@ -422,42 +588,39 @@ impl WindowMap {
         }
     }
 
-    pub fn refresh(&mut self) {
+    /// Forgets the dead windows, returning `true` if the top one was among them.
+    pub fn refresh(&mut self) -> bool {
+        let top_closed = self.windows.first().map(|w| !w.toplevel.alive()).unwrap_or(false);
         self.windows.retain(|w| w.toplevel.alive());
-        if self.windows.len() == 0 { 
-            self.menu_on_top = true;
-            self.tx.send(ToUi::SetMenuOnTop(true));
+        if top_closed && !self.menu_on_top {
+            // Switch to the next game rather than falling back to the menu
+            if let Some(window) = self.windows.first() {
+                window.toplevel.set_activated(true);
+            }
         }
         self.popups.retain(|p| p.popup.alive());
         self.layers.refresh();
         for w in &mut self.windows {
             w.self_update();
         }
-        if let Some(w) = &mut self.menu_window {
-            w.self_update();
-        }
+        top_closed
     }
 
     /// Refreshes the state of the toplevel, if it exists.
     pub fn refresh_toplevel(&mut self, toplevel: &Kind) {
         if let Some(w) = self.windows.iter_mut().find(|w| &w.toplevel == toplevel) {
             w.self_update();
-        } else if let Some(w) = &mut self.menu_window {
-            if &w.toplevel == toplevel {
-                w.self_update();
-            }
         }
     }
 
     pub fn clear(&mut self) {
         self.windows.clear();
         self.menu_on_top = true;
-        self.tx.send(ToUi::SetMenuOnTop(true));
     }
 
     /// Finds the toplevel corresponding to the given `WlSurface`.
     pub fn find(&self, surface: &wl_surface::WlSurface) -> Option<Kind> {
-        self.windows().find_map(|w| {
+        self.windows.iter().find_map(|w| {
             if w.toplevel
                 .get_surface()
                 .map(|s| s.as_ref().equals(surface.as_ref()))
@@ -487,7 +650,8 @@ impl WindowMap {
 
     /// Returns the location of the toplevel, if it exists.
     pub fn location(&self, toplevel: &Kind) -> Option<Point<i32, Logical>> {
-        self.windows()
+        self.windows
+            .iter()
             .find(|w| &w.toplevel == toplevel)
             .map(|w| w.location)
     }
@@ -502,18 +666,76 @@ impl WindowMap {
 
     /// Returns the geometry of the toplevel, if it exists.
     pub fn geometry(&self, toplevel: &Kind) -> Option<Rectangle<i32, Logical>> {
-        self.windows()
+        self.windows
+            .iter()
             .find(|w| &w.toplevel == toplevel)
             .map(|w| w.geometry())
     }
 
-    pub fn send_frames(&self, time: u32) {
-        for window in &self.windows {
-            window.send_frame(time);
+    /// Decides, by client pid, which game windows stop receiving frame callbacks.
+    ///
+    /// The window currently shown is never throttled.
+    pub fn set_throttled<F>(&mut self, mut f: F)
+    where
+        F: FnMut(i32) -> bool,
+    {
+        let menu_on_top = self.menu_on_top;
+        for (i, window) in self.windows.iter_mut().enumerate() {
+            let hidden = menu_on_top || i > 0;
+            window.throttled = hidden && window.toplevel.client_pid().map(&mut f).unwrap_or(false);
+        }
+    }
+
+    /// Decides, by client pid, how windows are scaled to the output.
+    pub fn set_scaling<F>(&mut self, mut f: F)
+    where
+        F: FnMut(i32) -> Option<ScalingMode>,
+    {
+        for window in &mut self.windows {
+            window.scaling = window.toplevel.client_pid().and_then(&mut f).unwrap_or_default();
         }
-        if let Some(w) = &self.menu_window {
-            w.send_frame(time);
+    }
+
+    /// Calls `f` with the root of every surface tree drawn on the output at `output`, and
+    /// whether its frame callbacks are withheld.
+    pub fn with_drawn_surfaces<F>(&self, output: Rectangle<i32, Logical>, mut f: F)
+    where
+        F: FnMut(&WlSurface, bool),
+    {
+        if let Some(w) = self.windows.first() {
+            let placement = w.placement(self.fullscreen_area);
+            let bbox =
+                placement.apply_rect(Rectangle::from_loc_and_size(w.bbox.loc - w.location, w.bbox.size));
+            if let Some(surface) = w.toplevel.get_surface().filter(|_| output.overlaps(bbox)) {
+                f(surface, w.throttled);
+                self.with_child_popups(surface, |popup| {
+                    if let Some(surface) = popup.get_surface() {
+                        f(surface, w.throttled);
+                    }
+                });
+            }
         }
-        self.layers.send_frames(time);
+        for layer in [Layer::Background, Layer::Bottom, Layer::Top, Layer::Overlay] {
+            self.layers
+                .with_layers_from_bottom_to_top(&layer, |layer_surface| {
+                    if let Some(surface) = layer_surface.surface.get_surface() {
+                        if output.overlaps(layer_surface.bbox) {
+                            f(surface, false);
+                        }
+                    }
+                });
+        }
+    }
+
+    /// Sends the frame callbacks of the surfaces drawn on the output at `output`, so that
+    /// their clients start drawing their next frame. Returns whether any client was waiting.
+    pub fn send_frames(&self, output: Rectangle<i32, Logical>, time: u32) -> bool {
+        let mut requested = false;
+        self.with_drawn_surfaces(output, |surface, throttled| {
+            if !throttled {
+                requested |= send_frame(surface, time);
+            }
+        });
+        requested
     }
 }
@@ -103,24 +103,6 @@ impl LayerSurface {
             .unwrap();
         }
     }
-
-    /// Sends the frame callback to all the subsurfaces in this
-    /// window that requested it
-    fn send_frame(&self, time: u32) {
-        if let Some(wl_surface) = self.surface.get_surface() {
-            with_surface_tree_downward(
-                wl_surface,
-                (),
-                |_, _, &()| TraversalAction::DoChildren(()),
-                |_, states, &()| {
-                    // the surface may not have any user_data if it is a subsurface and has not
-                    // yet been commited
-                    SurfaceData::send_frame(&mut *states.cached_state.current(), time)
-                },
-                |_, _, &()| true,
-            );
-        }
-    }
 }
 
 #[derive(Debug, Default)]
@@ -245,10 +227,4 @@ impl LayerMap {
             layer.location = location;
         }
     }
-
-    pub fn send_frames(&self, time: u32) {
-        for layer in &self.surfaces {
-            layer.send_frame(time);
-        }
-    }
 }
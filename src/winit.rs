@@ -25,11 +25,17 @@ use smithay::{
 use slog::Logger;
 
 use crate::state::{AnvilState, Backend};
-use crate::{drawing::*, render::render_layers_and_windows};
+use crate::{
+    drawing::*,
+    gui::{self, OverlayRenderer},
+    render::render_layers_and_windows,
+    screenshot::{self, CaptureRequest, Captures},
+};
 
 pub const OUTPUT_NAME: &str = "winit";
 
 pub struct WinitData {
+    overlay: OverlayRenderer,
     #[cfg(feature = "debug")]
     fps_texture: Gles2Texture,
     #[cfg(feature = "debug")]
@@ -85,6 +91,7 @@ pub fn run_winit(log: Logger) {
      */
 
     let data = WinitData {
+        overlay: OverlayRenderer::new(),
         #[cfg(feature = "debug")]
         fps_texture: import_bitmap(
             &mut renderer.borrow_mut().renderer(),
@@ -161,7 +168,27 @@ pub fn run_winit(log: Logger) {
                 .find_by_name(OUTPUT_NAME)
                 .map(|output| (output.geometry(), output.scale()))
                 .unwrap();
-
+            let widgets = gui::overlay_widgets(
+                &*state.window_map.borrow(),
+                &state.menu,
+                &state.games,
+                &state.power,
+                &state.audio,
+                state.backlight.as_ref(),
+                &state.osd,
+                output_geometry.size,
+            );
+
+            let size = renderer.window_size().physical_size;
+            let capture = CaptureRequest::new(
+                &mut state.screenshots,
+                &state.screencopy,
+                &mut state.recorder,
+                OUTPUT_NAME,
+                // Damage is not tracked, the whole output is drawn every frame
+                true,
+            );
+            let mut captures = Captures::default();
             let result = renderer
                 .render(|renderer, frame| {
                     render_layers_and_windows(
@@ -170,8 +197,14 @@ pub fn run_winit(log: Logger) {
                         &*state.window_map.borrow(),
                         output_geometry,
                         output_scale,
+                        &mut state.backend_data.overlay,
+                        &widgets,
                         &log,
                     )?;
+                    // The window is drawn from the bottom up
+                    if capture.without_cursor() {
+                        captures.without_cursor = Some(screenshot::capture(renderer, size, true)?);
+                    }
 
                     let (x, y) = state.pointer_location.into();
 
@@ -218,6 +251,9 @@ pub fn run_winit(log: Logger) {
                             cursor_visible = true;
                         }
                     }
+                    if capture.with_cursor() {
+                        captures.with_cursor = Some(screenshot::capture(renderer, size, true)?);
+                    }
 
                     #[cfg(feature = "debug")]
                     {
@@ -239,17 +275,22 @@ pub fn run_winit(log: Logger) {
 
             renderer.window().set_cursor_visible(cursor_visible);
 
+            let rendered = result.is_ok();
             if let Err(SwapBuffersError::ContextLost(err)) = result {
                 error!(log, "Critical Rendering Error: {}", err);
                 state.running.store(false, Ordering::SeqCst);
             }
-        }
+            state.frame_captured(OUTPUT_NAME, capture, captures, renderer.renderer());
+            if rendered {
+                state.nested_frame_presented(OUTPUT_NAME);
+            }
 
-        // Send frame events so that client start drawing their next frame
-        state
-            .window_map
-            .borrow()
-            .send_frames(start_time.elapsed().as_millis() as u32);
+            // Send frame events so that client start drawing their next frame
+            state
+                .window_map
+                .borrow()
+                .send_frames(output_geometry, start_time.elapsed().as_millis() as u32);
+        }
         display.borrow_mut().flush_clients(&mut state);
 
         if event_loop
@@ -259,7 +300,7 @@ pub fn run_winit(log: Logger) {
             state.running.store(false, Ordering::SeqCst);
         } else {
             display.borrow_mut().flush_clients(&mut state);
-            state.window_map.borrow_mut().refresh();
+            state.refresh_windows();
             state.output_map.borrow_mut().refresh();
         }
 
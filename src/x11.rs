@@ -22,7 +22,9 @@ use smithay::{
 
 use crate::{
     drawing::{draw_cursor, draw_dnd_icon},
+    gui::{self, OverlayRenderer},
     render::render_layers_and_windows,
+    screenshot::{self, CaptureRequest, Captures},
     state::Backend,
     AnvilState,
 };
@@ -35,6 +37,7 @@ pub const OUTPUT_NAME: &str = "x11";
 #[derive(Debug)]
 pub struct X11Data {
     render: bool,
+    overlay: OverlayRenderer,
     mode: Mode,
     surface: X11Surface,
     #[cfg(feature = "debug")]
@@ -96,6 +99,7 @@ pub fn run_x11(log: Logger) {
 
     let data = X11Data {
         render: true,
+        overlay: OverlayRenderer::new(),
         mode,
         surface,
         #[cfg(feature = "debug")]
@@ -186,6 +190,25 @@ pub fn run_x11(log: Logger) {
 
         if state.backend_data.render {
             state.backend_data.render = false;
+            let widgets = gui::overlay_widgets(
+                &*state.window_map.borrow(),
+                &state.menu,
+                &state.games,
+                &state.power,
+                &state.audio,
+                state.backlight.as_ref(),
+                &state.osd,
+                output_geometry.size,
+            );
+            let capture = CaptureRequest::new(
+                &mut state.screenshots,
+                &state.screencopy,
+                &mut state.recorder,
+                OUTPUT_NAME,
+                // Damage is not tracked, the whole output is drawn every frame
+                true,
+            );
+            let mut captures = Captures::default();
             let backend_data = &mut state.backend_data;
 
             match backend_data.surface.present() {
@@ -220,8 +243,14 @@ pub fn run_x11(log: Logger) {
                                     &*window_map,
                                     output_geometry,
                                     output_scale,
+                                    &mut backend_data.overlay,
+                                    &widgets,
                                     &log,
                                 )?;
+                                if capture.without_cursor() {
+                                    captures.without_cursor =
+                                        Some(screenshot::capture(renderer, backend_data.mode.size, false)?);
+                                }
 
                                 // draw the dnd icon if any
                                 {
@@ -269,6 +298,10 @@ pub fn run_x11(log: Logger) {
                                         cursor_visible = true;
                                     }
                                 }
+                                if capture.with_cursor() {
+                                    captures.with_cursor =
+                                        Some(screenshot::capture(renderer, backend_data.mode.size, false)?);
+                                }
 
                                 #[cfg(feature = "debug")]
                                 {
@@ -306,6 +339,9 @@ pub fn run_x11(log: Logger) {
                 }
             }
 
+            state.frame_captured(OUTPUT_NAME, capture, captures, &mut renderer.borrow_mut());
+            state.nested_frame_presented(OUTPUT_NAME);
+
             #[cfg(feature = "debug")]
             state.backend_data.fps.tick();
             window.set_cursor_visible(cursor_visible);
@@ -314,7 +350,7 @@ pub fn run_x11(log: Logger) {
             state
                 .window_map
                 .borrow()
-                .send_frames(start_time.elapsed().as_millis() as u32);
+                .send_frames(output_geometry, start_time.elapsed().as_millis() as u32);
             display.borrow_mut().flush_clients(&mut state);
         }
 
@@ -325,7 +361,7 @@ pub fn run_x11(log: Logger) {
             state.running.store(false, Ordering::SeqCst);
         } else {
             display.borrow_mut().flush_clients(&mut state);
-            state.window_map.borrow_mut().refresh();
+            state.refresh_windows();
             state.output_map.borrow_mut().refresh();
         }
     }
@@ -14,8 +14,8 @@ use x11rb::{
     protocol::{
         composite::{ConnectionExt as _, Redirect},
         xproto::{
-            ChangeWindowAttributesAux, ConfigWindow, ConfigureWindowAux, ConnectionExt as _, EventMask,
-            Window, WindowClass,
+            AtomEnum, ChangeWindowAttributesAux, ClientMessageEvent, ConfigWindow, ConfigureWindowAux,
+            ConnectionExt as _, EventMask, Window, WindowClass,
         },
         Event,
     },
@@ -58,6 +58,9 @@ x11rb::atom_manager! {
     Atoms: AtomsCookie {
         WM_S0,
         WL_SURFACE_ID,
+        WM_PROTOCOLS,
+        WM_DELETE_WINDOW,
+        _NET_WM_PID,
         _ANVIL_CLOSE_CONNECTION,
     }
 }
@@ -211,7 +214,22 @@ impl X11State {
             return;
         }
 
-        let x11surface = X11Surface { surface };
+        // The Wayland client is XWayland itself, so the owning process has to come from the window
+        let pid = self
+            .conn
+            .get_property(false, window, self.atoms._NET_WM_PID, AtomEnum::CARDINAL, 0, 1)
+            .ok()
+            .and_then(|cookie| cookie.reply().ok())
+            .and_then(|reply| reply.value32().and_then(|mut values| values.next()))
+            .map(|pid| pid as i32);
+
+        let x11surface = X11Surface {
+            surface,
+            pid,
+            window,
+            conn: Arc::clone(&self.conn),
+            atoms: self.atoms,
+        };
         self.window_map
             .borrow_mut()
             .insert(Kind::X11(x11surface), location);
@@ -236,6 +254,10 @@ pub fn commit_hook(surface: &WlSurface) {
 #[derive(Debug, Clone)]
 pub struct X11Surface {
     surface: WlSurface,
+    pid: Option<i32>,
+    window: Window,
+    conn: Arc<RustConnection>,
+    atoms: Atoms,
 }
 
 impl std::cmp::PartialEq for X11Surface {
@@ -256,4 +278,36 @@ impl X11Surface {
             None
         }
     }
+
+    /// The `_NET_WM_PID` of this window, if the client set it.
+    pub fn pid(&self) -> Option<i32> {
+        self.pid
+    }
+
+    /// Asks the window to close with `WM_DELETE_WINDOW`, or disconnects its client if the
+    /// window does not support that protocol.
+    pub fn send_close(&self) -> Result<(), ReplyOrIdError> {
+        let supports_delete = self
+            .conn
+            .get_property(false, self.window, self.atoms.WM_PROTOCOLS, AtomEnum::ATOM, 0, 32)?
+            .reply()?
+            .value32()
+            .map(|mut protocols| protocols.any(|p| p == self.atoms.WM_DELETE_WINDOW))
+            .unwrap_or(false);
+
+        if supports_delete {
+            let event = ClientMessageEvent::new(
+                32,
+                self.window,
+                self.atoms.WM_PROTOCOLS,
+                [self.atoms.WM_DELETE_WINDOW, x11rb::CURRENT_TIME, 0, 0, 0],
+            );
+            self.conn
+                .send_event(false, self.window, EventMask::NO_EVENT, event)?;
+        } else {
+            self.conn.kill_client(self.window)?;
+        }
+        self.conn.flush()?;
+        Ok(())
+    }
 }